@@ -0,0 +1,391 @@
+use http::header::HeaderMap;
+use isok_data::check::HttpAssertion;
+use isok_data::check_kinds::http::HttpAssertionResult;
+use nom::branch::alt;
+use nom::bytes::complete::{is_not, take_while1};
+use nom::character::complete::{char, u64 as parse_index};
+use nom::combinator::{all_consuming, map};
+use nom::multi::many0;
+use nom::sequence::{delimited, preceded};
+use nom::IResult;
+use regex::Regex;
+use serde_json::Value;
+
+/// Response bodies are kept up to this size, the rest is only counted
+pub const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+/// Step of a JSONPath, only child names and array indexes are supported
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+fn parse_segment(input: &str) -> IResult<&str, PathSegment> {
+    alt((
+        map(
+            preceded(
+                char('.'),
+                take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-'),
+            ),
+            |key: &str| PathSegment::Key(key.to_string()),
+        ),
+        map(
+            delimited(
+                char('['),
+                alt((
+                    delimited(char('\''), is_not("'"), char('\'')),
+                    delimited(char('"'), is_not("\""), char('"')),
+                )),
+                char(']'),
+            ),
+            |key: &str| PathSegment::Key(key.to_string()),
+        ),
+        map(delimited(char('['), parse_index, char(']')), |index| {
+            PathSegment::Index(index as usize)
+        }),
+    ))(input)
+}
+
+/// Parse a JSONPath such as `$.data.items[0]['status']`
+pub fn parse_json_path(path: &str) -> Result<Vec<PathSegment>, String> {
+    all_consuming(preceded(char('$'), many0(parse_segment)))(path)
+        .map(|(_, segments)| segments)
+        .map_err(|e| format!("invalid json path {path} : {e}"))
+}
+
+fn select<'a>(value: &'a Value, path: &[PathSegment]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, segment| match segment {
+        PathSegment::Key(key) => value.get(key),
+        PathSegment::Index(index) => value.get(index),
+    })
+}
+
+/// Response body as read by the agent
+pub struct ResponseBody {
+    /// Body start, up to [`MAX_BODY_SIZE`] bytes
+    pub bytes: Vec<u8>,
+    /// Whole body size in bytes
+    pub size: u64,
+}
+
+/// Parsed form of an assertion, regexes and json paths are compiled once per check
+#[derive(Debug, Clone)]
+enum Matcher {
+    Regex(Result<Regex, String>),
+    JsonPath(Result<Vec<PathSegment>, String>),
+    Plain,
+}
+
+/// Assertions of an http check, ready to be evaluated against responses
+#[derive(Debug, Clone, Default)]
+pub struct HttpAssertions {
+    assertions: Vec<(HttpAssertion, Matcher)>,
+}
+
+impl HttpAssertions {
+    pub fn new(assertions: Vec<HttpAssertion>) -> Self {
+        let assertions = assertions
+            .into_iter()
+            .map(|assertion| {
+                let matcher = match &assertion {
+                    HttpAssertion::BodyMatches { pattern } => Matcher::Regex(
+                        Regex::new(pattern).map_err(|e| format!("invalid regex : {e}")),
+                    ),
+                    HttpAssertion::JsonPathEquals { path, .. } => {
+                        Matcher::JsonPath(parse_json_path(path))
+                    }
+                    _ => Matcher::Plain,
+                };
+                (assertion, matcher)
+            })
+            .collect();
+
+        Self { assertions }
+    }
+
+    /// Whether the response body has to be read to evaluate the assertions
+    pub fn needs_body(&self) -> bool {
+        self.assertions
+            .iter()
+            .any(|(assertion, _)| !matches!(assertion, HttpAssertion::HeaderEquals { .. }))
+    }
+
+    fn evaluate_one(
+        assertion: &HttpAssertion,
+        matcher: &Matcher,
+        headers: &HeaderMap,
+        body: &Result<ResponseBody, String>,
+    ) -> Result<(), String> {
+        if let HttpAssertion::HeaderEquals { name, value } = assertion {
+            return match headers.get(name.as_str()).map(|v| v.to_str()) {
+                Some(Ok(actual)) if actual == value => Ok(()),
+                Some(Ok(actual)) => Err(format!("header {name} is {actual}")),
+                Some(Err(_)) => Err(format!("header {name} is not valid text")),
+                None => Err(format!("header {name} is missing")),
+            };
+        }
+
+        let body = body
+            .as_ref()
+            .map_err(|e| format!("could not read body : {e}"))?;
+        let text = String::from_utf8_lossy(&body.bytes);
+
+        match (assertion, matcher) {
+            (HttpAssertion::BodyContains { value }, _) => match text.contains(value.as_str()) {
+                true => Ok(()),
+                false => Err(format!("body does not contain {value}")),
+            },
+            (HttpAssertion::BodyNotContains { value }, _) => match text.contains(value.as_str()) {
+                true => Err(format!("body contains {value}")),
+                false => Ok(()),
+            },
+            (HttpAssertion::BodyMatches { pattern }, Matcher::Regex(regex)) => {
+                match regex.as_ref()?.is_match(&text) {
+                    true => Ok(()),
+                    false => Err(format!("body does not match {pattern}")),
+                }
+            }
+            (HttpAssertion::JsonPathEquals { path, value }, Matcher::JsonPath(segments)) => {
+                let segments = segments.as_ref()?;
+                let json: Value = serde_json::from_slice(&body.bytes)
+                    .map_err(|e| format!("body is not valid json : {e}"))?;
+                match select(&json, segments) {
+                    Some(actual) if actual == value => Ok(()),
+                    Some(actual) => Err(format!("{path} is {actual}, expected {value}")),
+                    None => Err(format!("{path} not found")),
+                }
+            }
+            (HttpAssertion::BodySize { min, max }, _) => {
+                let size = body.size;
+                match (min, max) {
+                    (Some(min), _) if size < *min => {
+                        Err(format!("body size {size} is below {min} bytes"))
+                    }
+                    (_, Some(max)) if size > *max => {
+                        Err(format!("body size {size} is above {max} bytes"))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            _ => Err("assertion could not be evaluated".to_string()),
+        }
+    }
+
+    /// Evaluate every assertion against a response
+    pub fn evaluate(
+        &self,
+        headers: &HeaderMap,
+        body: &Result<ResponseBody, String>,
+    ) -> Vec<HttpAssertionResult> {
+        self.assertions
+            .iter()
+            .map(|(assertion, matcher)| {
+                match Self::evaluate_one(assertion, matcher, headers, body) {
+                    Ok(()) => HttpAssertionResult::passed(assertion.clone()),
+                    Err(message) => HttpAssertionResult::failed(assertion.clone(), message),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{HeaderValue, CONTENT_TYPE};
+    use serde_json::json;
+
+    use super::*;
+
+    fn body(text: &str) -> Result<ResponseBody, String> {
+        Ok(ResponseBody {
+            bytes: text.as_bytes().to_vec(),
+            size: text.len() as u64,
+        })
+    }
+
+    /// Failure message of every assertion, none for the passed ones
+    fn evaluate(
+        assertions: Vec<HttpAssertion>,
+        headers: &HeaderMap,
+        body: &Result<ResponseBody, String>,
+    ) -> Vec<Option<String>> {
+        HttpAssertions::new(assertions)
+            .evaluate(headers, body)
+            .into_iter()
+            .map(|result| {
+                assert_eq!(result.passed, result.message.is_none());
+                result.message
+            })
+            .collect()
+    }
+
+    fn json_path(path: &str, value: Value) -> HttpAssertion {
+        HttpAssertion::JsonPathEquals {
+            path: path.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn json_paths() {
+        use PathSegment::*;
+
+        assert_eq!(parse_json_path("$"), Ok(vec![]));
+        assert_eq!(
+            parse_json_path("$.data.items[0]['status'][\"last-seen\"]"),
+            Ok(vec![
+                Key("data".to_string()),
+                Key("items".to_string()),
+                Index(0),
+                Key("status".to_string()),
+                Key("last-seen".to_string()),
+            ])
+        );
+        assert!(parse_json_path("data.items").is_err());
+        assert!(parse_json_path("$.data[").is_err());
+        assert!(parse_json_path("$.data[-1]").is_err());
+        assert!(parse_json_path("$..data").is_err());
+    }
+
+    #[test]
+    fn json_path_assertions() {
+        let response =
+            body(r#"{"data": {"items": [{"status": "ok"}, {"status": "ko"}], "count": 2}}"#);
+        let assertions = vec![
+            json_path("$.data.count", json!(2)),
+            json_path("$.data.items[1].status", json!("ko")),
+            json_path("$.data.items[0]['status']", json!("ko")),
+            json_path("$.data.items[2].status", json!("ok")),
+            json_path("$.data.missing", json!(null)),
+            json_path("$.data.items[", json!("ok")),
+        ];
+
+        let results = evaluate(assertions, &HeaderMap::new(), &response);
+        assert_eq!(results[0], None);
+        assert_eq!(results[1], None);
+        assert_eq!(
+            results[2].as_deref(),
+            Some(r#"$.data.items[0]['status'] is "ok", expected "ko""#)
+        );
+        assert_eq!(
+            results[3].as_deref(),
+            Some("$.data.items[2].status not found")
+        );
+        assert_eq!(results[4].as_deref(), Some("$.data.missing not found"));
+        assert!(results[5]
+            .as_deref()
+            .is_some_and(|message| message.starts_with("invalid json path $.data.items[")));
+    }
+
+    #[test]
+    fn json_path_on_a_non_json_body() {
+        let results = evaluate(
+            vec![json_path("$.status", json!("ok"))],
+            &HeaderMap::new(),
+            &body("<html>ok</html>"),
+        );
+
+        assert!(results[0]
+            .as_deref()
+            .is_some_and(|message| message.starts_with("body is not valid json")));
+    }
+
+    #[test]
+    fn body_assertions() {
+        let assertions = vec![
+            HttpAssertion::BodyContains {
+                value: "healthy".to_string(),
+            },
+            HttpAssertion::BodyNotContains {
+                value: "error".to_string(),
+            },
+            HttpAssertion::BodyMatches {
+                pattern: r"version \d+\.\d+".to_string(),
+            },
+            HttpAssertion::BodyMatches {
+                pattern: r"uptime \d+s".to_string(),
+            },
+            HttpAssertion::BodyMatches {
+                pattern: "(".to_string(),
+            },
+            HttpAssertion::BodySize {
+                min: Some(1),
+                max: Some(10),
+            },
+        ];
+
+        let results = evaluate(
+            assertions,
+            &HeaderMap::new(),
+            &body("healthy, version 1.42"),
+        );
+        assert_eq!(results[0], None);
+        assert_eq!(results[1], None);
+        assert_eq!(results[2], None);
+        assert_eq!(
+            results[3].as_deref(),
+            Some(r"body does not match uptime \d+s")
+        );
+        assert!(results[4]
+            .as_deref()
+            .is_some_and(|message| message.starts_with("invalid regex")));
+        assert_eq!(
+            results[5].as_deref(),
+            Some("body size 21 is above 10 bytes")
+        );
+    }
+
+    #[test]
+    fn header_assertions() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let header = |name: &str, value: &str| HttpAssertion::HeaderEquals {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let assertions = vec![
+            header("Content-Type", "application/json"),
+            header("content-type", "text/html"),
+            header("x-request-id", "42"),
+        ];
+
+        // headers are evaluated even when the body could not be read
+        let results = evaluate(assertions, &headers, &Err("reset".to_string()));
+        assert_eq!(results[0], None);
+        assert_eq!(
+            results[1].as_deref(),
+            Some("header content-type is application/json")
+        );
+        assert_eq!(
+            results[2].as_deref(),
+            Some("header x-request-id is missing")
+        );
+    }
+
+    #[test]
+    fn unreadable_body() {
+        let assertions = vec![HttpAssertion::BodyContains {
+            value: "ok".to_string(),
+        }];
+
+        let results = evaluate(assertions, &HeaderMap::new(), &Err("reset".to_string()));
+        assert_eq!(results[0].as_deref(), Some("could not read body : reset"));
+    }
+
+    #[test]
+    fn only_header_assertions_do_not_need_the_body() {
+        let header = HttpAssertion::HeaderEquals {
+            name: "server".to_string(),
+            value: "isok".to_string(),
+        };
+        let size = HttpAssertion::BodySize {
+            min: None,
+            max: Some(1024),
+        };
+
+        assert!(!HttpAssertions::default().needs_body());
+        assert!(!HttpAssertions::new(vec![header.clone()]).needs_body());
+        assert!(HttpAssertions::new(vec![header, size]).needs_body());
+    }
+}
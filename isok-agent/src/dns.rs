@@ -0,0 +1,668 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, FixedOffset, Local};
+use cookie_factory::bytes::{be_u16, be_u8};
+use cookie_factory::combinator::string;
+use cookie_factory::gen_simple;
+use cookie_factory::multi::all;
+use cookie_factory::sequence::{pair, tuple};
+use isok_data::check::{DnsCheck, DnsRecordType, Domain};
+use isok_data::check_kinds::dns::{DnsAnswer, DnsFields, DnsRcode};
+use isok_data::pulsar_messages::{CheckOutcome, CheckResult};
+use nom::bytes::complete::take;
+use nom::error::{Error as NomError, ErrorKind};
+use nom::multi::{count, length_data, many0};
+use nom::number::complete::{be_u16 as parse_u16, be_u32 as parse_u32, be_u8 as parse_u8};
+use nom::IResult;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Max compression pointers followed in a single name, to avoid loops
+const MAX_POINTERS: usize = 32;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const CLASS_IN: u16 = 1;
+
+/// Serialize a recursive query with a single question
+pub fn serialize_query(id: u16, name: &str, record_type: DnsRecordType) -> Vec<u8> {
+    let labels = name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .collect::<Vec<_>>();
+
+    gen_simple(
+        tuple((
+            be_u16(id),
+            be_u16(FLAG_RECURSION_DESIRED),
+            be_u16(1),
+            be_u16(0),
+            be_u16(0),
+            be_u16(0),
+            all(labels
+                .iter()
+                .map(|label| pair(be_u8(label.len() as u8), string(label)))),
+            be_u8(0),
+            be_u16(record_type.code()),
+            be_u16(CLASS_IN),
+        )),
+        Vec::with_capacity(18 + name.len()),
+    )
+    .expect("writing to a vec cannot fail")
+}
+
+/// Parse a possibly compressed name, `message` is the whole dns message pointers refer to
+fn parse_name<'a>(message: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], String> {
+    let mut labels = Vec::new();
+    let mut cursor = input;
+    let mut after_pointer = None;
+    let mut pointers = 0;
+
+    loop {
+        let (next, len) = parse_u8(cursor)?;
+        match len {
+            0 => {
+                cursor = next;
+                break;
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let (next, low) = parse_u8(next)?;
+                let offset = (((len & 0x3f) as usize) << 8) | low as usize;
+                pointers += 1;
+                if pointers > MAX_POINTERS || offset >= message.len() {
+                    return Err(nom::Err::Failure(NomError::new(cursor, ErrorKind::Verify)));
+                }
+                after_pointer.get_or_insert(next);
+                cursor = &message[offset..];
+            }
+            len => {
+                let (next, label) = take(len as usize)(next)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                cursor = next;
+            }
+        }
+    }
+
+    Ok((after_pointer.unwrap_or(cursor), labels.join(".")))
+}
+
+/// Question of a dns message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+    pub name: String,
+    /// Record type code
+    pub record_type: u16,
+}
+
+fn parse_question<'a>(message: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], DnsQuestion> {
+    let (input, name) = parse_name(message, input)?;
+    let (input, record_type) = parse_u16(input)?;
+    let (input, _class) = parse_u16(input)?;
+
+    Ok((input, DnsQuestion { name, record_type }))
+}
+
+/// Decode record data into its presentation format
+fn decode_rdata<'a>(
+    message: &'a [u8],
+    record_type: Option<DnsRecordType>,
+    rdata: &'a [u8],
+) -> IResult<&'a [u8], String> {
+    let data = match record_type {
+        Some(DnsRecordType::A) if rdata.len() == 4 => {
+            Ipv4Addr::from(<[u8; 4]>::try_from(rdata).unwrap()).to_string()
+        }
+        Some(DnsRecordType::Aaaa) if rdata.len() == 16 => {
+            Ipv6Addr::from(<[u8; 16]>::try_from(rdata).unwrap()).to_string()
+        }
+        Some(DnsRecordType::Cname) | Some(DnsRecordType::Ns) => parse_name(message, rdata)?.1,
+        Some(DnsRecordType::Mx) => {
+            let (rdata, preference) = parse_u16(rdata)?;
+            let (_, exchange) = parse_name(message, rdata)?;
+            format!("{preference} {exchange}")
+        }
+        Some(DnsRecordType::Txt) => {
+            let (_, strings) = many0(length_data(parse_u8))(rdata)?;
+            strings
+                .iter()
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .collect::<Vec<_>>()
+                .join("")
+        }
+        _ => rdata.iter().map(|b| format!("{b:02x}")).collect(),
+    };
+
+    Ok((&[], data))
+}
+
+fn parse_record<'a>(message: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], DnsAnswer> {
+    let (input, name) = parse_name(message, input)?;
+    let (input, kind) = parse_u16(input)?;
+    let (input, _class) = parse_u16(input)?;
+    let (input, ttl) = parse_u32(input)?;
+    let (input, rdata) = length_data(parse_u16)(input)?;
+    let record_type = DnsRecordType::from_code(kind);
+    let (_, data) = decode_rdata(message, record_type, rdata)?;
+
+    Ok((
+        input,
+        DnsAnswer {
+            name,
+            record_type,
+            ttl,
+            data,
+        },
+    ))
+}
+
+/// Decoded dns response, only the first question and the answer section are kept
+#[derive(Debug, Clone)]
+pub struct DnsResponse {
+    pub id: u16,
+    pub question: Option<DnsQuestion>,
+    pub truncated: bool,
+    pub rcode: DnsRcode,
+    pub answers: Vec<DnsAnswer>,
+}
+
+pub fn parse_response(message: &[u8]) -> IResult<&[u8], DnsResponse> {
+    let (input, id) = parse_u16(message)?;
+    let (input, flags) = parse_u16(input)?;
+    let (input, qdcount) = parse_u16(input)?;
+    let (input, ancount) = parse_u16(input)?;
+    let (input, _nscount) = parse_u16(input)?;
+    let (input, _arcount) = parse_u16(input)?;
+
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(nom::Err::Error(NomError::new(message, ErrorKind::Verify)));
+    }
+
+    let truncated = flags & FLAG_TRUNCATED != 0;
+    let rcode = DnsRcode::from((flags & 0x000f) as u8);
+
+    // truncated answers may end anywhere, keep what could be decoded
+    let (input, mut questions) = count(|i| parse_question(message, i), qdcount as usize)(input)?;
+    let (input, answers) = match count(|i| parse_record(message, i), ancount as usize)(input) {
+        Ok(res) => res,
+        Err(_) if truncated => (input, Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    Ok((
+        input,
+        DnsResponse {
+            id,
+            question: (!questions.is_empty()).then(|| questions.swap_remove(0)),
+            truncated,
+            rcode,
+            answers,
+        },
+    ))
+}
+
+fn invalid_response(e: nom::Err<NomError<&[u8]>>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid dns response : {:?}", e.map_input(|i| i.len())),
+    )
+}
+
+/// Query sent to a server, only replies with its id and question are accepted
+#[derive(Debug, Clone, Copy)]
+pub struct DnsQuery<'a> {
+    pub id: u16,
+    pub name: &'a str,
+    pub record_type: DnsRecordType,
+}
+
+impl<'a> DnsQuery<'a> {
+    /// Query with a random id, so that replies can't be guessed
+    pub fn new(name: &'a str, record_type: DnsRecordType) -> Self {
+        let mut id = [0u8; 2];
+        // a failure leaves a zero id, replies are still matched on their question
+        let _ = openssl::rand::rand_bytes(&mut id);
+
+        Self {
+            id: u16::from_be_bytes(id),
+            name,
+            record_type,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        serialize_query(self.id, self.name, self.record_type)
+    }
+
+    /// Whether `response` answers this query
+    pub fn matches(&self, response: &DnsResponse) -> bool {
+        response.id == self.id
+            && response.question.as_ref().is_some_and(|question| {
+                question.record_type == self.record_type.code()
+                    && question
+                        .name
+                        .trim_end_matches('.')
+                        .eq_ignore_ascii_case(self.name.trim_end_matches('.'))
+            })
+    }
+}
+
+async fn query_udp(server: SocketAddr, query: &DnsQuery<'_>) -> io::Result<DnsResponse> {
+    let request = query.serialize();
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    socket.send(&request).await?;
+
+    // replies to other queries are ignored
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        match parse_response(&buf[..len]) {
+            Ok((_, response)) if query.matches(&response) => return Ok(response),
+            _ => continue,
+        }
+    }
+}
+
+async fn query_tcp(server: SocketAddr, query: &DnsQuery<'_>) -> io::Result<DnsResponse> {
+    let request = query.serialize();
+    let mut stream = TcpStream::connect(server).await?;
+    stream
+        .write_all(&(request.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(&request).await?;
+
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    let (_, response) = parse_response(&buf).map_err(invalid_response)?;
+    if !query.matches(&response) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "dns response does not match the query",
+        ));
+    }
+
+    Ok(response)
+}
+
+/// Query over udp, retrying over tcp if the answer is truncated
+pub async fn query(server: SocketAddr, query: &DnsQuery<'_>) -> io::Result<DnsResponse> {
+    let response = query_udp(server, query).await?;
+
+    if response.truncated {
+        query_tcp(server, query).await
+    } else {
+        Ok(response)
+    }
+}
+
+/// Context of a dns [Job](crate::job::Job)
+#[derive(Debug, Clone)]
+pub struct DnsContext {
+    domain: Domain,
+    server: Option<IpAddr>,
+    record_type: DnsRecordType,
+}
+
+impl DnsContext {
+    pub fn new(domain: Domain, server: Option<IpAddr>, record_type: DnsRecordType) -> Self {
+        Self {
+            domain,
+            server,
+            record_type,
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        self.domain.host()
+    }
+
+    /// Server to query, the check one if any, `default_resolver` otherwise
+    pub fn server(&self, default_resolver: SocketAddr) -> SocketAddr {
+        self.server
+            .map(|ip| SocketAddr::new(ip, 53))
+            .unwrap_or(default_resolver)
+    }
+
+    /// Query `server`, waiting at most `timeout` for an answer, tcp fallback included
+    pub async fn run(&self, server: SocketAddr, timeout: Duration) -> DnsResult {
+        let datetime = Local::now().fixed_offset();
+        let request = DnsQuery::new(self.domain(), self.record_type);
+
+        let before = Instant::now();
+        let mut timed_out = false;
+        let outcome = match tokio::time::timeout(timeout, query(server, &request)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => {
+                timed_out = true;
+                Err(format!("no answer after {}ms", timeout.as_millis()))
+            }
+        };
+
+        DnsResult {
+            datetime,
+            elapsed: before.elapsed(),
+            server,
+            record_type: self.record_type,
+            outcome,
+            timed_out,
+        }
+    }
+}
+
+impl From<DnsCheck> for DnsContext {
+    fn from(value: DnsCheck) -> Self {
+        Self::new(value.domain, value.dns_server, value.record_type)
+    }
+}
+
+/// Result of a dns query ready to be send to warp10
+pub struct DnsResult {
+    pub datetime: DateTime<FixedOffset>,
+    pub elapsed: Duration,
+    pub server: SocketAddr,
+    pub record_type: DnsRecordType,
+    pub outcome: Result<DnsResponse, String>,
+    /// Whether the query failed because of the timeout
+    pub timed_out: bool,
+}
+
+impl Into<CheckResult<DnsFields>> for DnsResult {
+    fn into(self) -> CheckResult<DnsFields> {
+        let outcome = match &self.outcome {
+            Ok(response) if response.rcode == DnsRcode::NoError => CheckOutcome::Up,
+            Err(_) if self.timed_out => CheckOutcome::Timeout,
+            _ => CheckOutcome::Down,
+        };
+        let (rcode, answers, error) = match self.outcome {
+            Ok(response) => (Some(response.rcode), response.answers, None),
+            Err(e) => (None, Vec::new(), Some(e)),
+        };
+
+        CheckResult {
+            timestamp: self.datetime,
+            latency: Some(self.elapsed),
+            outcome,
+            fields: DnsFields {
+                server: self.server.to_string(),
+                record_type: self.record_type,
+                rcode,
+                answers,
+                error,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Name in wire format, without compression
+    fn name(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        for label in name.split('.') {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    /// Response to `query` echoing its question, every answer named after it
+    fn response(query: &[u8], rcode: u8, answers: &[(DnsRecordType, Vec<u8>)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&query[..2]);
+        out.extend_from_slice(&(0x8180 | rcode as u16).to_be_bytes());
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        out.extend_from_slice(&query[12..]);
+        for (record_type, rdata) in answers {
+            out.extend_from_slice(&[0xc0, 0x0c]);
+            out.extend_from_slice(&record_type.code().to_be_bytes());
+            out.extend_from_slice(&CLASS_IN.to_be_bytes());
+            out.extend_from_slice(&300u32.to_be_bytes());
+            out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            out.extend_from_slice(rdata);
+        }
+        out
+    }
+
+    /// Stub dns server on 127.0.0.1, sending the replies built by `reply` to every query
+    async fn stub_server<F>(reply: F) -> SocketAddr
+    where
+        F: Fn(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                for message in reply(&buf[..len]) {
+                    socket.send_to(&message, peer).await.unwrap();
+                }
+            }
+        });
+
+        address
+    }
+
+    async fn probe(
+        domain: &str,
+        record_type: DnsRecordType,
+        server: SocketAddr,
+    ) -> CheckResult<DnsFields> {
+        let ctx = DnsContext::new(Domain::try_from(domain).unwrap(), None, record_type);
+        ctx.run(server, Duration::from_secs(2)).await.into()
+    }
+
+    #[test]
+    fn parse_name_follows_compression_pointers() {
+        let mut message = vec![0u8; 12];
+        message.extend(name("example.com"));
+        // www + pointer to example.com at offset 12
+        let compressed = message.len();
+        message.extend_from_slice(&[3, b'w', b'w', b'w', 0xc0, 12, 0xff]);
+
+        let (rest, parsed) = parse_name(&message, &message[compressed..]).unwrap();
+        assert_eq!(parsed, "www.example.com");
+        assert_eq!(rest, &[0xff]);
+    }
+
+    #[test]
+    fn parse_name_rejects_pointer_loops() {
+        let message = [0xc0, 0x00];
+        assert!(parse_name(&message, &message).is_err());
+    }
+
+    #[test]
+    fn parse_name_rejects_pointers_out_of_message() {
+        let message = [0xc0, 0x40];
+        assert!(parse_name(&message, &message).is_err());
+    }
+
+    #[test]
+    fn parse_name_rejects_truncated_labels() {
+        let message = [7, b'e', b'x', b'a'];
+        assert!(parse_name(&message, &message).is_err());
+    }
+
+    #[test]
+    fn parse_response_decodes_question_and_answers() {
+        let query = serialize_query(0x1234, "example.com", DnsRecordType::A);
+        let message = response(&query, 0, &[(DnsRecordType::A, vec![192, 0, 2, 1])]);
+
+        let (_, response) = parse_response(&message).unwrap();
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.rcode, DnsRcode::NoError);
+        assert_eq!(
+            response.question,
+            Some(DnsQuestion {
+                name: "example.com".to_string(),
+                record_type: DnsRecordType::A.code(),
+            })
+        );
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, "example.com");
+        assert_eq!(response.answers[0].ttl, 300);
+        assert_eq!(response.answers[0].data, "192.0.2.1");
+    }
+
+    #[test]
+    fn parse_response_rejects_queries() {
+        let query = serialize_query(1, "example.com", DnsRecordType::A);
+        assert!(parse_response(&query).is_err());
+    }
+
+    #[test]
+    fn parse_response_rejects_truncated_packets() {
+        let query = serialize_query(1, "example.com", DnsRecordType::A);
+        let message = response(&query, 0, &[(DnsRecordType::A, vec![192, 0, 2, 1])]);
+
+        assert!(parse_response(&message[..6]).is_err());
+        assert!(parse_response(&message[..message.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn parse_response_keeps_truncated_flag_without_answers() {
+        let query = serialize_query(1, "example.com", DnsRecordType::A);
+        let mut message = response(&query, 0, &[(DnsRecordType::A, vec![192, 0, 2, 1])]);
+        message[2] |= (FLAG_TRUNCATED >> 8) as u8;
+        message.truncate(message.len() - 3);
+
+        let (_, response) = parse_response(&message).unwrap();
+        assert!(response.truncated);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn query_matches_id_and_question_only() {
+        let query = DnsQuery::new("Example.com.", DnsRecordType::A);
+        let message = response(&query.serialize(), 0, &[]);
+        let (_, response) = parse_response(&message).unwrap();
+        assert!(query.matches(&response));
+
+        let other_id = DnsQuery {
+            id: query.id.wrapping_add(1),
+            ..query
+        };
+        assert!(!other_id.matches(&response));
+
+        let other_type = DnsQuery {
+            record_type: DnsRecordType::Aaaa,
+            ..query
+        };
+        assert!(!other_type.matches(&response));
+
+        let other_name = DnsQuery {
+            name: "example.org",
+            ..query
+        };
+        assert!(!other_name.matches(&response));
+    }
+
+    #[tokio::test]
+    async fn stub_server_records() {
+        let server = stub_server(|query| {
+            let record_type = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+            let rdata = match DnsRecordType::from_code(record_type).unwrap() {
+                DnsRecordType::A => vec![192, 0, 2, 1],
+                DnsRecordType::Aaaa => {
+                    let mut ip = vec![0x20, 0x01, 0x0d, 0xb8];
+                    ip.extend_from_slice(&[0; 11]);
+                    ip.push(1);
+                    ip
+                }
+                DnsRecordType::Cname => name("target.example.net"),
+                DnsRecordType::Mx => {
+                    let mut mx = 10u16.to_be_bytes().to_vec();
+                    // exchange compressed to the question name
+                    mx.extend_from_slice(&[4, b'm', b'a', b'i', b'l', 0xc0, 0x0c]);
+                    mx
+                }
+                DnsRecordType::Txt => b"\x05hello\x05world".to_vec(),
+                DnsRecordType::Ns => name("ns1.example.com"),
+            };
+            let record_type = DnsRecordType::from_code(record_type).unwrap();
+            vec![response(query, 0, &[(record_type, rdata)])]
+        })
+        .await;
+
+        let expected = [
+            (DnsRecordType::A, "192.0.2.1"),
+            (DnsRecordType::Aaaa, "2001:db8::1"),
+            (DnsRecordType::Cname, "target.example.net"),
+            (DnsRecordType::Mx, "10 mail.example.com"),
+            (DnsRecordType::Txt, "helloworld"),
+            (DnsRecordType::Ns, "ns1.example.com"),
+        ];
+        for (record_type, data) in expected {
+            let result = probe("example.com", record_type, server).await;
+            assert_eq!(result.outcome, CheckOutcome::Up, "{record_type:?}");
+            assert_eq!(result.fields.rcode, Some(DnsRcode::NoError));
+            assert_eq!(result.fields.answers.len(), 1, "{record_type:?}");
+            assert_eq!(result.fields.answers[0].record_type, Some(record_type));
+            assert_eq!(result.fields.answers[0].data, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn stub_server_nxdomain() {
+        let server = stub_server(|query| vec![response(query, 3, &[])]).await;
+
+        let result = probe("missing.example.com", DnsRecordType::A, server).await;
+        assert_eq!(result.outcome, CheckOutcome::Down);
+        assert_eq!(result.fields.rcode, Some(DnsRcode::NxDomain));
+        assert!(result.fields.answers.is_empty());
+        assert!(result.fields.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn stub_server_spoofed_replies_are_ignored() {
+        let server = stub_server(|query| {
+            let mut wrong_id = response(query, 0, &[(DnsRecordType::A, vec![6, 6, 6, 6])]);
+            wrong_id[0] ^= 0xff;
+
+            let other_query = serialize_query(
+                u16::from_be_bytes([query[0], query[1]]),
+                "attacker.example",
+                DnsRecordType::A,
+            );
+            let wrong_question = response(&other_query, 0, &[(DnsRecordType::A, vec![6, 6, 6, 6])]);
+
+            let genuine = response(query, 0, &[(DnsRecordType::A, vec![192, 0, 2, 1])]);
+            vec![wrong_id, wrong_question, genuine]
+        })
+        .await;
+
+        let result = probe("example.com", DnsRecordType::A, server).await;
+        assert_eq!(result.outcome, CheckOutcome::Up);
+        assert_eq!(result.fields.answers[0].data, "192.0.2.1");
+    }
+
+    #[tokio::test]
+    async fn stub_server_silence_times_out() {
+        let server = stub_server(|_| Vec::new()).await;
+        let ctx = DnsContext::new(
+            Domain::try_from("example.com").unwrap(),
+            None,
+            DnsRecordType::A,
+        );
+
+        let result: CheckResult<DnsFields> =
+            ctx.run(server, Duration::from_millis(100)).await.into();
+        assert_eq!(result.outcome, CheckOutcome::Timeout);
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::error;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use isok_data::bus::{self, MessageBus, Topic};
+use isok_data::pulsar_heartbeats::Heartbeat;
+use isok_data::pulsar_messages::{CheckMessage, CheckType};
+
+use crate::job::SchedulerRegistry;
+use crate::pool::AgentPool;
+
+/// State of the agent reported in its heartbeats
+pub struct HeartbeatSource {
+    pub agent_id: String,
+    pub regions: Vec<String>,
+    pub schedulers: SchedulerRegistry,
+    /// Queue toward the pulsar sink, weak so that it does not keep the sink alive
+    pub queue: mpsc::WeakSender<(CheckType, CheckMessage)>,
+    pub pool: Option<Arc<RwLock<AgentPool>>>,
+}
+
+impl HeartbeatSource {
+    pub fn heartbeat(&self, started_at: DateTime<Utc>) -> Heartbeat {
+        let (jobs, scheduler_lag) = match self.schedulers.read() {
+            Ok(schedulers) => (
+                schedulers
+                    .iter()
+                    .map(|(frequency, metrics)| (frequency.as_secs(), metrics.jobs()))
+                    .collect(),
+                schedulers
+                    .values()
+                    .map(|metrics| metrics.last_lag().as_millis() as u64)
+                    .max()
+                    .unwrap_or_default(),
+            ),
+            Err(_) => Default::default(),
+        };
+        let queue_depth = self
+            .queue
+            .upgrade()
+            .map(|queue| (queue.max_capacity() - queue.capacity()) as u64)
+            .unwrap_or_default();
+        let pool = self
+            .pool
+            .as_ref()
+            .and_then(|pool| pool.read().ok().map(|pool| pool.name().to_string()));
+
+        Heartbeat {
+            agent_id: self.agent_id.clone(),
+            pool,
+            regions: self.regions.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: Utc::now(),
+            started_at,
+            jobs,
+            scheduler_lag,
+            queue_depth,
+            leaving: false,
+        }
+    }
+}
+
+/// Publish the agent heartbeat every `period`, then a last one flagged leaving once `leave` is cancelled
+pub async fn send_heartbeats(
+    source: HeartbeatSource,
+    bus: Arc<dyn MessageBus>,
+    period: Duration,
+    leave: CancellationToken,
+) {
+    // pooled agents join their pool from the first heartbeat on, not from the agent start
+    let started_at = Utc::now();
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        let leaving = tokio::select! {
+            _ = interval.tick() => false,
+            _ = leave.cancelled() => true,
+        };
+
+        let mut heartbeat = source.heartbeat(started_at);
+        heartbeat.leaving = leaving;
+        if let Some(Ok(mut pool)) = source.pool.as_ref().map(|pool| pool.write()) {
+            // the agent does not wait for its own heartbeat to come back
+            pool.record(&heartbeat, heartbeat.timestamp);
+            pool.prune(heartbeat.timestamp.timestamp());
+        }
+
+        let key = Some(heartbeat.agent_id.clone());
+        match bus::publish_json(bus.as_ref(), Topic::Heartbeats, key, &heartbeat).await {
+            // the other agents of the pool take the checks over from this last heartbeat
+            Ok(receipt) if leaving => {
+                if let Err(e) = receipt.await {
+                    error!("Could not send leaving heartbeat : {e}");
+                }
+                return;
+            }
+            Ok(_) => {}
+            Err(e) if leaving => {
+                error!("Could not send leaving heartbeat : {e}");
+                return;
+            }
+            Err(e) => error!("Could not send heartbeat : {e}"),
+        }
+    }
+}
@@ -1,48 +1,395 @@
 use chrono::{DateTime, FixedOffset, Local};
-use isok_data::check::HttpCheck;
-use isok_data::check_kinds::http::HttpFields;
-use isok_data::pulsar_messages::{CheckMessage, CheckResult};
+use http::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE,
+        HOST, LOCATION, PROXY_AUTHORIZATION,
+    },
+    Method, Request, Response, StatusCode,
+};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::{http1, http2};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use isok_data::check::{ExpectedStatus, HttpCheck};
+use isok_data::check_kinds::http::{HttpAssertionResult, HttpFailure, HttpFields, HttpTimings};
+use isok_data::check_kinds::tcp::TcpFailure;
+use isok_data::pulsar_messages::{CheckMessage, CheckOutcome, CheckResult};
 use log::error;
 use nom::AsBytes;
-use reqwest::{
-    header::{HeaderMap, HeaderName, HeaderValue},
-    Client, Method, Request, StatusCode, Url,
-};
+use openssl::ssl::{SslConnector, SslMethod};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+use url::{Host, Url};
 use uuid::Uuid;
 
-/// Http client, [`Client`] wrapper for storage in a [MagicPool](crate::magic_pool::MagicPool)
+use crate::assertion::{HttpAssertions, ResponseBody, MAX_BODY_SIZE};
+
+/// Max redirects followed by checks with `follow_redirects`
+const MAX_REDIRECTS: usize = 10;
+
+/// Drop the headers of a request that must not follow its redirect from `from` to `to` :
+/// credentials and the host when leaving the origin, the body description when
+/// the method is rewritten to GET
+fn redirect_headers(headers: &mut HeaderMap, from: &Url, to: &Url, rewritten: bool) {
+    let same_origin = from.scheme() == to.scheme()
+        && from.host() == to.host()
+        && from.port_or_known_default() == to.port_or_known_default();
+    if !same_origin {
+        for name in [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, HOST] {
+            headers.remove(name);
+        }
+    }
+    if rewritten {
+        headers.remove(CONTENT_TYPE);
+        headers.remove(CONTENT_LENGTH);
+    }
+}
+
+/// Duration of each request phase, see [`HttpTimings`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PhaseTimings {
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    pub ttfb: Option<Duration>,
+    pub download: Option<Duration>,
+}
+
+impl PhaseTimings {
+    fn sum(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Add the phases of a following request, after a redirect
+    fn add(&mut self, other: PhaseTimings) {
+        self.dns = Self::sum(self.dns, other.dns);
+        self.connect = Self::sum(self.connect, other.connect);
+        self.tls = Self::sum(self.tls, other.tls);
+        self.ttfb = Self::sum(self.ttfb, other.ttfb);
+        self.download = Self::sum(self.download, other.download);
+    }
+}
+
+impl From<PhaseTimings> for HttpTimings {
+    fn from(value: PhaseTimings) -> Self {
+        let micros = |d: Option<Duration>| d.map(|d| d.as_micros() as u64);
+
+        Self {
+            dns: micros(value.dns),
+            connect: micros(value.connect),
+            tls: micros(value.tls),
+            ttfb: micros(value.ttfb),
+            download: micros(value.download),
+        }
+    }
+}
+
+/// Failure kind and error message of a request without response
+type HttpError = (HttpFailure, String);
+
+/// Map a connection error to an [`HttpFailure`]
+fn classify_io_error(error: &io::Error) -> HttpFailure {
+    match crate::tcp::classify_io_error(error) {
+        TcpFailure::DnsFailure => HttpFailure::DnsFailure,
+        TcpFailure::Refused => HttpFailure::Refused,
+        TcpFailure::Timeout => HttpFailure::Timeout,
+        TcpFailure::Unreachable => HttpFailure::Unreachable,
+        TcpFailure::Other => HttpFailure::Other,
+    }
+}
+
+fn other_error(error: impl ToString) -> HttpError {
+    (HttpFailure::Other, error.to_string())
+}
+
+/// Request sender of a freshly opened connection
+enum Sender {
+    Http1(http1::SendRequest<Full<Bytes>>),
+    Http2(http2::SendRequest<Full<Bytes>>),
+}
+
+impl Sender {
+    async fn handshake<S>(stream: S, h2: bool) -> hyper::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let io = TokioIo::new(stream);
+
+        if h2 {
+            let (sender, conn) = http2::handshake(TokioExecutor::new(), io).await?;
+            tokio::spawn(conn);
+            Ok(Sender::Http2(sender))
+        } else {
+            let (sender, conn) = http1::handshake(io).await?;
+            tokio::spawn(conn);
+            Ok(Sender::Http1(sender))
+        }
+    }
+
+    async fn send(&mut self, req: Request<Full<Bytes>>) -> hyper::Result<Response<Incoming>> {
+        match self {
+            Sender::Http1(sender) => sender.send_request(req).await,
+            Sender::Http2(sender) => sender.send_request(req).await,
+        }
+    }
+}
+
+/// Response headers of a single request, before any redirect is followed
+struct Hop {
+    response: Response<Incoming>,
+    remote_ip: IpAddr,
+    timings: PhaseTimings,
+}
+
+/// Http client opening a new connection per request to time every phase, stored in a [MagicPool](crate::magic_pool::MagicPool)
 pub struct HttpClient {
-    client: Client,
+    tls: SslConnector,
 }
 
 impl HttpClient {
     pub fn new() -> Self {
+        let mut builder =
+            SslConnector::builder(SslMethod::tls()).expect("tls connector should be created");
+        builder
+            .set_alpn_protos(b"\x02h2\x08http/1.1")
+            .expect("alpn protocols should be valid");
+
         Self {
-            client: Client::new(),
+            tls: builder.build(),
+        }
+    }
+
+    /// Resolve, connect and send a single request
+    async fn send_once(
+        &self,
+        method: &Method,
+        url: &Url,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Result<Hop, HttpError> {
+        let mut timings = PhaseTimings::default();
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| other_error(format!("unknown port for {url}")))?;
+
+        let (server_name, ip) = match url.host() {
+            Some(Host::Domain(domain)) => {
+                let before = Instant::now();
+                let ip = tokio::net::lookup_host((domain, port))
+                    .await
+                    .map_err(|e| (HttpFailure::DnsFailure, e.to_string()))?
+                    .next()
+                    .map(|addr| addr.ip())
+                    .ok_or_else(|| {
+                        (
+                            HttpFailure::DnsFailure,
+                            format!("no address found for {domain}"),
+                        )
+                    })?;
+                timings.dns = Some(before.elapsed());
+                (domain.to_string(), ip)
+            }
+            Some(Host::Ipv4(ip)) => (ip.to_string(), IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => (ip.to_string(), IpAddr::V6(ip)),
+            None => return Err(other_error(format!("no host in {url}"))),
+        };
+
+        let before = Instant::now();
+        let tcp = TcpStream::connect(SocketAddr::new(ip, port))
+            .await
+            .map_err(|e| (classify_io_error(&e), e.to_string()))?;
+        timings.connect = Some(before.elapsed());
+
+        let mut sender = if url.scheme() == "https" {
+            let before = Instant::now();
+            let ssl = self
+                .tls
+                .configure()
+                .and_then(|config| config.into_ssl(&server_name))
+                .map_err(|e| (HttpFailure::Tls, e.to_string()))?;
+            let mut stream =
+                SslStream::new(ssl, tcp).map_err(|e| (HttpFailure::Tls, e.to_string()))?;
+            Pin::new(&mut stream)
+                .connect()
+                .await
+                .map_err(|e| (HttpFailure::Tls, e.to_string()))?;
+            timings.tls = Some(before.elapsed());
+
+            let h2 = stream.ssl().selected_alpn_protocol() == Some(b"h2");
+            Sender::handshake(stream, h2).await
+        } else {
+            Sender::handshake(tcp, false).await
+        }
+        .map_err(other_error)?;
+
+        let mut builder = Request::builder().method(method.clone());
+        builder = match sender {
+            Sender::Http1(_) => builder.uri(&url[url::Position::BeforePath..]).header(
+                HOST,
+                &url[url::Position::BeforeHost..url::Position::AfterPort],
+            ),
+            Sender::Http2(_) => builder.uri(url.as_str()),
+        };
+        let mut req = builder.body(Full::new(body)).map_err(other_error)?;
+        req.headers_mut().extend(headers.clone());
+
+        let before = Instant::now();
+        let response = sender.send(req).await.map_err(|e| match e.is_timeout() {
+            true => (HttpFailure::Timeout, e.to_string()),
+            false => other_error(e),
+        })?;
+        timings.ttfb = Some(before.elapsed());
+
+        Ok(Hop {
+            response,
+            remote_ip: ip,
+            timings,
+        })
+    }
+
+    /// Read a response body, keeping at most [`MAX_BODY_SIZE`] bytes, or none
+    /// when `keep` is false, the body being only counted
+    async fn read_body(mut body: Incoming, keep: bool) -> Result<ResponseBody, String> {
+        let mut res = ResponseBody {
+            bytes: Vec::new(),
+            size: 0,
+        };
+        let limit = if keep { MAX_BODY_SIZE } else { 0 };
+
+        while let Some(frame) = body.frame().await {
+            if let Ok(chunk) = frame.map_err(|e| e.to_string())?.into_data() {
+                let kept = chunk.len().min(limit - res.bytes.len());
+                res.bytes.extend_from_slice(&chunk[..kept]);
+                res.size += chunk.len() as u64;
+            }
         }
+
+        Ok(res)
     }
 
-    /// Send async an http [`Request`]
-    async fn send(&self, req: Request) -> Option<HttpResult> {
-        let before = std::time::SystemTime::now();
-        let res = self.client.execute(req).await.ok()?;
-        let elapsed = before.elapsed().ok()?;
+    /// Send async an http request, following redirects if needed, and evaluate the response
+    async fn send(&self, ctx: &HttpContext) -> Result<HttpResult, HttpError> {
+        let mut method = ctx.method.clone();
+        let mut url = ctx.url.clone();
+        let mut body = ctx.body.clone().unwrap_or_default();
+        let mut headers = ctx.headers.clone();
+        let mut timings = PhaseTimings::default();
+
+        let before = Instant::now();
+        let mut redirects = 0;
+        let hop = loop {
+            let hop = self
+                .send_once(&method, &url, &headers, body.clone())
+                .await?;
+            timings.add(hop.timings);
+
+            let status = hop.response.status();
+            let location = hop
+                .response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok());
+
+            match location {
+                Some(_) if !ctx.follow_redirects || !status.is_redirection() => break hop,
+                Some(_) if redirects == MAX_REDIRECTS => {
+                    return Err((
+                        HttpFailure::TooManyRedirects,
+                        format!("more than {MAX_REDIRECTS} redirects, last one to {url}"),
+                    ))
+                }
+                Some(location) => {
+                    let rewritten = status == StatusCode::SEE_OTHER
+                        || (method == Method::POST
+                            && matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND));
+                    if rewritten {
+                        method = Method::GET;
+                        body = Bytes::new();
+                    }
+                    redirect_headers(&mut headers, &url, &location, rewritten);
+                    url = location;
+                    redirects += 1;
+                }
+                None => break hop,
+            }
+        };
+        let request_time = before.elapsed();
 
-        Some(HttpResult {
+        let status = hop.response.status().as_u16();
+        let http_version = hop.response.version();
+        let (parts, incoming) = hop.response.into_parts();
+        // the body is always read, to time the download, but only kept for the assertions
+        let before = Instant::now();
+        let body = Self::read_body(incoming, ctx.assertions.needs_body()).await;
+        timings.download = Some(before.elapsed());
+        let assertions = ctx.assertions.evaluate(&parts.headers, &body);
+
+        Ok(HttpResult {
             datetime: Local::now().fixed_offset(),
-            request_time: elapsed,
-            status: res.status().as_u16(),
+            request_time: Some(request_time),
+            outcome: Ok(status),
+            up: ctx
+                .expected_status
+                .iter()
+                .any(|expected| expected.contains(status))
+                && assertions.iter().all(|assertion| assertion.passed),
+            assertions,
+            timings,
+            remote_ip: Some(hop.remote_ip),
+            http_version: Some(format!("{http_version:?}")),
         })
     }
 
-    pub async fn run(&self, req: Request) -> HttpResult {
-        self.send(req).await.unwrap_or_else(|| HttpResult {
+    /// Post a json `body` to `url`, giving up after `timeout`, for the result sinks
+    pub async fn post_json(
+        &self,
+        url: &Url,
+        body: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<StatusCode, String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        let posted = self.send_once(&Method::POST, url, &headers, Bytes::from(body));
+        let hop = tokio::time::timeout(timeout, posted)
+            .await
+            .map_err(|_| format!("no response after {}ms", timeout.as_millis()))?
+            .map_err(|(_, error)| error)?;
+        Ok(hop.response.status())
+    }
+
+    /// Send the request of `ctx`, giving up after `timeout` or the check own timeout if shorter
+    pub async fn run(&self, ctx: HttpContext, timeout: Duration) -> HttpResult {
+        let timeout = ctx.timeout.map_or(timeout, |t| t.min(timeout));
+        let res = tokio::time::timeout(timeout, self.send(&ctx))
+            .await
+            .unwrap_or_else(|_| {
+                Err((
+                    HttpFailure::Timeout,
+                    format!("no response after {}ms", timeout.as_millis()),
+                ))
+            });
+
+        res.unwrap_or_else(|failure| HttpResult {
             datetime: Local::now().fixed_offset(),
-            request_time: Duration::from_millis(i64::MAX as u64),
-            status: 500,
+            request_time: None,
+            outcome: Err(failure),
+            up: false,
+            assertions: Vec::new(),
+            timings: PhaseTimings::default(),
+            remote_ip: None,
+            http_version: None,
         })
     }
 }
@@ -59,10 +406,17 @@ impl Clone for HttpClient {
     }
 }
 
-/// Context of an http [Job](crate::job::Job), [`Request`] wrapper
-#[derive(Debug)]
+/// Context of an http [Job](crate::job::Job)
+#[derive(Debug, Clone)]
 pub struct HttpContext {
-    req: Request,
+    method: Method,
+    url: Url,
+    headers: HeaderMap,
+    body: Option<Bytes>,
+    timeout: Option<Duration>,
+    expected_status: Vec<ExpectedStatus>,
+    follow_redirects: bool,
+    assertions: HttpAssertions,
 }
 
 impl HttpContext {
@@ -80,47 +434,99 @@ impl HttpContext {
     }
 
     pub fn new(url: &str, headers: HashMap<String, String>) -> Self {
-        let mut req = Request::new(Method::GET, Url::parse(url).unwrap());
-        let header_map = req.headers_mut();
+        let mut header_map = HeaderMap::new();
         headers.into_iter().for_each(|(k, v)| {
-            Self::insert_header(header_map, k, v);
+            Self::insert_header(&mut header_map, k, v);
         });
 
-        Self { req }
+        Self {
+            method: Method::GET,
+            url: Url::parse(url).unwrap(),
+            headers: header_map,
+            body: None,
+            timeout: None,
+            expected_status: vec![ExpectedStatus::Range { from: 200, to: 299 }],
+            follow_redirects: true,
+            assertions: HttpAssertions::default(),
+        }
+    }
+
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn with_body(mut self, body: String) -> Self {
+        self.body = Some(Bytes::from(body));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_expected_status(mut self, expected_status: Vec<ExpectedStatus>) -> Self {
+        self.expected_status = expected_status;
+        self
+    }
+
+    pub fn with_follow_redirects(mut self, follow_redirects: bool) -> Self {
+        self.follow_redirects = follow_redirects;
+        self
+    }
+
+    pub fn with_assertions(mut self, assertions: HttpAssertions) -> Self {
+        self.assertions = assertions;
+        self
     }
 
     pub fn url(&self) -> String {
-        self.req.url().to_string()
+        self.url.to_string()
     }
-}
 
-impl Clone for HttpContext {
-    fn clone(&self) -> Self {
-        let mut req = Request::new(Method::GET, self.req.url().clone());
-        let header_map = req.headers_mut();
-        *header_map = self.req.headers().clone();
+    /// Host and port of the url
+    pub fn authority(&self) -> String {
+        self.url[url::Position::BeforeHost..url::Position::AfterPort].to_string()
+    }
 
-        Self { req }
+    pub fn method(&self) -> &Method {
+        &self.method
     }
 }
 
 impl From<HttpCheck> for HttpContext {
     fn from(value: HttpCheck) -> Self {
-        Self::new(&value.uri.to_string(), value.headers)
-    }
-}
+        let mut ctx = Self::new(&value.uri.to_string(), value.headers)
+            .with_method(value.method.into())
+            .with_expected_status(value.expected_status)
+            .with_follow_redirects(value.follow_redirects)
+            .with_assertions(HttpAssertions::new(value.assertions));
+
+        if let Some(body) = value.body {
+            ctx = ctx.with_body(body);
+        }
+        if let Some(timeout) = value.timeout {
+            ctx = ctx.with_timeout(Duration::from_millis(timeout));
+        }
 
-impl Into<Request> for HttpContext {
-    fn into(self) -> Request {
-        self.req
+        ctx
     }
 }
 
 /// Result of an http request ready to be send to warp10
 pub struct HttpResult {
     pub datetime: DateTime<FixedOffset>,
-    pub request_time: Duration,
-    pub status: u16,
+    /// Time until the final response headers, none if there was no response
+    pub request_time: Option<Duration>,
+    /// Final response status or why there was no response
+    pub outcome: Result<u16, HttpError>,
+    /// Whether the status is one of the expected ones and all assertions passed
+    pub up: bool,
+    pub assertions: Vec<HttpAssertionResult>,
+    pub timings: PhaseTimings,
+    pub remote_ip: Option<IpAddr>,
+    pub http_version: Option<String>,
 }
 
 impl Into<CheckResult<HttpFields>> for HttpResult {
@@ -128,7 +534,112 @@ impl Into<CheckResult<HttpFields>> for HttpResult {
         CheckResult {
             timestamp: self.datetime,
             latency: self.request_time,
-            fields: HttpFields::new(self.status),
+            outcome: match &self.outcome {
+                Ok(_) if self.up => CheckOutcome::Up,
+                Err((HttpFailure::Timeout, _)) => CheckOutcome::Timeout,
+                _ => CheckOutcome::Down,
+            },
+            fields: match self.outcome {
+                Ok(status) => HttpFields::new(status, self.up, self.assertions).with_connection(
+                    self.timings.into(),
+                    self.remote_ip,
+                    self.http_version,
+                ),
+                Err((failure, error)) => HttpFields::failed(failure, error),
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (AUTHORIZATION, "Bearer secret"),
+            (COOKIE, "session=secret"),
+            (HOST, "example.com"),
+            (CONTENT_TYPE, "application/json"),
+            (CONTENT_LENGTH, "2"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        headers.insert("x-trace", HeaderValue::from_static("1"));
+        headers
+    }
+
+    fn redirect(from: &str, to: &str, rewritten: bool) -> HeaderMap {
+        let mut headers = headers();
+        redirect_headers(
+            &mut headers,
+            &Url::parse(from).unwrap(),
+            &Url::parse(to).unwrap(),
+            rewritten,
+        );
+        headers
+    }
+
+    #[test]
+    fn same_origin_redirect_keeps_headers() {
+        let headers = redirect("https://example.com/a", "https://example.com:443/b", false);
+        assert_eq!(headers, self::headers());
+    }
+
+    #[test]
+    fn cross_origin_redirect_drops_credentials() {
+        for to in [
+            "http://example.com/a",
+            "https://other.example.com/a",
+            "https://example.com:8443/a",
+        ] {
+            let headers = redirect("https://example.com/a", to, false);
+            for name in [AUTHORIZATION, COOKIE, HOST] {
+                assert!(!headers.contains_key(&name), "{name} kept toward {to}");
+            }
+            assert!(headers.contains_key(CONTENT_TYPE));
+            assert!(headers.contains_key("x-trace"));
+        }
+    }
+
+    #[test]
+    fn rewritten_redirect_drops_body_headers() {
+        let headers = redirect("https://example.com/a", "https://example.com/b", true);
+        assert!(!headers.contains_key(CONTENT_TYPE));
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+        assert!(headers.contains_key(AUTHORIZATION));
+    }
+
+    #[tokio::test]
+    async fn request_phases_are_timed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello",
+                )
+                .await
+                .unwrap();
+        });
+
+        let ctx = HttpContext::new(&format!("http://127.0.0.1:{port}/"), HashMap::new());
+        let result = HttpClient::new().run(ctx, Duration::from_secs(5)).await;
+
+        assert_eq!(result.outcome, Ok(200));
+        assert!(result.up);
+        // no assertion needs the body, its download is timed all the same
+        assert!(result.timings.download.is_some());
+        assert!(result.timings.connect.is_some());
+        assert!(result.timings.ttfb.is_some());
+        assert_eq!(result.timings.dns, None);
+        assert_eq!(result.timings.tls, None);
+    }
+}
@@ -1 +1,513 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::Duration;
 
+use chrono::{DateTime, FixedOffset, Local};
+use cookie_factory::bytes::{be_u16, be_u8};
+use cookie_factory::combinator::slice;
+use cookie_factory::gen_simple;
+use cookie_factory::sequence::tuple;
+use isok_data::check::{Host, IcmpCheck};
+use isok_data::check_kinds::icmp::IcmpFields;
+use isok_data::pulsar_messages::{CheckOutcome, CheckResult};
+use log::{error, warn};
+use nom::bytes::complete::take;
+use nom::combinator::rest;
+use nom::number::complete::{be_u16 as parse_u16, be_u8 as parse_u8};
+use nom::sequence::preceded;
+use nom::IResult;
+use socket2::{Domain as SocketDomain, Protocol, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+use tokio::time::Instant;
+
+use crate::resolve::resolve_host;
+
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Payload of every echo request, replies are expected to carry it back
+const ECHO_PAYLOAD: &[u8] = b"isok-agent icmp echo probe 0123";
+
+/// Icmp echo message (request or reply), without ip header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoPacket {
+    pub kind: u8,
+    pub code: u8,
+    pub checksum: u16,
+    pub identifier: u16,
+    pub sequence: u16,
+    pub payload: Vec<u8>,
+}
+
+impl EchoPacket {
+    pub fn request(ip: &IpAddr, identifier: u16, sequence: u16) -> Self {
+        Self {
+            kind: match ip {
+                IpAddr::V4(_) => ICMPV4_ECHO_REQUEST,
+                IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+            },
+            code: 0,
+            checksum: 0,
+            identifier,
+            sequence,
+            payload: ECHO_PAYLOAD.to_vec(),
+        }
+    }
+
+    /// Serialize the packet, filling the checksum for icmpv4 (the kernel computes the icmpv6 one)
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut packet = gen_simple(
+            tuple((
+                be_u8(self.kind),
+                be_u8(self.code),
+                be_u16(0),
+                be_u16(self.identifier),
+                be_u16(self.sequence),
+                slice(&self.payload),
+            )),
+            Vec::with_capacity(8 + self.payload.len()),
+        )
+        .expect("writing to a vec cannot fail");
+
+        if self.kind == ICMPV4_ECHO_REQUEST {
+            let checksum = checksum(&packet);
+            packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+
+        packet
+    }
+
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
+        let (input, kind) = parse_u8(input)?;
+        let (input, code) = parse_u8(input)?;
+        let (input, checksum) = parse_u16(input)?;
+        let (input, identifier) = parse_u16(input)?;
+        let (input, sequence) = parse_u16(input)?;
+        let (input, payload) = rest(input)?;
+
+        Ok((
+            input,
+            Self {
+                kind,
+                code,
+                checksum,
+                identifier,
+                sequence,
+                payload: payload.to_vec(),
+            },
+        ))
+    }
+
+    fn is_reply_to(&self, request: &EchoPacket, check_identifier: bool) -> bool {
+        let expected_kind = match request.kind {
+            ICMPV4_ECHO_REQUEST => ICMPV4_ECHO_REPLY,
+            _ => ICMPV6_ECHO_REPLY,
+        };
+
+        self.kind == expected_kind
+            && self.sequence == request.sequence
+            && self.payload == request.payload
+            && (!check_identifier || self.identifier == request.identifier)
+    }
+}
+
+/// Internet checksum (RFC 1071)
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Parse an ipv4 header (prepended by raw sockets), returning its ttl
+fn parse_ipv4_header(input: &[u8]) -> IResult<&[u8], u8> {
+    let (_, version_ihl) = parse_u8(input)?;
+    let (input, header) = take(((version_ihl & 0x0f) as usize) * 4)(input)?;
+    let (_, ttl) = preceded(take(8usize), parse_u8)(header)?;
+
+    Ok((input, ttl))
+}
+
+/// Receive a datagram, along with its ttl (hop limit) ancillary data if any
+fn recv_with_ttl(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+    let mut control = [0u8; 64];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // SAFETY: all zero is a valid msghdr
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+
+    // SAFETY: msg points to buffers living for the whole call
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut ttl = None;
+    // SAFETY: control messages are read within msg_controllen, as filled by recvmsg
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let (level, kind) = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+            if (level == libc::IPPROTO_IP && kind == libc::IP_TTL)
+                || (level == libc::IPPROTO_IPV6 && kind == libc::IPV6_HOPLIMIT)
+            {
+                let value = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                ttl = u8::try_from(value).ok();
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((len as usize, ttl))
+}
+
+/// Ask the kernel to attach the ttl (hop limit) of received packets as ancillary data
+fn enable_ttl_reception(socket: &Socket, v6: bool) -> io::Result<()> {
+    let (level, name) = if v6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_RECVTTL)
+    };
+    let enable: libc::c_int = 1;
+
+    // SAFETY: option value is a valid c_int of the given size
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SocketKind {
+    /// Unprivileged ping socket, the kernel handles identifiers and strips ip headers
+    Datagram,
+    /// Raw socket, needs CAP_NET_RAW and receives every icmp packet of the host
+    Raw,
+}
+
+/// Non blocking icmp socket bound to a single target
+struct IcmpSocket {
+    inner: AsyncFd<Socket>,
+    kind: SocketKind,
+    target: SockAddr,
+    v6: bool,
+}
+
+impl IcmpSocket {
+    /// Open an unprivileged datagram socket, falling back to a raw socket
+    fn open(ip: IpAddr) -> io::Result<Self> {
+        let (domain, protocol) = match ip {
+            IpAddr::V4(_) => (SocketDomain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (SocketDomain::IPV6, Protocol::ICMPV6),
+        };
+
+        let (socket, kind) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+            Ok(socket) => (socket, SocketKind::Datagram),
+            Err(e) => {
+                warn!("Can't open datagram icmp socket ({e}), falling back to raw socket");
+                (
+                    Socket::new(domain, Type::RAW, Some(protocol))?,
+                    SocketKind::Raw,
+                )
+            }
+        };
+
+        socket.set_nonblocking(true)?;
+        if let Err(e) = enable_ttl_reception(&socket, ip.is_ipv6()) {
+            warn!("Can't enable ttl reception on icmp socket : {e}");
+        }
+
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+            kind,
+            target: SockAddr::from(SocketAddr::new(ip, 0)),
+            v6: ip.is_ipv6(),
+        })
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        loop {
+            let mut guard = self.inner.writable().await?;
+            match guard.try_io(|inner| inner.get_ref().send_to(packet, &self.target)) {
+                Ok(res) => return res.map(|_| ()),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|inner| recv_with_ttl(inner.as_raw_fd(), buf)) {
+                Ok(res) => return res,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Wait for the reply of an echo request, returning its ttl
+    async fn wait_reply(&self, request: &EchoPacket) -> io::Result<Option<u8>> {
+        let mut buf = [0u8; 1500];
+
+        loop {
+            let (len, ttl) = self.recv(&mut buf).await?;
+            let (data, ttl) = match (self.kind, self.v6) {
+                (SocketKind::Raw, false) => match parse_ipv4_header(&buf[..len]) {
+                    Ok((data, ttl)) => (data, Some(ttl)),
+                    Err(_) => continue,
+                },
+                _ => (&buf[..len], ttl),
+            };
+
+            if let Ok((_, reply)) = EchoPacket::parse(data) {
+                if reply.is_reply_to(request, self.kind == SocketKind::Raw) {
+                    return Ok(ttl);
+                }
+            }
+        }
+    }
+}
+
+/// Context of an icmp [Job](crate::job::Job)
+#[derive(Debug, Clone)]
+pub struct IcmpContext {
+    host: Host,
+    probes: u16,
+}
+
+impl IcmpContext {
+    pub fn new(host: Host, probes: u16) -> Self {
+        Self { host, probes }
+    }
+
+    pub fn host(&self) -> String {
+        match &self.host {
+            Host::IpAddr(ip) => ip.to_string(),
+            Host::Domain(domain) => domain.as_str().to_string(),
+        }
+    }
+
+    /// Send all echo requests one after the other, each waiting for its reply,
+    /// within `timeout` overall, resolution included. `identifier` is only used by raw sockets
+    pub async fn run(&self, identifier: u16, timeout: Duration) -> IcmpResult {
+        let deadline = Instant::now() + timeout;
+        let datetime = Local::now().fixed_offset();
+        let mut result = IcmpResult {
+            datetime,
+            sent: 0,
+            rtts: Vec::with_capacity(self.probes as usize),
+            ttl: None,
+        };
+
+        let ip = match tokio::time::timeout_at(deadline, resolve_host(&self.host)).await {
+            Ok(Ok(ip)) => ip,
+            Ok(Err(e)) => {
+                error!("Can't resolve icmp host {} : {e}", self.host());
+                return result;
+            }
+            Err(_) => {
+                error!("Can't resolve icmp host {} in time", self.host());
+                return result;
+            }
+        };
+
+        let socket = match IcmpSocket::open(ip) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Can't open icmp socket to {ip} : {e}");
+                return result;
+            }
+        };
+
+        let socket = &socket;
+        probe_all(&mut result, self.probes, deadline, |sequence| async move {
+            let request = EchoPacket::request(&ip, identifier, sequence);
+            socket
+                .send(&request.serialize())
+                .await
+                .inspect_err(|e| error!("Can't send icmp echo request to {ip} : {e}"))?;
+            socket
+                .wait_reply(&request)
+                .await
+                .inspect_err(|e| error!("Can't receive icmp echo reply from {ip} : {e}"))
+        })
+        .await;
+
+        result
+    }
+}
+
+/// Run `probes` one after the other, each waiting for its reply for an equal share of
+/// the time left before `deadline`, so that a lost reply does not starve the next probes.
+/// Probes left unsent once `deadline` elapsed are counted as sent and lost
+async fn probe_all<F, Fut>(result: &mut IcmpResult, probes: u16, deadline: Instant, mut probe: F)
+where
+    F: FnMut(u16) -> Fut,
+    Fut: Future<Output = io::Result<Option<u8>>>,
+{
+    let wait = deadline.saturating_duration_since(Instant::now()) / probes.max(1) as u32;
+
+    for sequence in 0..probes {
+        result.sent += 1;
+        let before = Instant::now();
+        if before >= deadline {
+            continue;
+        }
+
+        if let Ok(Ok(ttl)) =
+            tokio::time::timeout_at((before + wait).min(deadline), probe(sequence)).await
+        {
+            result.rtts.push(before.elapsed());
+            result.ttl = ttl.or(result.ttl);
+        }
+    }
+}
+
+impl From<IcmpCheck> for IcmpContext {
+    fn from(value: IcmpCheck) -> Self {
+        Self::new(value.host().clone(), value.probes())
+    }
+}
+
+/// Result of icmp probes ready to be send to warp10
+pub struct IcmpResult {
+    pub datetime: DateTime<FixedOffset>,
+    /// Probes of the run, including those left unsent once the timeout elapsed
+    pub sent: u16,
+    /// Round trip time of each received reply
+    pub rtts: Vec<Duration>,
+    pub ttl: Option<u8>,
+}
+
+impl IcmpResult {
+    /// Number of probes without reply
+    pub fn lost(&self) -> u16 {
+        self.sent - self.rtts.len() as u16
+    }
+}
+
+impl Into<CheckResult<IcmpFields>> for IcmpResult {
+    fn into(self) -> CheckResult<IcmpFields> {
+        let latency = if self.rtts.is_empty() {
+            None
+        } else {
+            Some(self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32)
+        };
+        let rtts = self
+            .rtts
+            .iter()
+            .map(|rtt| rtt.as_micros() as u64)
+            .collect::<Vec<_>>();
+
+        let outcome = match (self.sent, self.lost()) {
+            (0, _) => CheckOutcome::Down,
+            (sent, lost) if sent == lost => CheckOutcome::Timeout,
+            (_, 0) => CheckOutcome::Up,
+            _ => CheckOutcome::Degraded,
+        };
+
+        CheckResult {
+            timestamp: self.datetime,
+            latency,
+            outcome,
+            fields: IcmpFields::new(self.sent, &rtts, self.ttl),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+
+    use super::*;
+
+    fn result() -> IcmpResult {
+        IcmpResult {
+            datetime: Local::now().fixed_offset(),
+            sent: 0,
+            rtts: Vec::new(),
+            ttl: None,
+        }
+    }
+
+    /// Probes answered after 10ms, but those in `lost`
+    async fn probe(lost: &[u16], probes: u16, timeout: Duration) -> CheckResult<IcmpFields> {
+        let mut result = result();
+        probe_all(&mut result, probes, Instant::now() + timeout, |sequence| {
+            let lost = lost.contains(&sequence);
+            async move {
+                if lost {
+                    future::pending::<()>().await;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(Some(64))
+            }
+        })
+        .await;
+        result.into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_lost_reply_does_not_starve_the_next_probes() {
+        let result = probe(&[0], 5, Duration::from_secs(1)).await;
+
+        assert_eq!(result.outcome, CheckOutcome::Degraded);
+        assert_eq!(result.fields.sent, 5);
+        assert_eq!(result.fields.received, 4);
+        assert_eq!(result.fields.packet_loss, 20.0);
+        assert_eq!(result.fields.ttl, Some(64));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probes_left_unsent_are_lost() {
+        // the resolution took the whole timeout
+        let mut result = result();
+        probe_all(&mut result, 5, Instant::now(), |_| async {
+            panic!("probe sent after the deadline")
+        })
+        .await;
+        assert_eq!(result.sent, 5);
+        assert_eq!(result.lost(), 5);
+
+        let result = probe(&[0, 1, 2, 3, 4], 5, Duration::from_secs(1)).await;
+        assert_eq!(result.outcome, CheckOutcome::Timeout);
+        assert_eq!(result.fields.packet_loss, 100.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_probe_fits_in_the_timeout() {
+        let started = Instant::now();
+        let result = probe(&[0, 2], 4, Duration::from_secs(1)).await;
+
+        assert_eq!(result.fields.sent, 4);
+        assert_eq!(result.fields.received, 2);
+        assert!(started.elapsed() <= Duration::from_secs(1));
+    }
+}
@@ -1,113 +1,509 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use slab::Slab;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
-use tokio_util::task::LocalPoolHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::task::{LocalPoolHandle, TaskTracker};
 use uuid::Uuid;
 
 pub use isok_data::check::CheckKind;
-use isok_data::check::CheckOutput;
+use isok_data::check::{CheckOutput, RetryPolicy, TargetLimits};
+use isok_data::check_kinds::dns::DnsFields;
 use isok_data::check_kinds::http::HttpFields;
+use isok_data::check_kinds::icmp::IcmpFields;
+use isok_data::check_kinds::tcp::TcpFields;
+use isok_data::check_kinds::tls::TlsFields;
 pub use isok_data::pulsar_commands::Command;
 use isok_data::pulsar_commands::CommandKind;
 use isok_data::pulsar_messages::{CheckMessage, CheckResult, CheckType};
 
+use crate::dns::DnsContext;
 use crate::http::{HttpClient, HttpContext, HttpResult};
+use crate::icmp::IcmpContext;
 use crate::magic_pool::MagicPool;
+use crate::pool::{stable_hash, AgentPool};
+use crate::retry::{fit_retries, Confirmation};
+use crate::scope::AgentScope;
+use crate::tcp::TcpContext;
+use crate::throttle::{TargetLimiter, Throttle};
+use crate::tls::TlsContext;
+
+/// Threads running the probes of a scheduler, keeping track of the probes in flight
+#[derive(Clone)]
+pub struct ProbePool {
+    pool: LocalPoolHandle,
+    in_flight: TaskTracker,
+}
 
 /// Ressources shared between jobs
 pub struct JobResources {
     pub http_pool: MagicPool<HttpClient>,
+    /// Dns server used by dns checks without their own server
+    pub dns_resolver: SocketAddr,
+    /// Part of the check max latency above which an up result is degraded
+    pub degraded_ratio: f64,
+    /// Limits of the probes toward each target host
+    pub limiter: Arc<TargetLimiter>,
 }
 
 impl Default for JobResources {
     fn default() -> Self {
         JobResources {
             http_pool: MagicPool::with_capacity(1000, 20),
+            dns_resolver: crate::env_get_dns_resolver(),
+            degraded_ratio: crate::env_get_num("DEGRADED_LATENCY_RATIO", 0.8),
+            limiter: Arc::new(TargetLimiter::new(crate::env_get_target_limits())),
         }
     }
 }
 
+/// Timing of a job execution, derived from its jitter and the check max latency
+#[derive(Debug, Clone, Copy)]
+pub struct JobTiming {
+    /// Delay between the scheduler tick and the probe start
+    pub delay: Duration,
+    /// Hard timeout of a probe
+    pub timeout: Duration,
+    /// Latency above which an up result is degraded
+    pub degraded: Duration,
+    /// Probes run again after a failed one, before reporting it
+    pub retry: RetryPolicy,
+}
+
 /// Different job contexts, mapped from [`CheckKind`]
 #[derive(Debug, Clone)]
 pub enum JobKind {
     Http(HttpContext),
+    Icmp(IcmpContext),
+    Tcp(TcpContext),
+    Dns(DnsContext),
+    Tls(TlsContext),
     Dummy,
 }
 
+impl JobKind {
+    /// Type of the results of the job, none for jobs without results
+    pub fn check_type(&self) -> Option<CheckType> {
+        match self {
+            JobKind::Http(_) => Some(CheckType::Http),
+            JobKind::Icmp(_) => Some(CheckType::Icmp),
+            JobKind::Tcp(_) => Some(CheckType::Tcp),
+            JobKind::Dns(_) => Some(CheckType::Dns),
+            JobKind::Tls(_) => Some(CheckType::Tls),
+            JobKind::Dummy => None,
+        }
+    }
+
+    /// Host probed by the job, as an authority or an ip
+    pub fn target(&self, dns_resolver: SocketAddr) -> Option<String> {
+        match self {
+            JobKind::Http(ctx) => Some(ctx.authority()),
+            JobKind::Icmp(ctx) => Some(ctx.host()),
+            JobKind::Tcp(ctx) => Some(ctx.target()),
+            JobKind::Dns(ctx) => Some(ctx.server(dns_resolver).to_string()),
+            JobKind::Tls(ctx) => Some(ctx.target()),
+            JobKind::Dummy => None,
+        }
+    }
+}
+
+/// Sends the results of a job, tagged with its check type
+#[derive(Debug, Clone)]
+pub struct ResultSender {
+    check_type: CheckType,
+    sender: mpsc::Sender<(CheckType, CheckMessage)>,
+}
+
+impl ResultSender {
+    pub fn new(check_type: CheckType, sender: mpsc::Sender<(CheckType, CheckMessage)>) -> Self {
+        Self { check_type, sender }
+    }
+
+    async fn send(&self, check_message: CheckMessage) {
+        let _ = self.sender.send((self.check_type, check_message)).await;
+    }
+}
+
 /// Everything you need to execute a job
 #[derive(Debug, Clone)]
 pub struct Job {
     id: Uuid,
     kind: JobKind,
+    max_latency: Duration,
+    /// Delay of the probe within its scheduler slot
+    jitter: Duration,
+    /// Limits of the check toward its target, over the agent ones
+    limits: TargetLimits,
+    retry: RetryPolicy,
 }
 
 impl Job {
+    /// Delay the probe by `jitter` after its scheduler tick
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
     /// Execute a dummy job (for test only)
-    fn execute_dummy(id: &Uuid, task_pool: &LocalPoolHandle) {
+    fn execute_dummy(id: &Uuid, task_pool: &ProbePool) {
         let borowed_id = id.clone();
         let process = async move {
             info!("Check dummy {borowed_id} has been trigerred !");
         };
 
         info!("Triggering check {id}...");
-        task_pool.spawn_pinned(|| process);
+        Self::spawn_delayed(task_pool, Duration::ZERO, None, process);
     }
 
     /// Execute a http job
     fn execute_http(
         id: &Uuid,
         ctx: HttpContext,
-        task_pool: &LocalPoolHandle,
+        task_pool: &ProbePool,
         resources: &mut JobResources,
-        pulsar_sender: mpsc::Sender<CheckMessage>,
+        timing: JobTiming,
+        throttle: Option<Throttle>,
+        results: ResultSender,
         agent_id: String,
     ) {
         let borrowed_id = id.clone();
-        let borrowed_req = ctx.clone().into();
+        let borrowed_ctx = ctx.clone();
         let checkout = resources.http_pool.get();
 
         let process = async move {
-            let http_result = checkout.run(borrowed_req).await;
+            let (checkout, ctx, agent_id) = (&checkout, &borrowed_ctx, &agent_id);
+            let probe = || async move {
+                let http_result = checkout.run(ctx.clone(), timing.timeout).await;
 
-            info!(
-                "Check http {borrowed_id} has been trigerred with status {} and response time {} !",
-                http_result.status,
-                http_result.request_time.as_millis()
-            );
+                match &http_result.outcome {
+                    Ok(status) => info!(
+                        "Check http {borrowed_id} has been trigerred with status {status} ({}) and response time {} !",
+                        if http_result.up { "up" } else { "down" },
+                        http_result.request_time.unwrap_or_default().as_millis()
+                    ),
+                    Err((failure, e)) => info!(
+                        "Check http {borrowed_id} has been trigerred and failed ({failure:?}) : {e}"
+                    ),
+                }
+
+                http_result
+                    .assertions
+                    .iter()
+                    .filter_map(|assertion| assertion.message.as_ref())
+                    .for_each(|message| {
+                        info!("Check http {borrowed_id} assertion failed : {message}")
+                    });
+
+                let answered = http_result.outcome.is_ok();
+                let check_result: CheckResult<HttpFields> = http_result.into();
+                let check_result = check_result.with_degraded_threshold(timing.degraded);
+                (
+                    check_result.to_message(borrowed_id, agent_id.clone()),
+                    answered,
+                )
+            };
+
+            let confirmation = Confirmation::new(borrowed_id, timing.retry);
+            results.send(confirmation.run(probe).await).await;
+        };
+
+        info!(
+            "Triggering check http {id} : {} {} ...",
+            ctx.method(),
+            ctx.url()
+        );
+        Self::spawn_delayed(task_pool, timing.delay, throttle, process);
+    }
+
+    /// Execute an icmp job
+    fn execute_icmp(
+        id: &Uuid,
+        ctx: IcmpContext,
+        task_pool: &ProbePool,
+        timing: JobTiming,
+        throttle: Option<Throttle>,
+        results: ResultSender,
+        agent_id: String,
+    ) {
+        let borrowed_id = id.clone();
+        let host = ctx.host();
+        let identifier = u16::from_be_bytes([id.as_bytes()[0], id.as_bytes()[1]]);
+
+        let process = async move {
+            let (ctx, agent_id) = (&ctx, &agent_id);
+            let probe = || async move {
+                let icmp_result = ctx.run(identifier, timing.timeout).await;
+
+                info!(
+                    "Check icmp {borrowed_id} has been trigerred with {}/{} lost probes !",
+                    icmp_result.lost(),
+                    icmp_result.sent
+                );
+
+                let answered = false;
+                let check_result: CheckResult<IcmpFields> = icmp_result.into();
+                let check_result = check_result.with_degraded_threshold(timing.degraded);
+                (
+                    check_result.to_message(borrowed_id, agent_id.clone()),
+                    answered,
+                )
+            };
+
+            let confirmation = Confirmation::new(borrowed_id, timing.retry);
+            results.send(confirmation.run(probe).await).await;
+        };
+
+        info!("Triggering check icmp {id} at {host} ...");
+        Self::spawn_delayed(task_pool, timing.delay, throttle, process);
+    }
+
+    /// Execute a tcp job
+    fn execute_tcp(
+        id: &Uuid,
+        ctx: TcpContext,
+        task_pool: &ProbePool,
+        timing: JobTiming,
+        throttle: Option<Throttle>,
+        results: ResultSender,
+        agent_id: String,
+    ) {
+        let borrowed_id = id.clone();
+        let target = ctx.target();
+
+        let process = async move {
+            let (ctx, agent_id) = (&ctx, &agent_id);
+            let probe = || async move {
+                let tcp_result = ctx.run(timing.timeout).await;
+
+                match &tcp_result.outcome {
+                    Ok(()) => info!(
+                        "Check tcp {borrowed_id} has been trigerred with connect time {} !",
+                        tcp_result.elapsed.as_millis()
+                    ),
+                    Err((failure, e)) => info!(
+                        "Check tcp {borrowed_id} has been trigerred and failed ({failure:?}) : {e}"
+                    ),
+                }
+
+                let answered = tcp_result.outcome.is_ok();
+                let check_result: CheckResult<TcpFields> = tcp_result.into();
+                let check_result = check_result.with_degraded_threshold(timing.degraded);
+                (
+                    check_result.to_message(borrowed_id, agent_id.clone()),
+                    answered,
+                )
+            };
+
+            let confirmation = Confirmation::new(borrowed_id, timing.retry);
+            results.send(confirmation.run(probe).await).await;
+        };
+
+        info!("Triggering check tcp {id} at {target} ...");
+        Self::spawn_delayed(task_pool, timing.delay, throttle, process);
+    }
+
+    /// Execute a dns job
+    fn execute_dns(
+        id: &Uuid,
+        ctx: DnsContext,
+        task_pool: &ProbePool,
+        resources: &mut JobResources,
+        timing: JobTiming,
+        throttle: Option<Throttle>,
+        results: ResultSender,
+        agent_id: String,
+    ) {
+        let borrowed_id = id.clone();
+        let server = ctx.server(resources.dns_resolver);
+        let domain = ctx.domain().to_string();
 
-            let check_result: CheckResult<HttpFields> = http_result.into();
-            let check_message: CheckMessage = check_result.to_message(borrowed_id, agent_id);
+        let process = async move {
+            let (ctx, agent_id) = (&ctx, &agent_id);
+            let probe = || async move {
+                let dns_result = ctx.run(server, timing.timeout).await;
 
-            let _ = pulsar_sender.send(check_message).await;
+                match &dns_result.outcome {
+                    Ok(response) => info!(
+                        "Check dns {borrowed_id} has been trigerred with rcode {:?}, {} answers and response time {} !",
+                        response.rcode,
+                        response.answers.len(),
+                        dns_result.elapsed.as_millis()
+                    ),
+                    Err(e) => info!("Check dns {borrowed_id} has been trigerred and failed : {e}"),
+                }
+
+                let answered = dns_result.outcome.is_ok();
+                let check_result: CheckResult<DnsFields> = dns_result.into();
+                let check_result = check_result.with_degraded_threshold(timing.degraded);
+                (
+                    check_result.to_message(borrowed_id, agent_id.clone()),
+                    answered,
+                )
+            };
+
+            let confirmation = Confirmation::new(borrowed_id, timing.retry);
+            results.send(confirmation.run(probe).await).await;
         };
 
-        info!("Triggering check http {id} at {} ...", ctx.url());
-        task_pool.spawn_pinned(|| process);
+        info!("Triggering check dns {id} for {domain} at {server} ...");
+        Self::spawn_delayed(task_pool, timing.delay, throttle, process);
+    }
+
+    /// Execute a tls job
+    fn execute_tls(
+        id: &Uuid,
+        ctx: TlsContext,
+        task_pool: &ProbePool,
+        timing: JobTiming,
+        throttle: Option<Throttle>,
+        results: ResultSender,
+        agent_id: String,
+    ) {
+        let borrowed_id = id.clone();
+        let target = ctx.target();
+
+        let process = async move {
+            let (ctx, agent_id) = (&ctx, &agent_id);
+            let probe = || async move {
+                let tls_result = ctx.run(timing.timeout).await;
+                let answered = tls_result.outcome.is_ok();
+                let check_result: CheckResult<TlsFields> = tls_result.into();
+                let check_result = check_result.with_degraded_threshold(timing.degraded);
+
+                match &check_result.fields.error {
+                    None => info!(
+                        "Check tls {borrowed_id} has been trigerred with {} days until expiry ({}) !",
+                        check_result
+                            .fields
+                            .days_until_expiry
+                            .map(|days| days.to_string())
+                            .unwrap_or_else(|| "unknown".to_string()),
+                        check_result.fields.expiry_status().as_str()
+                    ),
+                    Some(e) => info!("Check tls {borrowed_id} has been trigerred and failed : {e}"),
+                }
+
+                (
+                    check_result.to_message(borrowed_id, agent_id.clone()),
+                    answered,
+                )
+            };
+
+            let confirmation = Confirmation::new(borrowed_id, timing.retry);
+            results.send(confirmation.run(probe).await).await;
+        };
+
+        info!("Triggering check tls {id} at {target} ...");
+        Self::spawn_delayed(task_pool, timing.delay, throttle, process);
+    }
+
+    /// Spawn a probe once its jitter delay has elapsed and its target is under its limits
+    fn spawn_delayed<F>(
+        task_pool: &ProbePool,
+        delay: Duration,
+        throttle: Option<Throttle>,
+        process: F,
+    ) where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let probe = task_pool.in_flight.track_future(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let _permit = match throttle {
+                Some(throttle) => match throttle.acquire().await {
+                    Some(permit) => Some(permit),
+                    None => return,
+                },
+                None => None,
+            };
+            process.await
+        });
+        task_pool.pool.spawn_pinned(move || probe);
     }
 
     /// Execute a job
     pub fn execute(
         &self,
-        task_pool: &LocalPoolHandle,
+        task_pool: &ProbePool,
         resources: &mut JobResources,
-        pulsar_sender: mpsc::Sender<CheckMessage>,
+        pulsar_sender: mpsc::Sender<(CheckType, CheckMessage)>,
         agent_id: String,
+        metrics: &Arc<SchedulerMetrics>,
     ) {
+        let timing = JobTiming {
+            delay: self.jitter,
+            timeout: self.max_latency,
+            degraded: self.max_latency.mul_f64(resources.degraded_ratio),
+            retry: self.retry,
+        };
+
+        let throttle = self.kind.target(resources.dns_resolver).map(|target| {
+            let limiter = &resources.limiter;
+            limiter.throttle(self.id, target, self.limits, Arc::clone(metrics))
+        });
+        let results = match self.kind.check_type() {
+            Some(check_type) => ResultSender::new(check_type, pulsar_sender),
+            None => return Self::execute_dummy(&self.id, task_pool),
+        };
+
         match &self.kind {
-            JobKind::Dummy => Self::execute_dummy(&self.id, task_pool),
             JobKind::Http(ctx) => Self::execute_http(
                 &self.id,
                 ctx.clone(),
                 task_pool,
                 resources,
-                pulsar_sender,
+                timing,
+                throttle,
+                results,
                 agent_id,
             ),
+            JobKind::Icmp(ctx) => Self::execute_icmp(
+                &self.id,
+                ctx.clone(),
+                task_pool,
+                timing,
+                throttle,
+                results,
+                agent_id,
+            ),
+            JobKind::Tcp(ctx) => Self::execute_tcp(
+                &self.id,
+                ctx.clone(),
+                task_pool,
+                timing,
+                throttle,
+                results,
+                agent_id,
+            ),
+            JobKind::Dns(ctx) => Self::execute_dns(
+                &self.id,
+                ctx.clone(),
+                task_pool,
+                resources,
+                timing,
+                throttle,
+                results,
+                agent_id,
+            ),
+            JobKind::Tls(ctx) => Self::execute_tls(
+                &self.id,
+                ctx.clone(),
+                task_pool,
+                timing,
+                throttle,
+                results,
+                agent_id,
+            ),
+            JobKind::Dummy => {}
         }
     }
 }
@@ -116,10 +512,30 @@ impl From<CheckOutput> for Job {
     fn from(value: CheckOutput) -> Self {
         let kind = match value.kind {
             CheckKind::Http(http) => JobKind::Http(HttpContext::from(http)),
-            _ => JobKind::Dummy,
+            CheckKind::Icmp(icmp) => JobKind::Icmp(IcmpContext::from(icmp)),
+            CheckKind::Tcp(tcp) => JobKind::Tcp(TcpContext::from(tcp)),
+            CheckKind::Dns(dns) => JobKind::Dns(DnsContext::from(dns)),
+            CheckKind::Tls(tls) => JobKind::Tls(TlsContext::from(tls)),
         };
 
-        Self { id: value.id, kind }
+        // a failure is confirmed before the next probe of the check starts
+        let interval = Duration::from_secs(value.interval as u64).max(SLOT_DURATION);
+        let retry = fit_retries(value.retry, value.max_latency, interval);
+        if retry.retries < value.retry.retries {
+            warn!(
+                "Check {} retries lowered to {} to fit within its interval",
+                value.id, retry.retries
+            );
+        }
+
+        Self {
+            id: value.id,
+            kind,
+            max_latency: value.max_latency,
+            jitter: Duration::ZERO,
+            limits: value.limits,
+            retry,
+        }
     }
 }
 
@@ -129,21 +545,123 @@ pub struct JobLocation {
     position: usize,
 }
 
+/// What a [`JobScheduler`] does with ticks missed because the runtime was busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissedTicks {
+    /// Run missed slots as soon as possible, one after the other
+    CatchUp,
+    /// Drop missed slots, their jobs run again on the next round
+    Skip,
+}
+
+impl From<MissedTicks> for MissedTickBehavior {
+    fn from(value: MissedTicks) -> Self {
+        match value {
+            MissedTicks::CatchUp => MissedTickBehavior::Burst,
+            MissedTicks::Skip => MissedTickBehavior::Skip,
+        }
+    }
+}
+
+impl FromStr for MissedTicks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "catch_up" => Ok(MissedTicks::CatchUp),
+            "skip" => Ok(MissedTicks::Skip),
+            other => Err(format!("unknown missed ticks policy {other}")),
+        }
+    }
+}
+
+/// Jobs and tick execution lag of a [`JobScheduler`], lags in microseconds
+#[derive(Debug, Default)]
+pub struct SchedulerMetrics {
+    jobs: AtomicU64,
+    ticks: AtomicU64,
+    skipped_ticks: AtomicU64,
+    last_lag: AtomicU64,
+    max_lag: AtomicU64,
+}
+
+impl SchedulerMetrics {
+    fn record_tick(&self, lag: Duration, skipped: u64) {
+        let lag = lag.as_micros() as u64;
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.skipped_ticks.fetch_add(skipped, Ordering::Relaxed);
+        self.last_lag.store(lag, Ordering::Relaxed);
+        self.max_lag.fetch_max(lag, Ordering::Relaxed);
+    }
+
+    /// Jobs held by the scheduler, pooled agents only run part of them
+    pub fn jobs(&self) -> u64 {
+        self.jobs.load(Ordering::Relaxed)
+    }
+
+    /// Ticks executed so far
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    /// Ticks dropped by the [`MissedTicks::Skip`] policy
+    pub fn skipped_ticks(&self) -> u64 {
+        self.skipped_ticks.load(Ordering::Relaxed)
+    }
+
+    /// Probe of the scheduler delayed by the limits of its target
+    pub fn record_throttle(&self, lag: Duration) {
+        let lag = lag.as_micros() as u64;
+        self.last_lag.fetch_max(lag, Ordering::Relaxed);
+        self.max_lag.fetch_max(lag, Ordering::Relaxed);
+    }
+
+    /// Delay between the last tick deadline and its execution, or the start of
+    /// its longest probe delayed by target limits
+    pub fn last_lag(&self) -> Duration {
+        Duration::from_micros(self.last_lag.load(Ordering::Relaxed))
+    }
+
+    pub fn max_lag(&self) -> Duration {
+        Duration::from_micros(self.max_lag.load(Ordering::Relaxed))
+    }
+}
+
+/// Time between two scheduler slots
+pub const SLOT_DURATION: Duration = Duration::from_secs(1);
+
+/// Metrics of the schedulers of an agent, by check frequency
+pub type SchedulerRegistry = Arc<RwLock<HashMap<Duration, Arc<SchedulerMetrics>>>>;
+
+/// Settings shared by every [`JobScheduler`] of an agent
+#[derive(Debug, Clone)]
+pub struct SchedulerOptions {
+    pub task_pool_size: usize,
+    pub missed_ticks: MissedTicks,
+    /// Upper bound of the delay added to probes within their slot
+    pub max_jitter: Duration,
+    /// Pool sharing checks with other agents, none to run every check
+    pub pool: Option<Arc<RwLock<AgentPool>>>,
+}
+
+/// Timing wheel of `range` slots, one slot being executed every `wait`
 pub struct JobScheduler {
     frequency: Duration,
-    fill_cursor: usize,
-    empty_slot: Vec<usize>,
     jobs: Arc<Mutex<Vec<Slab<Job>>>>,
-    _process: JoinHandle<()>,
+    metrics: Arc<SchedulerMetrics>,
+    /// Kept outside of the ticking task, so that stopping ticks does not drop probes in flight
+    _task_pool: ProbePool,
+    process: JoinHandle<()>,
 }
 
 impl JobScheduler {
     pub fn new(
         range: usize,
         wait: Duration,
+        options: SchedulerOptions,
         resources: Arc<Mutex<JobResources>>,
-        pulsar_sender: mpsc::Sender<CheckMessage>,
-        task_pool_size: usize,
+        in_flight: TaskTracker,
+        pulsar_sender: mpsc::Sender<(CheckType, CheckMessage)>,
         agent_id: String,
     ) -> Self {
         let jobs = {
@@ -157,63 +675,112 @@ impl JobScheduler {
 
         let jobs = Arc::new(Mutex::new(jobs));
         let job_list = Arc::clone(&jobs);
+        let metrics = Arc::new(SchedulerMetrics::default());
+        let tick_metrics = Arc::clone(&metrics);
+        let SchedulerOptions {
+            task_pool_size,
+            missed_ticks,
+            pool,
+            ..
+        } = options;
+        let task_pool = ProbePool {
+            pool: LocalPoolHandle::new(task_pool_size),
+            in_flight,
+        };
+        let tick_task_pool = task_pool.clone();
 
         let process = async move {
-            let task_pool = LocalPoolHandle::new(task_pool_size);
-            let pulsar_sender: mpsc::Sender<CheckMessage> = pulsar_sender;
-            let mut time_cursor = 0;
+            let task_pool = tick_task_pool;
+            let pulsar_sender: mpsc::Sender<(CheckType, CheckMessage)> = pulsar_sender;
+            // ticks are numbered from the unix epoch, so agents of a pool share
+            // slots, and slots are derived from tick deadlines, so late ticks
+            // never shift the wheel
+            let since_epoch = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System should have time");
+            let tick_time =
+                |tick: u64| Duration::from_nanos((wait.as_nanos() * tick as u128) as u64);
+            let first_tick = (since_epoch.as_nanos() / wait.as_nanos()) as u64 + 1;
+            let start = Instant::now() + tick_time(first_tick).saturating_sub(since_epoch);
+            let mut interval = tokio::time::interval_at(start, wait);
+            interval.set_missed_tick_behavior(missed_ticks.into());
+            let mut next_tick = first_tick;
 
             loop {
-                let now = SystemTime::now();
-                if let Ok(mut jl) = job_list.lock() {
+                let deadline = interval.tick().await;
+                let lag = deadline.elapsed();
+                let tick = first_tick + ((deadline - start).as_nanos() / wait.as_nanos()) as u64;
+                tick_metrics.record_tick(lag, tick - next_tick);
+                next_tick = tick + 1;
+
+                if lag > wait {
+                    warn!("Scheduler {}s is running {}ms late", range, lag.as_millis());
+                }
+
+                // jobs only spawn their probes, locks are never held across an await
+                let time_cursor = (tick % range as u64) as usize;
+                let ring = pool.as_ref().and_then(|pool| {
+                    let time = tick_time(tick).as_secs() as i64;
+                    pool.read().ok().map(|pool| pool.ring_at(time))
+                });
+                if let (Ok(mut jl), Ok(mut resources)) = (job_list.lock(), resources.lock()) {
                     for (_, j) in &mut jl[time_cursor] {
-                        if let Ok(mut resources) = resources.lock() {
-                            j.execute(
-                                &task_pool,
-                                &mut resources,
-                                pulsar_sender.clone(),
-                                agent_id.clone(),
-                            )
+                        if ring
+                            .as_ref()
+                            .is_some_and(|ring| ring.owner(&j.id) != Some(agent_id.as_str()))
+                        {
+                            continue;
                         }
-                    }
-                }
 
-                if time_cursor + 1 == range {
-                    time_cursor = 0;
-                } else {
-                    time_cursor += 1;
+                        j.execute(
+                            &task_pool,
+                            &mut resources,
+                            pulsar_sender.clone(),
+                            agent_id.clone(),
+                            &tick_metrics,
+                        )
+                    }
                 }
-                std::thread::sleep(wait - now.elapsed().expect("System should have time"));
             }
         };
 
         Self {
             frequency: Duration::from_secs(range as u64),
-            fill_cursor: 0,
-            empty_slot: Vec::new(),
             jobs,
-            _process: tokio::task::spawn(process),
+            metrics,
+            _task_pool: task_pool,
+            process: tokio::task::spawn(process),
         }
     }
 
-    pub fn add_job(&mut self, job: Job) -> (usize, usize) {
-        let mut cursor = self.fill_cursor;
+    /// Stop ticking, probes already spawned keep running
+    pub fn stop(&self) {
+        self.process.abort();
+    }
+
+    pub fn metrics(&self) -> Arc<SchedulerMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Add a job to the slot picked by `placement`, returns its slot and position
+    pub fn add_job(&mut self, job: Job, placement: u64) -> (usize, usize) {
         let mut position = 0;
+        let mut offset = 0;
         if let Ok(mut jobs) = self.jobs.lock() {
-            if self.empty_slot.is_empty() {
-                position = jobs[cursor].insert(job);
-                if self.fill_cursor + 1 == jobs.len() {
-                    self.fill_cursor = 0;
-                } else {
-                    self.fill_cursor += 1;
-                }
-            } else {
-                cursor = self.empty_slot.pop().unwrap();
-                position = jobs[cursor].insert(job);
-            }
+            offset = (placement % jobs.len() as u64) as usize;
+            position = jobs[offset].insert(job);
+            self.metrics.jobs.fetch_add(1, Ordering::Relaxed);
         }
 
-        (cursor, position)
+        (offset, position)
+    }
+
+    /// Number of jobs in each slot
+    pub fn slot_loads(&self) -> Vec<usize> {
+        self.jobs
+            .lock()
+            .map(|jobs| jobs.iter().map(Slab::len).collect())
+            .unwrap_or_default()
     }
 
     pub fn remove_job(&mut self, jl: JobLocation, id: Uuid) {
@@ -232,7 +799,7 @@ impl JobScheduler {
 
             if slab.contains(jl.position) {
                 slab.remove(jl.position);
-                self.empty_slot.push(jl.offset);
+                self.metrics.jobs.fetch_sub(1, Ordering::Relaxed);
             } else {
                 error!(
                     "Could not find a job {id} at jobscheduler {} at offset {} at position {}",
@@ -247,62 +814,186 @@ impl JobScheduler {
     }
 }
 
+/// Stable hash of a check for an agent or a pool, so each agent spreads its checks
+/// over the scheduler slots and probes a target at its own time, while agents of
+/// a pool agree on the slot of every check
+fn placement(check_id: &Uuid, key: &str) -> u64 {
+    stable_hash(&[check_id.as_bytes(), key.as_bytes()])
+}
+
 /// App main state handling pulsar commands ([`Command`]), storing jobs ([`Job`]) and job ressources ([`JobResources`])
 pub struct JobsHandler {
     resources: Arc<Mutex<JobResources>>,
     checks: HashMap<Uuid, JobLocation>,
     jobs: HashMap<Duration, JobScheduler>,
-    scheduler_task_pool_size: usize,
-    pulsar_sender: mpsc::Sender<CheckMessage>,
+    metrics: SchedulerRegistry,
+    options: SchedulerOptions,
+    /// Pool name in pooled mode, agent id otherwise
+    placement_key: String,
+    /// Regions and labels filtering the checks to run
+    scope: AgentScope,
+    pulsar_sender: mpsc::Sender<(CheckType, CheckMessage)>,
+    /// Probes spawned by every scheduler and not finished yet
+    in_flight: TaskTracker,
     agent_id: String,
 }
 
 impl JobsHandler {
     pub fn new(
         resources: JobResources,
-        pulsar_sender: mpsc::Sender<CheckMessage>,
-        scheduler_task_pool_size: usize,
+        pulsar_sender: mpsc::Sender<(CheckType, CheckMessage)>,
+        options: SchedulerOptions,
+        scope: AgentScope,
         agent_id: String,
     ) -> Self {
         Self {
             resources: Arc::new(Mutex::new(resources)),
             checks: HashMap::new(),
             jobs: HashMap::new(),
-            scheduler_task_pool_size,
+            metrics: SchedulerRegistry::default(),
+            placement_key: match &options.pool {
+                Some(pool) => pool
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .name()
+                    .to_string(),
+                None => agent_id.clone(),
+            },
+            options: SchedulerOptions {
+                max_jitter: options.max_jitter.min(SLOT_DURATION),
+                ..options
+            },
+            scope,
             pulsar_sender,
+            in_flight: TaskTracker::new(),
             agent_id,
         }
     }
 
+    pub fn resources(&self) -> Arc<Mutex<JobResources>> {
+        Arc::clone(&self.resources)
+    }
+
+    /// Metrics of every scheduler, by check frequency, kept up to date as schedulers are created
+    pub fn scheduler_metrics(&self) -> SchedulerRegistry {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Number of jobs in each slot of every scheduler, by check frequency
+    pub fn slot_histogram(&self) -> impl Iterator<Item = (Duration, Vec<usize>)> + '_ {
+        self.jobs
+            .iter()
+            .map(|(frequency, scheduler)| (*frequency, scheduler.slot_loads()))
+    }
+
+    /// Stop every scheduler and wait up to `deadline` for the probes in flight
+    ///
+    /// The handler is dropped afterwards, along with its queue toward the pulsar sink.
+    pub async fn shutdown(self, deadline: Instant) {
+        for scheduler in self.jobs.values() {
+            scheduler.stop();
+        }
+        self.in_flight.close();
+
+        info!("Waiting for {} probes in flight...", self.in_flight.len());
+        match tokio::time::timeout_at(deadline, self.in_flight.wait()).await {
+            Ok(()) => info!("Every probe in flight has finished"),
+            Err(_) => warn!(
+                "Giving up on {} probes still in flight",
+                self.in_flight.len()
+            ),
+        }
+    }
+
     pub fn handle_command(&mut self, cmd: Command) {
         match cmd.kind() {
             CommandKind::Add(a) => self.add_check(&a.check),
+            CommandKind::Update(u) => self.update_check(&u.check),
             CommandKind::Remove(id) => self.remove_check(id.clone()),
         }
     }
 
     pub fn add_check(&mut self, c: &CheckOutput) {
-        let frequency = Duration::from_secs(c.interval as u64);
+        if !self.scope.accepts(c) {
+            info!(
+                "Ignoring check {} of region {}, out of agent scope",
+                c.id, c.region
+            );
+            return;
+        }
+
+        if self.checks.contains_key(&c.id) {
+            warn!(
+                "Trying to add already scheduled check {}, updating it",
+                c.id
+            );
+            return self.update_check(c);
+        }
+
+        self.schedule(c);
+        info!("Check {} successfully added and scheduled !", c.id);
+    }
+
+    /// Replace a check definition, moving it to another scheduler if its interval changed
+    pub fn update_check(&mut self, c: &CheckOutput) {
+        if !self.scope.accepts(c) {
+            if self.unschedule(c.id) {
+                info!("Check {} moved out of agent scope, removed !", c.id);
+            }
+            return;
+        }
+
+        if !self.unschedule(c.id) {
+            warn!("Trying to update unknown check {}, adding it", c.id);
+        }
+
+        self.schedule(c);
+        info!("Check {} successfully updated !", c.id);
+    }
+
+    pub fn remove_check(&mut self, id: Uuid) {
+        if !self.unschedule(id) {
+            // checks out of the agent scope were never scheduled
+            debug!("Ignoring removal of unknown check {id}");
+            return;
+        }
+
+        info!("Check {} successfully removed !", &id);
+    }
+
+    fn schedule(&mut self, c: &CheckOutput) {
+        // a scheduler needs at least one slot
+        if c.interval == 0 {
+            warn!("Check {} has no interval, running it every slot", c.id);
+        }
+        let frequency = Duration::from_secs(c.interval as u64).max(SLOT_DURATION);
+        let placement = placement(&c.id, &self.placement_key);
+        let jitter = match self.options.max_jitter.as_micros() as u64 {
+            0 => Duration::ZERO,
+            max => Duration::from_micros((placement >> 32) % max),
+        };
 
         if !self.jobs.contains_key(&frequency) {
-            self.jobs.insert(
-                frequency,
-                JobScheduler::new(
-                    frequency.as_secs() as usize,
-                    Duration::from_secs(1),
-                    Arc::clone(&self.resources),
-                    self.pulsar_sender.clone(),
-                    self.scheduler_task_pool_size,
-                    self.agent_id.clone(),
-                ),
+            let scheduler = JobScheduler::new(
+                frequency.as_secs() as usize,
+                SLOT_DURATION,
+                self.options.clone(),
+                Arc::clone(&self.resources),
+                self.in_flight.clone(),
+                self.pulsar_sender.clone(),
+                self.agent_id.clone(),
             );
+            if let Ok(mut metrics) = self.metrics.write() {
+                metrics.insert(frequency, scheduler.metrics());
+            }
+            self.jobs.insert(frequency, scheduler);
         }
 
         let (offset, position) = self
             .jobs
             .get_mut(&frequency)
             .unwrap()
-            .add_job(Job::from(c.clone()));
+            .add_job(Job::from(c.clone()).with_jitter(jitter), placement);
 
         self.checks.insert(
             c.id,
@@ -312,26 +1003,160 @@ impl JobsHandler {
                 position,
             },
         );
-
-        info!("Check {} successfully added and scheduled !", c.id);
     }
 
-    pub fn remove_check(&mut self, id: Uuid) {
-        if !self.checks.contains_key(&id) {
-            warn!("Trying to removing unkown check {id}");
-            return;
-        }
-
-        let jl = self
-            .checks
-            .remove(&id)
-            .expect("Should have a key after checking if key exist");
+    /// Remove a check from its scheduler, returns false if it was not scheduled
+    fn unschedule(&mut self, id: Uuid) -> bool {
+        let Some(jl) = self.checks.remove(&id) else {
+            return false;
+        };
 
         self.jobs
             .get_mut(&jl.frequency)
             .expect("Should have key here")
             .remove_job(jl, id);
 
-        info!("Check {} successfully removed !", &id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(missed_ticks: MissedTicks) -> JobScheduler {
+        let options = SchedulerOptions {
+            task_pool_size: 1,
+            missed_ticks,
+            max_jitter: Duration::ZERO,
+            pool: None,
+        };
+        let (sender, _) = mpsc::channel(1);
+
+        JobScheduler::new(
+            10,
+            SLOT_DURATION,
+            options,
+            Arc::new(Mutex::new(JobResources::default())),
+            TaskTracker::new(),
+            sender,
+            "agent".to_string(),
+        )
+    }
+
+    /// Let the clock run until `ticks` ticks have been executed, the scheduler
+    /// being aligned on the wall clock its first tick happens within a slot
+    async fn wait_ticks(metrics: &SchedulerMetrics, ticks: u64) {
+        while metrics.ticks() < ticks {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Make the scheduler `late` behind its ticks, as if the runtime was busy,
+    /// the clock being half a slot after its last tick
+    async fn overrun(metrics: &SchedulerMetrics, late: Duration) {
+        wait_ticks(metrics, 1).await;
+        tokio::time::sleep(SLOT_DURATION / 2).await;
+        tokio::time::advance(late).await;
+    }
+
+    #[tokio::test]
+    async fn zero_interval_runs_every_slot() {
+        let (sender, _) = mpsc::channel(1);
+        let options = SchedulerOptions {
+            task_pool_size: 1,
+            missed_ticks: MissedTicks::Skip,
+            max_jitter: Duration::ZERO,
+            pool: None,
+        };
+        let mut handler = JobsHandler::new(
+            JobResources::default(),
+            sender,
+            options,
+            AgentScope::new(Default::default(), Default::default()),
+            "agent".to_string(),
+        );
+        let check: CheckOutput = serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "owner_id": Uuid::new_v4(),
+            "kind": {"type": "tcp", "data": {"host": {"IpAddr": "127.0.0.1"}, "port": 80}},
+            "max_latency": {"secs": 1, "nanos": 0},
+            "interval": 0,
+            "region": "eu",
+        }))
+        .unwrap();
+
+        handler.add_check(&check);
+
+        let slots: Vec<_> = handler.slot_histogram().collect();
+        assert_eq!(slots, vec![(SLOT_DURATION, vec![1])]);
+        handler.shutdown(Instant::now()).await;
+    }
+
+    #[test]
+    fn missed_ticks_parse() {
+        assert_eq!("catch_up".parse(), Ok(MissedTicks::CatchUp));
+        assert_eq!("skip".parse(), Ok(MissedTicks::Skip));
+        assert!("burst".parse::<MissedTicks>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ticks_do_not_drift() {
+        let scheduler = scheduler(MissedTicks::Skip);
+        let metrics = scheduler.metrics();
+
+        wait_ticks(&metrics, 1).await;
+        tokio::time::sleep(SLOT_DURATION / 2).await;
+        tokio::time::sleep(SLOT_DURATION * 1000).await;
+
+        // a tick late by even a millisecond would have shifted the following ones
+        assert_eq!(metrics.ticks(), 1001);
+        assert_eq!(metrics.skipped_ticks(), 0);
+        assert!(metrics.max_lag() < Duration::from_millis(1));
+        assert!(metrics.last_lag() < Duration::from_millis(1));
+        scheduler.stop();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn catch_up_bursts_missed_ticks() {
+        let scheduler = scheduler(MissedTicks::CatchUp);
+        let metrics = scheduler.metrics();
+
+        overrun(&metrics, SLOT_DURATION * 5).await;
+        let overran = Instant::now();
+        wait_ticks(&metrics, 6).await;
+
+        // the 5 missed ticks ran right away, the first one 4.5 slots late
+        assert!(overran.elapsed() < Duration::from_millis(10));
+        assert_eq!(metrics.skipped_ticks(), 0);
+        assert!(metrics.max_lag() >= Duration::from_millis(4500));
+        assert!(metrics.max_lag() < Duration::from_millis(4510));
+        assert!(metrics.last_lag() >= Duration::from_millis(500));
+        assert!(metrics.last_lag() < Duration::from_millis(510));
+        scheduler.stop();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_drops_missed_ticks() {
+        let scheduler = scheduler(MissedTicks::Skip);
+        let metrics = scheduler.metrics();
+
+        overrun(&metrics, SLOT_DURATION * 5).await;
+        let overran = Instant::now();
+        wait_ticks(&metrics, 2).await;
+
+        // the first missed tick runs late, the other ones are dropped
+        assert_eq!(metrics.skipped_ticks(), 0);
+        assert!(metrics.last_lag() >= Duration::from_millis(4500));
+        assert!(metrics.last_lag() < Duration::from_millis(4510));
+
+        wait_ticks(&metrics, 3).await;
+
+        // the next tick runs on its own deadline, back on time
+        assert!(overran.elapsed() >= SLOT_DURATION / 2);
+        assert_eq!(metrics.skipped_ticks(), 4);
+        assert!(metrics.last_lag() < Duration::from_millis(1));
+        assert!(metrics.max_lag() >= Duration::from_millis(4500));
+        scheduler.stop();
     }
 }
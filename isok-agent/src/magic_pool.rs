@@ -19,6 +19,15 @@ impl<T: Default + Clone> MagicPool<T> {
         }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.pool.capacity()
+    }
+
+    /// Number of checked out values
+    pub fn used(&self) -> usize {
+        self.pool.used()
+    }
+
     pub fn get(&mut self) -> Checkout<T> {
         if self.pool.used() == self.pool.capacity() {
             self.pool.grow_to(self.pool.capacity() + self.grow_factor);
@@ -1,15 +1,39 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use env_logger::{Builder as Logger, Env};
-use futures::TryStreamExt;
-use log::{error, info};
+use log::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Instant;
 use tokio::{runtime, sync::mpsc};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
-pub use job::{JobResources, JobsHandler};
+pub use heartbeat::HeartbeatSource;
+pub use metrics::{AgentMetrics, MetricsState};
+pub use job::{JobResources, JobsHandler, MissedTicks, SchedulerOptions, SLOT_DURATION};
+use isok_data::bus::{BusKind, MemoryBus, MessageBus, Topic};
+use isok_data::check::TargetLimits;
+use isok_data::pulsar_bus::PulsarBus;
+use isok_data::pulsar_connector::ConnectionStates;
+use isok_data::pulsar_commands::Command;
 use isok_data::pulsar_messages::{CheckMessage, CheckResult, CheckType};
 pub use pulsar_client::{PulsarClient, PulsarConnectionData};
+pub use pool::AgentPool;
+pub use scope::AgentScope;
+pub use sink::{ResultSink, SinkKind, SinkOptions};
+pub use spool::Spool;
+pub use standalone::ChecksWatcher;
 
+/// http response assertions module
+pub mod assertion;
+/// dns resolution check module
+pub mod dns;
+/// agent heartbeats module
+pub mod heartbeat;
 /// http ping module
 pub mod http;
 /// icmp ping module
@@ -18,10 +42,30 @@ pub mod icmp;
 pub mod job;
 /// [MagicPool](crate::magic_pool::MagicPool)'s module
 pub mod magic_pool;
+/// sharded agent pool module
+pub mod pool;
+/// prometheus metrics and health endpoints module
+pub mod metrics;
 /// pulsar related stuff
 pub mod pulsar_client;
+/// host resolution module
+pub mod resolve;
+/// failed probes confirmation module
+pub mod retry;
+/// agent region and labels filtering module
+pub mod scope;
+/// result sinks module
+pub mod sink;
+/// on-disk spool of unsent results module
+pub mod spool;
+/// checks file driven agent module
+pub mod standalone;
 /// tcp ping module
 pub mod tcp;
+/// per target host probe limits module
+pub mod throttle;
+/// tls certificate check module
+pub mod tls;
 mod pulsar_sink;
 
 /// Get env var as string or panic
@@ -51,86 +95,263 @@ pub fn env_get_dns_resolver() -> SocketAddr {
         .expect("Valid DNS_RESOLVER socket address expected")
 }
 
+/// Helper to get TARGET_MAX_CONCURRENT, TARGET_RATE and TARGET_BURST env vars as [`TargetLimits`]
+pub fn env_get_target_limits() -> TargetLimits {
+    TargetLimits {
+        max_concurrent: Some(env_get_num("TARGET_MAX_CONCURRENT", 0)),
+        rate: Some(env_get_num("TARGET_RATE", 0.0)),
+        burst: Some(env_get_num("TARGET_BURST", 1)),
+    }
+}
+
+/// Helper to get RESULT_SINKS and RESULTS_* env vars as [`SinkOptions`]
+pub fn env_get_sinks(default: &str) -> SinkOptions {
+    let kinds = std::env::var("RESULT_SINKS").unwrap_or(default.to_owned());
+    let kinds = SinkKind::parse_list(&kinds)
+        .expect("Valid RESULT_SINKS (pulsar,stdout,file,webhook) expected");
+
+    SinkOptions {
+        results_file: kinds
+            .contains(&SinkKind::File)
+            .then(|| PathBuf::from(env_get("RESULTS_FILE"))),
+        file_max_bytes: env_get_num("RESULTS_FILE_MAX_BYTES", 64 * 1024 * 1024),
+        file_keep: env_get_num("RESULTS_FILE_KEEP", 4),
+        webhook_url: kinds
+            .contains(&SinkKind::Webhook)
+            .then(|| env_get("RESULTS_WEBHOOK_URL")),
+        kinds,
+    }
+}
+
+/// Helper to get AGENT_REGIONS and AGENT_LABELS env vars as an [`AgentScope`]
+pub fn env_get_scope() -> AgentScope {
+    let regions = std::env::var("AGENT_REGIONS").unwrap_or_default();
+    let labels = std::env::var("AGENT_LABELS").unwrap_or_default();
+    AgentScope::parse(&regions, &labels)
+        .expect("Valid AGENT_LABELS (key=value,key=value) expected")
+}
+
+/// Wait for SIGTERM or ctrl-c
+pub async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Can't listen for SIGTERM : {e}");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
 /// Start logger with default log level : info (overridden by env var LOG_LEVEL)
 pub fn init_logger() {
     let env = Env::new().filter_or("LOG_LEVEL", "info");
     Logger::from_env(env).init();
 }
 
-/// Main async process : pulsar consumer loop
+/// Main async process : commands consumer loop
+///
+/// `connections` are the pulsar connections of the bus, none on the memory bus.
 pub async fn main_process(
-    pulsar_connection_data: PulsarConnectionData,
-    task_pools_size: usize,
+    bus: Arc<dyn MessageBus>,
+    connections: Option<ConnectionStates>,
+    options: SchedulerOptions,
+    scope: AgentScope,
+    heartbeat_interval: Duration,
+    metrics_address: Option<String>,
+    sinks: SinkOptions,
+    spool: Option<Spool>,
+    shutdown_timeout: Duration,
     agent_id: String
-) -> Option<()> {
+) {
     let resources = JobResources::default();
-    let (pulsar_sender, pulsar_receiver): (mpsc::Sender<CheckMessage>, mpsc::Receiver<CheckMessage>) =
-        mpsc::channel(512);
-    let mut handler = JobsHandler::new(resources, pulsar_sender, task_pools_size, agent_id);
-
-    info!(
-        "Connecting to pulsar topic {}...",
-        pulsar_client::pulsar_link(&pulsar_connection_data)
-    );
-    let mut pulsar_client = match PulsarClient::new(pulsar_connection_data).await {
-        Some(pc) => {
-            info!("Connected to pulsar topic !");
-            pc
-        }
-        None => {
-            error!("Failed to connect to pulsar topic");
+    let (pulsar_sender, pulsar_receiver): (
+        mpsc::Sender<(CheckType, CheckMessage)>,
+        mpsc::Receiver<(CheckType, CheckMessage)>,
+    ) = mpsc::channel(512);
+    let regions = scope.regions();
+    let pool = options.pool.clone();
+    let queue = pulsar_sender.downgrade();
+    let mut handler =
+        JobsHandler::new(resources, pulsar_sender, options, scope, agent_id.clone());
+
+    let metrics = Arc::new(AgentMetrics::default());
+    if let Some(address) = metrics_address {
+        tokio::task::spawn(metrics::serve(
+            address,
+            MetricsState {
+                metrics: Arc::clone(&metrics),
+                schedulers: handler.scheduler_metrics(),
+                resources: handler.resources(),
+                queue: queue.clone(),
+                spool: spool.as_ref().map(Spool::stats),
+                connections,
+            },
+        ));
+    }
+    let heartbeat_source = HeartbeatSource {
+        agent_id,
+        regions,
+        schedulers: handler.scheduler_metrics(),
+        queue,
+        pool,
+    };
+
+    let leave = CancellationToken::new();
+    let flush = CancellationToken::new();
+
+    let mut result_sinks = match sinks.open() {
+        Ok(result_sinks) => result_sinks,
+        Err(e) => {
+            error!("Failed to open result sinks : {e}");
             std::process::exit(1);
         }
     };
+    if sinks.kinds.contains(&SinkKind::Pulsar) {
+        result_sinks.push(Box::new(pulsar_sink::PulsarSink::new(
+            Arc::clone(&bus),
+            spool,
+            Arc::clone(&metrics),
+        )));
+    }
+    let sink = tokio::task::spawn(sink::dispatch(
+        result_sinks,
+        pulsar_receiver,
+        Arc::clone(&metrics),
+        flush.clone(),
+    ));
+
+    if let Some(pool) = heartbeat_source.pool.clone() {
+        tokio::task::spawn(pool::receive_heartbeats(pool, Arc::clone(&bus)));
+    }
 
-    let http_producer = match pulsar_client.create_producer(CheckType::Http).await {
-        Some(producer) => {
-            info!("Connected to pulsar topic !");
-            producer
+    let pool = heartbeat_source.pool.clone();
+    let heartbeats = tokio::task::spawn(heartbeat::send_heartbeats(
+        heartbeat_source,
+        Arc::clone(&bus),
+        heartbeat_interval,
+        leave.clone(),
+    ));
+
+    let termination = shutdown_signal();
+    tokio::pin!(termination);
+
+    // a new subscription replays the compacted commands, adding known checks again updates them
+    'consume: loop {
+        let subscription = Uuid::new_v4().hyphenated().to_string();
+        let mut commands = tokio::select! {
+            commands = bus.subscribe(Topic::Commands, &subscription) => commands,
+            _ = &mut termination => break 'consume,
+        };
+
+        loop {
+            let received = tokio::select! {
+                received = commands.next() => received,
+                _ = &mut termination => break 'consume,
+            };
+            let Some(payload) = received else {
+                break;
+            };
+
+            commands.ack().await;
+
+            match serde_json::from_slice::<Command>(&payload) {
+                Ok(command) => {
+                    info!("Handling new pulsar command...");
+                    handler.handle_command(command);
+                }
+                Err(e) => {
+                    error!(
+                        "Can't deserialize command [{}] : {e} ",
+                        payload
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                    ()
+                }
+            };
         }
-        None => {
-            error!("Failed to connect to pulsar topic");
+    }
+
+    info!("Shutting down, no more commands are handled");
+    leave.cancel();
+    _ = tokio::time::timeout(heartbeat_interval, heartbeats).await;
+    if let Some(pool) = pool {
+        // checks keep running until the other agents of the pool take them over
+        let handover = pool.read().map(|pool| pool.settle_delay()).unwrap_or_default();
+        info!("Handing checks over to the pool in {}s...", handover.as_secs());
+        tokio::time::sleep(handover + SLOT_DURATION).await;
+    }
+
+    let deadline = Instant::now() + shutdown_timeout;
+    handler.shutdown(deadline).await;
+
+    flush.cancel();
+    match tokio::time::timeout_at(deadline, sink).await {
+        Ok(_) => info!("Agent stopped"),
+        Err(_) => warn!("Agent stopped before every result was delivered"),
+    }
+}
+
+/// Standalone async process : checks loaded from a file, results handed to local sinks
+pub async fn standalone_process(
+    checks_file: PathBuf,
+    sinks: SinkOptions,
+    reload_interval: Duration,
+    options: SchedulerOptions,
+    scope: AgentScope,
+    shutdown_timeout: Duration,
+    agent_id: String
+) {
+    let result_sinks = match sinks.open() {
+        Ok(result_sinks) => result_sinks,
+        Err(e) => {
+            error!("Failed to open result sinks : {e}");
             std::process::exit(1);
         }
     };
+    let (results_sender, results_receiver) = mpsc::channel(512);
+    let mut handler =
+        JobsHandler::new(JobResources::default(), results_sender, options, scope, agent_id);
+    let flush = CancellationToken::new();
+    let sink = tokio::task::spawn(sink::dispatch(
+        result_sinks,
+        results_receiver,
+        Arc::new(AgentMetrics::default()),
+        flush.clone(),
+    ));
 
-    tokio::task::spawn(pulsar_sink::pulsar_sink(http_producer, pulsar_receiver));
-
-    while let Some(msg) = pulsar_client
-        .consumer
-        .try_next()
-        .await
-        .map_err(|e| {
-            error!("Cant receive pulsar message : {e}");
-            e
-        })
-        .ok()?
-    {
-        _ = pulsar_client.consumer.ack(&msg).await.map_err(|e| {
-            error!("Can't acknoledge pulsar message : {e}");
-            e
-        });
+    let mut watcher = ChecksWatcher::new(checks_file);
+    let mut reload = tokio::time::interval(reload_interval);
+    let termination = shutdown_signal();
+    tokio::pin!(termination);
 
-        match msg.deserialize() {
-            Ok(command) => {
-                info!("Handling new pulsar command...");
-                handler.handle_command(command);
-            }
-            Err(e) => {
-                error!(
-                    "Can't deserialize command [{}] : {e} ",
-                    msg.payload
-                        .data
-                        .iter()
-                        .map(ToString::to_string)
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                );
-                ()
-            }
-        };
+    loop {
+        tokio::select! {
+            _ = reload.tick() => watcher.poll(&mut handler),
+            _ = &mut termination => break,
+        }
+    }
+
+    info!("Shutting down, checks file is no longer watched");
+    let deadline = Instant::now() + shutdown_timeout;
+    handler.shutdown(deadline).await;
+
+    flush.cancel();
+    match tokio::time::timeout_at(deadline, sink).await {
+        Ok(_) => info!("Agent stopped"),
+        Err(_) => warn!("Agent stopped before every result was delivered"),
     }
-    Some(())
 }
 
 /// The agent main entry point
@@ -139,22 +360,9 @@ pub fn main() {
 
     let job_number = env_get_num("JOBS", 1);
     let task_pools_size = env_get_num("TASK_POOLS_SIZE", 128);
-
-    let pulsar_address = env_get("PULSAR_ADDRESS");
-    let pulsar_token = env_get("PULSAR_TOKEN");
-    let pulsar_tenant = env_get("PULSAR_TENANT");
-    let pulsar_namespace = env_get("PULSAR_NAMESPACE");
-    let pulsar_topic = env_get("PULSAR_TOPIC");
-
-    let agent_id = env_get("AGENT_ID");
-
-    let pulsar_connection_data = PulsarConnectionData {
-        pulsar_address,
-        pulsar_token,
-        pulsar_tenant,
-        pulsar_namespace,
-        pulsar_consumer_topic: pulsar_topic,
-    };
+    let missed_ticks = env_get_num("MISSED_TICKS", MissedTicks::CatchUp);
+    let max_jitter = Duration::from_millis(env_get_num("JOB_JITTER_MS", 0));
+    let shutdown_timeout = Duration::from_secs(env_get_num("SHUTDOWN_TIMEOUT", 20));
 
     let runtime = runtime::Builder::new_multi_thread()
         .worker_threads(job_number)
@@ -162,10 +370,258 @@ pub fn main() {
         .build()
         .expect("tokio runtime spawn");
 
+    if let Ok(checks_file) = std::env::var("CHECKS_FILE") {
+        let default_sink = match std::env::var("RESULTS_FILE") {
+            Ok(_) => "file",
+            Err(_) => "stdout",
+        };
+        let sinks = env_get_sinks(default_sink);
+        if sinks.kinds.contains(&SinkKind::Pulsar) {
+            panic!("RESULT_SINKS can't hold pulsar in standalone mode");
+        }
+        let reload_interval = Duration::from_secs(env_get_num("CHECKS_RELOAD_INTERVAL", 5));
+        let options = SchedulerOptions {
+            task_pool_size: task_pools_size,
+            missed_ticks,
+            max_jitter,
+            pool: None,
+        };
+
+        info!("Starting standalone agent with {job_number} jobs, running checks of {checks_file}...");
+        runtime.block_on(standalone_process(
+            PathBuf::from(checks_file),
+            sinks,
+            reload_interval,
+            options,
+            env_get_scope(),
+            shutdown_timeout,
+            env_get("AGENT_ID")
+        ));
+        return;
+    }
+
+    let bus_kind = env_get_num("MESSAGE_BUS", BusKind::Pulsar);
+    let agent_id = env_get("AGENT_ID");
+    let scope = env_get_scope();
+    let heartbeat_interval = Duration::from_secs(env_get_num("HEARTBEAT_INTERVAL", 5));
+    let metrics_address = std::env::var("METRICS_ADDRESS").ok();
+    let sinks = env_get_sinks("pulsar");
+    let spool = std::env::var("SPOOL_DIR").ok().map(|dir| {
+        let max_bytes = env_get_num("SPOOL_MAX_BYTES", 256 * 1024 * 1024);
+        let segment_bytes = env_get_num("SPOOL_SEGMENT_BYTES", 8 * 1024 * 1024);
+        match Spool::open(&dir, max_bytes, segment_bytes) {
+            Ok(spool) => {
+                info!("Spooling unsent results in {dir}");
+                spool
+            }
+            Err(e) => {
+                error!("Failed to open spool {dir} : {e}");
+                std::process::exit(1);
+            }
+        }
+    });
+    let pool = std::env::var("AGENT_POOL").ok().map(|name| {
+        info!("Sharing checks with agents of pool {name}");
+        let max_skew = Duration::from_millis(env_get_num("POOL_MAX_CLOCK_SKEW_MS", 2000));
+        Arc::new(RwLock::new(AgentPool::new(
+            name,
+            heartbeat_interval,
+            max_skew,
+        )))
+    });
+    let options = SchedulerOptions {
+        task_pool_size: task_pools_size,
+        missed_ticks,
+        max_jitter,
+        pool,
+    };
+
+    let (bus, connections): (Arc<dyn MessageBus>, _) = match bus_kind {
+        BusKind::Pulsar => {
+            let pulsar_connection_data = PulsarConnectionData {
+                pulsar_address: env_get("PULSAR_ADDRESS"),
+                pulsar_token: env_get("PULSAR_TOKEN"),
+                pulsar_tenant: env_get("PULSAR_TENANT"),
+                pulsar_namespace: env_get("PULSAR_NAMESPACE"),
+                pulsar_consumer_topic: env_get("PULSAR_TOPIC"),
+            };
+
+            let pulsar_client = PulsarClient::new(pulsar_connection_data);
+            let bus = PulsarBus::new(
+                pulsar_client.connector.clone(),
+                &pulsar_client.connection_data.pulsar_tenant,
+                &pulsar_client.connection_data.pulsar_namespace,
+            )
+            .with_commands_topic(&pulsar_client.connection_data.pulsar_consumer_topic);
+
+            info!(
+                "Connecting to pulsar topic {}...",
+                pulsar_client::pulsar_link(&pulsar_client.connection_data)
+            );
+            (Arc::new(bus), Some(pulsar_client.connector.states()))
+        }
+        // commands and results stay within the process, along with the api and offloader running in it
+        BusKind::Memory => {
+            warn!("Running on the in-memory message bus, no pulsar broker is used");
+            (Arc::new(MemoryBus::new()), None)
+        }
+    };
+
     info!("Starting agent with {job_number} jobs...");
     runtime.block_on(main_process(
-        pulsar_connection_data,
-        task_pools_size,
+        bus,
+        connections,
+        options,
+        scope,
+        heartbeat_interval,
+        metrics_address,
+        sinks,
+        spool,
+        shutdown_timeout,
         agent_id
     ));
 }
+
+#[cfg(test)]
+mod tests {
+    use isok_api::commands::PulsarClient as ApiClient;
+    use isok_data::bus::{self, MemoryBus};
+    use isok_data::check::Check;
+    use isok_data::check_kinds::tcp::TcpFields;
+    use isok_data::pulsar_messages::CheckData;
+    use isok_offloader::pulsar_source::{PulsarConnectionData as SourceConnectionData, PulsarSource};
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    /// Kind of a tcp check of a local listener, kept open by the returned listener
+    async fn tcp_check() -> (serde_json::Value, TcpListener) {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let kind = serde_json::json!({
+            "type": "tcp",
+            "data": {"host": {"IpAddr": "127.0.0.1"}, "port": port},
+        });
+        (kind, target)
+    }
+
+    /// Agent handling the commands of `bus`, its results published on `bus`
+    fn agent(bus: &Arc<dyn MessageBus>) -> JobsHandler {
+        let (sender, receiver) = mpsc::channel(16);
+        let options = SchedulerOptions {
+            task_pool_size: 1,
+            missed_ticks: MissedTicks::Skip,
+            max_jitter: Duration::ZERO,
+            pool: None,
+        };
+        let handler = JobsHandler::new(
+            JobResources::default(),
+            sender,
+            options,
+            AgentScope::new(Default::default(), Default::default()),
+            "agent".to_string(),
+        );
+        let metrics = Arc::new(AgentMetrics::default());
+        let sink = pulsar_sink::PulsarSink::new(Arc::clone(bus), None, Arc::clone(&metrics));
+        let sinks: Vec<Box<dyn ResultSink>> = vec![Box::new(sink)];
+        tokio::spawn(sink::dispatch(sinks, receiver, metrics, CancellationToken::new()));
+
+        handler
+    }
+
+    #[tokio::test]
+    async fn add_command_yields_a_result() {
+        let bus: Arc<dyn MessageBus> = Arc::new(MemoryBus::new());
+        let check_id = Uuid::new_v4();
+        let (kind, _target) = tcp_check().await;
+
+        let mut results = bus.subscribe(Topic::Results(CheckType::Tcp), "offloader").await;
+        let command: Command = serde_json::from_value(serde_json::json!({
+            "id": check_id,
+            "kind": {"Add": {"check": {
+                "id": check_id,
+                "owner_id": Uuid::new_v4(),
+                "kind": kind,
+                "max_latency": {"secs": 1, "nanos": 0},
+                "interval": 1,
+                "region": "eu",
+            }}},
+        }))
+        .unwrap();
+        let key = Some(check_id.to_string());
+        let receipt = bus::publish_json(bus.as_ref(), Topic::Commands, key, &command).await;
+        receipt.unwrap().await.unwrap();
+
+        let mut handler = agent(&bus);
+        let mut commands = bus.subscribe(Topic::Commands, "agent").await;
+        let payload = commands.next().await.unwrap();
+        handler.handle_command(serde_json::from_slice(&payload).unwrap());
+
+        let payload = tokio::time::timeout(Duration::from_secs(5), results.next())
+            .await
+            .expect("a result should be published")
+            .unwrap();
+        let result: CheckMessage = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(result.check_id, check_id);
+        assert_eq!(result.agent_id, "agent");
+        handler.shutdown(Instant::now()).await;
+    }
+
+    #[tokio::test]
+    async fn api_check_reaches_the_offloader() {
+        let bus: Arc<dyn MessageBus> = Arc::new(MemoryBus::new());
+        let check_id = Uuid::new_v4();
+        let (kind, _target) = tcp_check().await;
+
+        // offloader
+        let (sender, mut received) = broadcast::channel(16);
+        let connection_data = SourceConnectionData {
+            pulsar_address: String::new(),
+            pulsar_token: String::new(),
+            pulsar_tenant: "isok".to_string(),
+            pulsar_namespace: "checks".to_string(),
+            subscription_uuid: Uuid::new_v4().to_string(),
+        };
+        let mut source = PulsarSource::<TcpFields>::new(
+            Arc::clone(&bus),
+            &connection_data,
+            sender,
+            CheckType::Tcp,
+        );
+        tokio::spawn(async move { source.run().await });
+
+        // api
+        let check: Check = serde_json::from_value(serde_json::json!({
+            "check_id": check_id,
+            "owner_id": Uuid::new_v4(),
+            "kind": kind,
+            "max_latency": {"secs": 1, "nanos": 0},
+            "interval": {"secs": 1, "nanos": 0},
+            "region": "eu",
+            "selector": {},
+            "limits": {},
+            "retry": {},
+            "created_at": chrono::Utc::now(),
+            "updated_at": chrono::Utc::now(),
+            "deleted_at": null,
+        }))
+        .unwrap();
+        ApiClient::new(Arc::clone(&bus)).add_check(check).await;
+
+        // agent
+        let mut handler = agent(&bus);
+        let mut commands = bus.subscribe(Topic::Commands, "agent").await;
+        let payload = commands.next().await.unwrap();
+        handler.handle_command(serde_json::from_slice(&payload).unwrap());
+
+        let data: CheckData<TcpFields> = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .expect("the offloader should get a result")
+            .unwrap();
+        assert_eq!(data.check_id, check_id);
+        assert_eq!(data.agent_id, "agent");
+        assert!(data.fields.connected);
+        handler.shutdown(Instant::now()).await;
+    }
+}
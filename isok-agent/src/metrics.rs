@@ -0,0 +1,455 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use chrono::Utc;
+use log::{error, info};
+use tokio::sync::mpsc;
+
+use isok_data::pulsar_connector::ConnectionStates;
+use isok_data::pulsar_messages::{CheckMessage, CheckOutcome, CheckType};
+
+use crate::job::{JobResources, SchedulerRegistry};
+use crate::spool::SpoolStats;
+
+/// Upper bounds of the probe latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Non cumulative bucket counts, the last one holds values above every bound
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProbeMetrics {
+    executed: AtomicU64,
+    failed: AtomicU64,
+    latency: Histogram,
+}
+
+/// Counters of the agent, updated as results flow toward pulsar
+#[derive(Debug)]
+pub struct AgentMetrics {
+    probes: HashMap<CheckType, ProbeMetrics>,
+    pulsar_send_errors: AtomicU64,
+    /// Results dropped because the queue of a sink was full, by sink name
+    sink_drops: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Default for AgentMetrics {
+    fn default() -> Self {
+        Self {
+            probes: CheckType::ALL
+                .into_iter()
+                .map(|check_type| (check_type, ProbeMetrics::default()))
+                .collect(),
+            pulsar_send_errors: AtomicU64::new(0),
+            sink_drops: Mutex::default(),
+        }
+    }
+}
+
+impl AgentMetrics {
+    /// Count a probe result, failed unless up or degraded
+    pub fn record_probe(&self, check_type: CheckType, message: &CheckMessage) {
+        let Some(probe) = self.probes.get(&check_type) else {
+            return;
+        };
+
+        probe.executed.fetch_add(1, Ordering::Relaxed);
+        if matches!(message.outcome, CheckOutcome::Down | CheckOutcome::Timeout) {
+            probe.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(latency) = message.latency {
+            probe.latency.observe(Duration::from_millis(latency));
+        }
+    }
+
+    pub fn record_send_error(&self) {
+        self.pulsar_send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sink_drop(&self, sink: &'static str) {
+        let mut drops = self.sink_drops.lock().unwrap_or_else(|e| e.into_inner());
+        *drops.entry(sink).or_default() += 1;
+    }
+
+    pub fn sink_drops(&self) -> BTreeMap<&'static str, u64> {
+        self.sink_drops
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn probes(&self) -> impl Iterator<Item = (CheckType, &ProbeMetrics)> {
+        CheckType::ALL
+            .iter()
+            .filter_map(|check_type| Some((*check_type, self.probes.get(check_type)?)))
+    }
+
+    fn render_probes(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE isok_agent_probes_total counter");
+        for (check_type, probe) in self.probes() {
+            let executed = probe.executed.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "isok_agent_probes_total{{kind=\"{check_type}\"}} {executed}"
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE isok_agent_probes_failed_total counter");
+        for (check_type, probe) in self.probes() {
+            let failed = probe.failed.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "isok_agent_probes_failed_total{{kind=\"{check_type}\"}} {failed}"
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE isok_agent_probe_latency_seconds histogram");
+        for (check_type, probe) in self.probes() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&probe.latency.buckets) {
+                cumulative += count.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "isok_agent_probe_latency_seconds_bucket{{kind=\"{check_type}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let count = probe.latency.count.load(Ordering::Relaxed);
+            let sum = probe.latency.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(
+                out,
+                "isok_agent_probe_latency_seconds_bucket{{kind=\"{check_type}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(
+                out,
+                "isok_agent_probe_latency_seconds_sum{{kind=\"{check_type}\"}} {sum}"
+            );
+            let _ = writeln!(
+                out,
+                "isok_agent_probe_latency_seconds_count{{kind=\"{check_type}\"}} {count}"
+            );
+        }
+    }
+}
+
+/// Everything exposed by the metrics listener
+#[derive(Clone)]
+pub struct MetricsState {
+    pub metrics: Arc<AgentMetrics>,
+    pub schedulers: SchedulerRegistry,
+    pub resources: Arc<Mutex<JobResources>>,
+    /// Queue toward the pulsar sink, weak so that it does not keep the sink alive
+    pub queue: mpsc::WeakSender<(CheckType, CheckMessage)>,
+    pub spool: Option<Arc<SpoolStats>>,
+    /// Pulsar connections, none on the memory bus
+    pub connections: Option<ConnectionStates>,
+}
+
+impl MetricsState {
+    /// Metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        if let Ok(schedulers) = self.schedulers.read() {
+            let mut schedulers: Vec<_> = schedulers.iter().collect();
+            schedulers.sort_by_key(|(frequency, _)| **frequency);
+
+            let _ = writeln!(out, "# TYPE isok_agent_scheduler_jobs gauge");
+            for (frequency, metrics) in &schedulers {
+                let _ = writeln!(
+                    out,
+                    "isok_agent_scheduler_jobs{{interval=\"{}\"}} {}",
+                    frequency.as_secs(),
+                    metrics.jobs()
+                );
+            }
+            let _ = writeln!(out, "# TYPE isok_agent_scheduler_lag_seconds gauge");
+            for (frequency, metrics) in &schedulers {
+                let _ = writeln!(
+                    out,
+                    "isok_agent_scheduler_lag_seconds{{interval=\"{}\"}} {}",
+                    frequency.as_secs(),
+                    metrics.last_lag().as_secs_f64()
+                );
+            }
+            let _ = writeln!(
+                out,
+                "# TYPE isok_agent_scheduler_skipped_ticks_total counter"
+            );
+            for (frequency, metrics) in &schedulers {
+                let _ = writeln!(
+                    out,
+                    "isok_agent_scheduler_skipped_ticks_total{{interval=\"{}\"}} {}",
+                    frequency.as_secs(),
+                    metrics.skipped_ticks()
+                );
+            }
+        }
+
+        self.metrics.render_probes(&mut out);
+
+        if let Ok(resources) = self.resources.lock() {
+            let _ = writeln!(out, "# TYPE isok_agent_http_pool_capacity gauge");
+            let _ = writeln!(
+                out,
+                "isok_agent_http_pool_capacity {}",
+                resources.http_pool.capacity()
+            );
+            let _ = writeln!(out, "# TYPE isok_agent_http_pool_used gauge");
+            let _ = writeln!(
+                out,
+                "isok_agent_http_pool_used {}",
+                resources.http_pool.used()
+            );
+
+            let limiter = &resources.limiter;
+            let _ = writeln!(out, "# TYPE isok_agent_target_hosts gauge");
+            let _ = writeln!(out, "isok_agent_target_hosts {}", limiter.hosts());
+            let _ = writeln!(out, "# TYPE isok_agent_throttled_probes_total counter");
+            let _ = writeln!(
+                out,
+                "isok_agent_throttled_probes_total {}",
+                limiter.throttled()
+            );
+            let _ = writeln!(
+                out,
+                "# TYPE isok_agent_throttle_skipped_probes_total counter"
+            );
+            let _ = writeln!(
+                out,
+                "isok_agent_throttle_skipped_probes_total {}",
+                limiter.skipped()
+            );
+            let _ = writeln!(out, "# TYPE isok_agent_throttle_lag_seconds_total counter");
+            let _ = writeln!(
+                out,
+                "isok_agent_throttle_lag_seconds_total {}",
+                limiter.total_lag().as_secs_f64()
+            );
+            let _ = writeln!(out, "# TYPE isok_agent_throttle_lag_seconds_max gauge");
+            let _ = writeln!(
+                out,
+                "isok_agent_throttle_lag_seconds_max {}",
+                limiter.max_lag().as_secs_f64()
+            );
+        }
+
+        if let Some(queue) = self.queue.upgrade() {
+            let _ = writeln!(out, "# TYPE isok_agent_result_queue_depth gauge");
+            let _ = writeln!(
+                out,
+                "isok_agent_result_queue_depth {}",
+                queue.max_capacity() - queue.capacity()
+            );
+            let _ = writeln!(out, "# TYPE isok_agent_result_queue_capacity gauge");
+            let _ = writeln!(
+                out,
+                "isok_agent_result_queue_capacity {}",
+                queue.max_capacity()
+            );
+        }
+
+        if let Some(spool) = &self.spool {
+            let age = spool
+                .oldest()
+                .map(|oldest| (Utc::now() - oldest).num_milliseconds().max(0) as f64 / 1000.0)
+                .unwrap_or_default();
+
+            let _ = writeln!(out, "# TYPE isok_agent_spool_results gauge");
+            let _ = writeln!(out, "isok_agent_spool_results {}", spool.len());
+            let _ = writeln!(out, "# TYPE isok_agent_spool_bytes gauge");
+            let _ = writeln!(out, "isok_agent_spool_bytes {}", spool.size());
+            let _ = writeln!(out, "# TYPE isok_agent_spool_oldest_age_seconds gauge");
+            let _ = writeln!(out, "isok_agent_spool_oldest_age_seconds {age}");
+            let _ = writeln!(out, "# TYPE isok_agent_spool_dropped_total counter");
+            let _ = writeln!(out, "isok_agent_spool_dropped_total {}", spool.dropped());
+        }
+
+        let _ = writeln!(out, "# TYPE isok_agent_pulsar_send_errors_total counter");
+        let _ = writeln!(
+            out,
+            "isok_agent_pulsar_send_errors_total {}",
+            self.metrics.pulsar_send_errors.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(out, "# TYPE isok_agent_sink_dropped_total counter");
+        for (sink, dropped) in self.metrics.sink_drops() {
+            let _ = writeln!(
+                out,
+                "isok_agent_sink_dropped_total{{sink=\"{sink}\"}} {dropped}"
+            );
+        }
+
+        if let Some(connections) = &self.connections {
+            let _ = writeln!(out, "# TYPE isok_agent_pulsar_connected gauge");
+            for (name, connected) in connections.snapshot() {
+                let _ = writeln!(
+                    out,
+                    "isok_agent_pulsar_connected{{connection=\"{name}\"}} {}",
+                    connected as u8
+                );
+            }
+        }
+
+        out
+    }
+}
+
+async fn metrics(State(state): State<MetricsState>) -> String {
+    state.render()
+}
+
+async fn healthz(State(state): State<MetricsState>) -> (StatusCode, String) {
+    // the memory bus never disconnects
+    let Some(connections) = state.connections else {
+        return (StatusCode::OK, "OK".to_string());
+    };
+    let disconnected = connections.disconnected();
+
+    if connections.snapshot().is_empty() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "pulsar not connected yet".to_string(),
+        )
+    } else if disconnected.is_empty() {
+        (StatusCode::OK, "OK".to_string())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("pulsar disconnected : {}", disconnected.join(", ")),
+        )
+    }
+}
+
+/// Serve `/metrics` and `/healthz` on `address`
+pub async fn serve(address: String, state: MetricsState) {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .with_state(state);
+
+    let listener = match tokio::net::TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not start metrics listener on {address} : {e}");
+            return;
+        }
+    };
+
+    info!("Serving metrics at http://{address}/metrics");
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("Metrics listener stopped : {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn state(connections: Option<ConnectionStates>) -> MetricsState {
+        let (queue, _) = mpsc::channel(1);
+        MetricsState {
+            metrics: Arc::new(AgentMetrics::default()),
+            schedulers: Arc::new(RwLock::new(HashMap::new())),
+            resources: Arc::new(Mutex::new(JobResources::default())),
+            queue: queue.downgrade(),
+            spool: None,
+            connections,
+        }
+    }
+
+    fn probe(latency: u64) -> CheckMessage {
+        CheckMessage {
+            check_id: Uuid::new_v4(),
+            agent_id: "agent".to_string(),
+            timestamp: Utc::now().fixed_offset(),
+            latency: Some(latency),
+            outcome: CheckOutcome::Up,
+            attempts: 1,
+            attempt_outcomes: vec![CheckOutcome::Up],
+            fields: serde_json::Value::Null,
+        }
+    }
+
+    #[test]
+    fn latency_buckets_are_cumulative() {
+        let state = state(None);
+        for latency in [3, 40, 40, 400, 40_000] {
+            state.metrics.record_probe(CheckType::Http, &probe(latency));
+        }
+
+        let rendered = state.render();
+        let buckets: Vec<(&str, u64)> = rendered
+            .lines()
+            .filter_map(|line| {
+                line.strip_prefix("isok_agent_probe_latency_seconds_bucket{kind=\"http\",le=\"")
+            })
+            .map(|line| {
+                let (bound, count) = line.split_once("\"} ").unwrap();
+                (bound, count.parse().unwrap())
+            })
+            .collect();
+
+        assert_eq!(buckets.len(), LATENCY_BUCKETS.len() + 1);
+        assert!(buckets.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(buckets[0], ("0.005", 1));
+        assert_eq!(buckets[3], ("0.05", 3));
+        assert_eq!(buckets[6], ("0.5", 4));
+        assert_eq!(buckets[LATENCY_BUCKETS.len() - 1], ("30", 4));
+        assert_eq!(buckets.last(), Some(&("+Inf", 5)));
+        assert!(rendered.contains("isok_agent_probe_latency_seconds_count{kind=\"http\"} 5\n"));
+        assert!(rendered.contains("isok_agent_probes_total{kind=\"http\"} 5\n"));
+    }
+
+    #[tokio::test]
+    async fn healthz_status() {
+        let (status, _) = healthz(State(state(None))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let connections = ConnectionStates::default();
+        let (status, _) = healthz(State(state(Some(connections.clone())))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        connections.set("commands", true);
+        connections.set("results-http", true);
+        let (status, _) = healthz(State(state(Some(connections.clone())))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        connections.set("results-http", false);
+        let (status, body) = healthz(State(state(Some(connections)))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "pulsar disconnected : results-http");
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use uuid::Uuid;
+
+use isok_data::bus::{MessageBus, Topic};
+use isok_data::pulsar_heartbeats::Heartbeat;
+
+/// Points of each agent on the ring, to even out the share of every agent
+const VIRTUAL_NODES: u32 = 64;
+
+/// Hash stable across builds and platforms, agents of a pool must agree on it
+pub fn stable_hash(parts: &[&[u8]]) -> u64 {
+    // fnv-1a followed by a splitmix64 finalizer for well spread low bits
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for part in parts {
+        for byte in part.iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Consistent hash ring of the active agents of a pool
+#[derive(Debug, Default)]
+pub struct HashRing {
+    nodes: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new<'a>(agents: impl Iterator<Item = &'a str>) -> Self {
+        let nodes = agents
+            .flat_map(|agent| {
+                (0..VIRTUAL_NODES).map(move |i| {
+                    (
+                        stable_hash(&[agent.as_bytes(), &i.to_be_bytes()]),
+                        agent.to_string(),
+                    )
+                })
+            })
+            .collect();
+
+        Self { nodes }
+    }
+
+    /// Agent in charge of a check, none if the ring is empty
+    pub fn owner(&self, check_id: &Uuid) -> Option<&str> {
+        let hash = stable_hash(&[check_id.as_bytes()]);
+
+        self.nodes
+            .range(hash..)
+            .next()
+            .or_else(|| self.nodes.iter().next())
+            .map(|(_, agent)| agent.as_str())
+    }
+}
+
+/// Membership of an agent, as unix timestamps in seconds
+#[derive(Debug, Clone, Copy)]
+struct Member {
+    started_at: i64,
+    last_seen: i64,
+    leaving_at: Option<i64>,
+}
+
+/// Agents of a pool as seen through their heartbeats
+///
+/// Membership changes take effect `settle` after the heartbeat announcing them,
+/// so that every agent switches to the new ring at the same scheduler tick : a
+/// check is then run by its previous owner up to that tick and by its new owner
+/// from that tick on.
+///
+/// Ticks are read on the clock of each agent, so agents of a pool need clocks
+/// within `max_skew` of each other : at a membership change, a check may run
+/// twice or not at all for as long as the clocks of its owners differ.
+/// Heartbeats further than `max_skew` from the local clock, delivery delay
+/// included, are ignored, so agents with a skewed clock drop out of the pool.
+#[derive(Debug)]
+pub struct AgentPool {
+    name: String,
+    heartbeat_interval: Duration,
+    max_skew: Duration,
+    members: HashMap<String, Member>,
+}
+
+impl AgentPool {
+    pub fn new(name: String, heartbeat_interval: Duration, max_skew: Duration) -> Self {
+        Self {
+            name,
+            heartbeat_interval,
+            max_skew,
+            members: HashMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Delay before a membership change takes effect, leaving time for heartbeats to spread
+    fn settle(&self) -> i64 {
+        2 * self.heartbeat_interval.as_secs().max(1) as i64
+    }
+
+    /// Time left to the other agents to take over the checks of a leaving agent
+    pub fn settle_delay(&self) -> Duration {
+        Duration::from_secs(self.settle() as u64)
+    }
+
+    /// Silence after which an agent is considered gone
+    fn timeout(&self) -> i64 {
+        3 * self.heartbeat_interval.as_secs().max(1) as i64
+    }
+
+    /// Update membership from a heartbeat received at `now`, ignoring other
+    /// pools ones and the ones of agents with a skewed clock
+    pub fn record(&mut self, heartbeat: &Heartbeat, now: DateTime<Utc>) {
+        if heartbeat.pool.as_deref() != Some(self.name.as_str()) {
+            return;
+        }
+
+        let skew = (heartbeat.timestamp - now).abs();
+        if skew.to_std().unwrap_or(Duration::MAX) > self.max_skew {
+            warn!(
+                "Ignoring heartbeat of agent {} sent {}ms away from local time, over the {}ms clock skew bound of pool {}",
+                heartbeat.agent_id,
+                skew.num_milliseconds(),
+                self.max_skew.as_millis(),
+                self.name
+            );
+            return;
+        }
+
+        let started_at = heartbeat.started_at.timestamp();
+        let timestamp = heartbeat.timestamp.timestamp();
+        let leaving_at = heartbeat.leaving.then(|| timestamp + self.settle());
+
+        match self.members.get_mut(&heartbeat.agent_id) {
+            // a restarted agent joins again from its new start
+            Some(member) if member.started_at == started_at => {
+                member.last_seen = member.last_seen.max(timestamp);
+                member.leaving_at = member.leaving_at.or(leaving_at);
+            }
+            _ => {
+                info!(
+                    "Agent {} joined pool {} (started at {})",
+                    heartbeat.agent_id, self.name, heartbeat.started_at
+                );
+                self.members.insert(
+                    heartbeat.agent_id.clone(),
+                    Member {
+                        started_at,
+                        last_seen: timestamp,
+                        leaving_at,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Whether an agent is part of the ring at `time`
+    fn is_active(&self, member: &Member, time: i64) -> bool {
+        let joined = member.started_at + self.settle();
+        let left = member
+            .leaving_at
+            .unwrap_or(i64::MAX)
+            .min(member.last_seen + self.timeout());
+
+        joined <= time && time < left
+    }
+
+    /// Ring of the agents active at `time`, a unix timestamp in seconds
+    pub fn ring_at(&self, time: i64) -> HashRing {
+        HashRing::new(
+            self.members
+                .iter()
+                .filter(|(_, member)| self.is_active(member, time))
+                .map(|(agent, _)| agent.as_str()),
+        )
+    }
+
+    /// Forget agents gone for a while
+    pub fn prune(&mut self, now: i64) {
+        let timeout = self.timeout();
+        self.members.retain(|agent, member| {
+            let gone = member
+                .leaving_at
+                .unwrap_or(i64::MAX)
+                .min(member.last_seen + timeout);
+            if gone + timeout < now {
+                warn!("Agent {agent} left pool");
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Track the pool members through the heartbeats topic, subscribing again when disconnected
+pub async fn receive_heartbeats(pool: Arc<RwLock<AgentPool>>, bus: Arc<dyn MessageBus>) {
+    loop {
+        // every agent gets all the heartbeats, through its own subscription
+        let subscription = Uuid::new_v4().hyphenated().to_string();
+        let mut heartbeats = bus.subscribe(Topic::Heartbeats, &subscription).await;
+
+        while let Some(payload) = heartbeats.next().await {
+            heartbeats.ack().await;
+
+            match serde_json::from_slice::<Heartbeat>(&payload) {
+                Ok(heartbeat) => {
+                    if let Ok(mut pool) = pool.write() {
+                        pool.record(&heartbeat, Utc::now());
+                    }
+                }
+                Err(e) => error!("Can't deserialize heartbeat : {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POOL: &str = "pool";
+
+    fn pool() -> AgentPool {
+        AgentPool::new(
+            POOL.to_string(),
+            Duration::from_secs(5),
+            Duration::from_secs(2),
+        )
+    }
+
+    fn heartbeat(agent_id: &str, started_at: i64, timestamp: i64) -> Heartbeat {
+        Heartbeat {
+            agent_id: agent_id.to_string(),
+            pool: Some(POOL.to_string()),
+            regions: Vec::new(),
+            version: String::new(),
+            timestamp: DateTime::from_timestamp(timestamp, 0).unwrap(),
+            started_at: DateTime::from_timestamp(started_at, 0).unwrap(),
+            jobs: Default::default(),
+            scheduler_lag: 0,
+            queue_depth: 0,
+            leaving: false,
+        }
+    }
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    fn agents(pool: &AgentPool, time: i64) -> usize {
+        let ring = pool.ring_at(time);
+        ring.nodes
+            .values()
+            .collect::<std::collections::HashSet<_>>()
+            .len()
+    }
+
+    #[test]
+    fn members_join_and_leave_after_settling() {
+        let mut pool = pool();
+        pool.record(&heartbeat("a", 1000, 1000), at(1000));
+        pool.record(&heartbeat("b", 1000, 1000), at(1000));
+
+        assert_eq!(agents(&pool, 1009), 0);
+        assert_eq!(agents(&pool, 1010), 2);
+
+        let mut leaving = heartbeat("b", 1000, 1020);
+        leaving.leaving = true;
+        pool.record(&heartbeat("a", 1000, 1020), at(1020));
+        pool.record(&leaving, at(1020));
+
+        assert_eq!(agents(&pool, 1029), 2);
+        assert_eq!(agents(&pool, 1030), 1);
+    }
+
+    #[test]
+    fn skewed_heartbeats_are_ignored() {
+        let mut pool = pool();
+        pool.record(&heartbeat("ahead", 1003, 1003), at(1000));
+        pool.record(&heartbeat("behind", 997, 997), at(1000));
+        pool.record(&heartbeat("close", 1002, 1002), at(1000));
+
+        assert_eq!(agents(&pool, 1015), 1);
+        assert_eq!(pool.ring_at(1015).owner(&Uuid::new_v4()), Some("close"));
+    }
+
+    #[test]
+    fn skewed_members_time_out() {
+        let mut pool = pool();
+        pool.record(&heartbeat("a", 1000, 1000), at(1000));
+
+        // its clock drifted, its heartbeats stop counting
+        pool.record(&heartbeat("a", 1000, 1010), at(1005));
+
+        assert_eq!(agents(&pool, 1014), 1);
+        assert_eq!(agents(&pool, 1015), 0);
+    }
+}
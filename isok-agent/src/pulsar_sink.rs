@@ -1,16 +1,307 @@
-use isok_data::check::{Deserialize, Serialize};
-use pulsar::{Producer, TokioExecutor};
-use std::time::Duration;
-use tokio::sync::mpsc::Receiver;
-use uuid::Uuid;
-
-use isok_data::pulsar_messages::CheckMessage;
-
-pub async fn pulsar_sink(
-    mut producer: Producer<TokioExecutor>,
-    mut receiver: Receiver<CheckMessage>,
-) {
-    while let Some(check_msg) = receiver.recv().await {
-        let _ = producer.send_non_blocking(check_msg).await;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{error, info, warn};
+
+use isok_data::bus::{self, MessageBus, Receipt, Topic};
+use isok_data::pulsar_messages::{CheckMessage, CheckType};
+
+use crate::metrics::AgentMetrics;
+use crate::sink::ResultSink;
+use crate::spool::{Spool, SpoolStats};
+
+/// Results replayed at once, the sink does not take new results meanwhile
+const REPLAY_BATCH: usize = 64;
+/// Results sent without receipt yet, past it the sink waits for the oldest receipt
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// Run `op` on the spool from a blocking thread, as it does disk io
+async fn on_spool<T, F>(spool: &Arc<Mutex<Spool>>, op: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Spool) -> io::Result<T> + Send + 'static,
+{
+    let spool = Arc::clone(spool);
+    tokio::task::spawn_blocking(move || {
+        let mut spool = spool.lock().unwrap_or_else(|e| e.into_inner());
+        op(&mut spool)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Sends results to the message bus, spooling them while it is unreachable
+///
+/// Results are sent in order : once a result is spooled, the following ones
+/// are spooled behind it until the spool is replayed, and the results sent
+/// before it are spooled first if they fail.
+pub struct PulsarSink {
+    bus: Arc<dyn MessageBus>,
+    spool: Option<(Arc<Mutex<Spool>>, Arc<SpoolStats>)>,
+    /// Results sent and waiting for their receipt, oldest first
+    in_flight: VecDeque<(CheckType, CheckMessage, Receipt)>,
+    metrics: Arc<AgentMetrics>,
+}
+
+impl PulsarSink {
+    pub fn new(bus: Arc<dyn MessageBus>, spool: Option<Spool>, metrics: Arc<AgentMetrics>) -> Self {
+        Self {
+            bus,
+            spool: spool.map(|spool| {
+                let stats = spool.stats();
+                (Arc::new(Mutex::new(spool)), stats)
+            }),
+            in_flight: VecDeque::new(),
+            metrics,
+        }
+    }
+
+    async fn push(&mut self, check_type: CheckType, check_msg: CheckMessage) {
+        let check_id = check_msg.check_id;
+        let Some((spool, _)) = &self.spool else {
+            error!("Could not send result of check {check_id} to pulsar topic {check_type}, dropping it");
+            return;
+        };
+
+        if let Err(e) = on_spool(spool, move |spool| spool.push(check_type, check_msg)).await {
+            error!("Could not spool result of check {check_id}, dropping it : {e}");
+        }
+    }
+
+    /// Spool a result once the results sent before it got their receipt
+    async fn spool(&mut self, check_type: CheckType, check_msg: CheckMessage) {
+        if self.spool.is_some() {
+            self.settle(true).await;
+        }
+        self.push(check_type, check_msg).await;
+    }
+
+    /// Handle the receipts of the results in flight, oldest first, spooling the
+    /// failed ones : all of them if `wait`, otherwise the ones already received
+    async fn settle(&mut self, wait: bool) {
+        loop {
+            let over = self.in_flight.len() > MAX_IN_FLIGHT;
+            let Some((_, _, receipt)) = self.in_flight.front_mut() else {
+                return;
+            };
+            let received = match wait || over {
+                true => Some(receipt.await),
+                false => receipt.now_or_never(),
+            };
+            let (Some(received), Some((check_type, check_msg, _))) =
+                (received, self.in_flight.pop_front())
+            else {
+                return;
+            };
+
+            if let Err(e) = received {
+                warn!(
+                    "Result of check {} was not stored : {e}",
+                    check_msg.check_id
+                );
+                self.metrics.record_send_error();
+                self.push(check_type, check_msg).await;
+            }
+        }
+    }
+
+    async fn send(&mut self, check_type: CheckType, check_msg: CheckMessage) {
+        self.settle(false).await;
+
+        // results queue up behind the spooled ones to keep them in order
+        if self
+            .spool
+            .as_ref()
+            .is_some_and(|(_, stats)| !stats.is_empty())
+        {
+            return self.spool(check_type, check_msg).await;
+        }
+
+        let topic = Topic::Results(check_type);
+        match bus::publish_json(self.bus.as_ref(), topic, None, &check_msg).await {
+            Ok(receipt) => self.in_flight.push_back((check_type, check_msg, receipt)),
+            Err(e) => {
+                warn!("Could not send result to pulsar topic {check_type} : {e}");
+                self.metrics.record_send_error();
+                self.spool(check_type, check_msg).await;
+            }
+        }
+    }
+
+    /// Send a batch of spooled results, stopping at the first failure
+    async fn replay(&mut self) {
+        let Some((spool, stats)) = &self.spool else {
+            return;
+        };
+        if stats.is_empty() {
+            return;
+        }
+        let spool = Arc::clone(spool);
+        self.settle(true).await;
+
+        let batch = match on_spool(&spool, |spool| spool.peek(REPLAY_BATCH)).await {
+            Ok(batch) => batch,
+            Err(e) => {
+                error!("Could not read spooled results : {e}");
+                return;
+            }
+        };
+
+        let mut receipts = Vec::with_capacity(batch.len());
+        for spooled in &batch {
+            let Some(record) = &spooled.record else {
+                receipts.push(None);
+                continue;
+            };
+
+            let topic = Topic::Results(record.check_type);
+            match bus::publish_json(self.bus.as_ref(), topic, None, &record.message).await {
+                Ok(receipt) => receipts.push(Some(receipt)),
+                Err(e) => {
+                    warn!("Could not replay spooled results : {e}");
+                    self.metrics.record_send_error();
+                    break;
+                }
+            }
+        }
+
+        // only the results sent before the first failure are acknowledged, in order
+        let mut replayed: usize = 0;
+        for receipt in receipts {
+            if let Some(receipt) = receipt {
+                if let Err(e) = receipt.await {
+                    warn!("Could not replay spooled results : {e}");
+                    self.metrics.record_send_error();
+                    break;
+                }
+            }
+            replayed += 1;
+        }
+        let Some(last) = replayed
+            .checked_sub(1)
+            .and_then(|last| batch.into_iter().nth(last))
+        else {
+            return;
+        };
+
+        let acked = on_spool(&spool, move |spool| {
+            spool.ack(replayed as u64, &last)?;
+            Ok(spool.is_empty())
+        });
+        match acked.await {
+            Ok(true) => info!("Spooled results all replayed"),
+            Ok(false) => {}
+            Err(e) => error!("Could not acknowledge spooled results : {e}"),
+        }
+    }
+}
+
+impl ResultSink for PulsarSink {
+    fn name(&self) -> &'static str {
+        "pulsar"
+    }
+
+    fn send(&mut self, check_type: CheckType, message: CheckMessage) -> BoxFuture<'_, ()> {
+        Box::pin(self.send(check_type, message))
+    }
+
+    fn tick(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.settle(false).await;
+            self.replay().await;
+        })
+    }
+
+    /// Wait for the receipts of the results sent
+    fn flush(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(self.settle(true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use chrono::Utc;
+    use isok_data::bus::{BusError, Subscriber};
+    use isok_data::pulsar_messages::CheckOutcome;
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// Bus storing the agent id of the messages published, or failing their receipts
+    #[derive(Default)]
+    struct FlakyBus {
+        down: AtomicBool,
+        stored: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MessageBus for FlakyBus {
+        fn publish(
+            &self,
+            topic: Topic,
+            _: Option<String>,
+            payload: Vec<u8>,
+        ) -> BoxFuture<'_, Result<Receipt, BusError>> {
+            let down = self.down.load(Ordering::Relaxed);
+            let stored = Arc::clone(&self.stored);
+            let receipt: Receipt = Box::pin(async move {
+                if down {
+                    return Err(BusError::Disconnected(topic));
+                }
+                let message: CheckMessage = serde_json::from_slice(&payload)?;
+                stored.lock().unwrap().push(message.agent_id);
+                Ok(())
+            });
+            Box::pin(async { Ok(receipt) })
+        }
+
+        fn subscribe<'a>(&'a self, _: Topic, _: &'a str) -> BoxFuture<'a, Box<dyn Subscriber>> {
+            unimplemented!()
+        }
+    }
+
+    fn message(agent_id: &str) -> CheckMessage {
+        CheckMessage {
+            check_id: Uuid::new_v4(),
+            agent_id: agent_id.to_string(),
+            timestamp: Utc::now().fixed_offset(),
+            latency: Some(1),
+            outcome: CheckOutcome::Up,
+            attempts: 1,
+            attempt_outcomes: vec![CheckOutcome::Up],
+            fields: serde_json::Value::Null,
+        }
+    }
+
+    #[tokio::test]
+    async fn results_stay_in_order_after_a_failed_receipt() {
+        let dir = std::env::temp_dir().join(format!("isok-sink-{}", Uuid::new_v4()));
+        let spool = Spool::open(&dir, 1 << 20, 1 << 20).unwrap();
+        let bus = Arc::new(FlakyBus::default());
+        let mut sink = PulsarSink::new(
+            Arc::clone(&bus) as Arc<dyn MessageBus>,
+            Some(spool),
+            Arc::new(AgentMetrics::default()),
+        );
+
+        bus.down.store(true, Ordering::Relaxed);
+        sink.send(CheckType::Http, message("a")).await;
+        bus.down.store(false, Ordering::Relaxed);
+        // the receipt of a failed meanwhile, b has to wait behind it
+        sink.send(CheckType::Http, message("b")).await;
+        sink.send(CheckType::Http, message("c")).await;
+        assert!(bus.stored.lock().unwrap().is_empty());
+
+        ResultSink::tick(&mut sink).await;
+        sink.send(CheckType::Http, message("d")).await;
+        ResultSink::flush(&mut sink).await;
+
+        assert_eq!(*bus.stored.lock().unwrap(), ["a", "b", "c", "d"]);
+        _ = fs::remove_dir_all(dir);
     }
 }
@@ -0,0 +1,21 @@
+use std::io;
+use std::net::IpAddr;
+
+use isok_data::check::Host;
+
+/// Resolve a [`Host`] to its first ip address, using the system resolver for domains
+pub async fn resolve_host(host: &Host) -> io::Result<IpAddr> {
+    match host {
+        Host::IpAddr(ip) => Ok(*ip),
+        Host::Domain(domain) => tokio::net::lookup_host((domain.host(), 0))
+            .await?
+            .next()
+            .map(|addr| addr.ip())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no address found for {}", domain.as_str()),
+                )
+            }),
+    }
+}
@@ -0,0 +1,252 @@
+use std::future::Future;
+use std::time::Duration;
+
+use log::info;
+use uuid::Uuid;
+
+use isok_data::check::{RetryOn, RetryPolicy};
+use isok_data::pulsar_messages::{CheckMessage, CheckOutcome};
+
+/// Why a probe failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeFailure {
+    /// The target did not answer : timeout, connection or resolution error
+    Network,
+    /// The target answered, but not as expected
+    Assertion,
+}
+
+impl ProbeFailure {
+    /// Failure of a probe reporting `outcome`, `answered` being whether the target answered at all
+    pub fn of(outcome: CheckOutcome, answered: bool) -> Option<Self> {
+        match outcome {
+            CheckOutcome::Up | CheckOutcome::Degraded => None,
+            CheckOutcome::Timeout => Some(ProbeFailure::Network),
+            CheckOutcome::Down if answered => Some(ProbeFailure::Assertion),
+            CheckOutcome::Down => Some(ProbeFailure::Network),
+        }
+    }
+}
+
+/// Retries of `policy` fitting within `interval` after the first attempt, each retry
+/// lasting up to `timeout` plus the retry delay, so a failure is confirmed before the next probe
+pub fn fit_retries(policy: RetryPolicy, timeout: Duration, interval: Duration) -> RetryPolicy {
+    let left = interval.saturating_sub(timeout);
+    let retry = timeout + Duration::from_millis(policy.delay);
+    let fitting = match retry.is_zero() {
+        true => u32::MAX,
+        false => (left.as_nanos() / retry.as_nanos()).min(u32::MAX as u128) as u32,
+    };
+
+    RetryPolicy {
+        retries: policy.retries.min(fitting),
+        ..policy
+    }
+}
+
+/// Attempts of a probe, run again on failure as long as its [`RetryPolicy`] allows
+pub struct Confirmation {
+    check_id: Uuid,
+    policy: RetryPolicy,
+    outcomes: Vec<CheckOutcome>,
+}
+
+impl Confirmation {
+    pub fn new(check_id: Uuid, policy: RetryPolicy) -> Self {
+        Self {
+            check_id,
+            policy,
+            outcomes: Vec::with_capacity(1),
+        }
+    }
+
+    /// Run `probe` until its outcome is confirmed, `probe` giving the message of
+    /// an attempt and whether the target answered
+    ///
+    /// Returns the message of the last attempt, along with the outcome of every attempt.
+    pub async fn run<F, Fut>(mut self, mut probe: F) -> CheckMessage
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = (CheckMessage, bool)>,
+    {
+        loop {
+            let (message, answered) = probe().await;
+            let failure = ProbeFailure::of(message.outcome, answered);
+
+            if !self.retry(&message, failure).await {
+                return self.report(message);
+            }
+        }
+    }
+
+    /// Record an attempt, returns whether the probe has to run again, once the
+    /// retry delay has elapsed
+    async fn retry(&mut self, message: &CheckMessage, failure: Option<ProbeFailure>) -> bool {
+        self.outcomes.push(message.outcome);
+
+        let retried = match failure {
+            None => false,
+            Some(ProbeFailure::Network) => true,
+            Some(ProbeFailure::Assertion) => self.policy.on == RetryOn::AllFailures,
+        };
+        let attempt = self.outcomes.len() as u32;
+        if !retried || attempt > self.policy.retries {
+            return false;
+        }
+
+        info!(
+            "Check {} is {}, confirming ({attempt}/{})...",
+            self.check_id,
+            message.outcome.as_str(),
+            self.policy.retries
+        );
+        if self.policy.delay > 0 {
+            tokio::time::sleep(Duration::from_millis(self.policy.delay)).await;
+        }
+        true
+    }
+
+    /// Message of the last attempt, along with the outcome of every attempt
+    fn report(self, mut message: CheckMessage) -> CheckMessage {
+        message.attempts = self.outcomes.len() as u32;
+        message.attempt_outcomes = self.outcomes;
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use chrono::Utc;
+
+    use super::*;
+
+    fn message(outcome: CheckOutcome) -> CheckMessage {
+        CheckMessage {
+            check_id: Uuid::new_v4(),
+            agent_id: "agent".to_string(),
+            timestamp: Utc::now().fixed_offset(),
+            latency: Some(1),
+            outcome,
+            attempts: 1,
+            attempt_outcomes: vec![outcome],
+            fields: serde_json::Value::Null,
+        }
+    }
+
+    fn policy(retries: u32, on: RetryOn) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            delay: 100,
+            on,
+        }
+    }
+
+    /// Run a confirmation over `attempts`, each one an outcome and whether the target answered
+    async fn confirm(policy: RetryPolicy, attempts: &[(CheckOutcome, bool)]) -> CheckMessage {
+        let mut attempts: VecDeque<_> = attempts.iter().copied().collect();
+        Confirmation::new(Uuid::new_v4(), policy)
+            .run(|| {
+                let (outcome, answered) = attempts.pop_front().expect("no attempt left");
+                async move { (message(outcome), answered) }
+            })
+            .await
+    }
+
+    #[test]
+    fn probe_failures() {
+        assert_eq!(ProbeFailure::of(CheckOutcome::Up, true), None);
+        assert_eq!(ProbeFailure::of(CheckOutcome::Degraded, true), None);
+        assert_eq!(
+            ProbeFailure::of(CheckOutcome::Timeout, false),
+            Some(ProbeFailure::Network)
+        );
+        assert_eq!(
+            ProbeFailure::of(CheckOutcome::Down, false),
+            Some(ProbeFailure::Network)
+        );
+        assert_eq!(
+            ProbeFailure::of(CheckOutcome::Down, true),
+            Some(ProbeFailure::Assertion)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn up_probes_are_reported_at_once() {
+        let message = confirm(policy(3, RetryOn::AllFailures), &[(CheckOutcome::Up, true)]).await;
+
+        assert_eq!(message.attempts, 1);
+        assert_eq!(message.attempt_outcomes, [CheckOutcome::Up]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_are_retried_until_confirmed() {
+        let attempts = [
+            (CheckOutcome::Timeout, false),
+            (CheckOutcome::Down, false),
+            (CheckOutcome::Up, true),
+        ];
+        let message = confirm(policy(3, RetryOn::AllFailures), &attempts).await;
+
+        assert_eq!(message.outcome, CheckOutcome::Up);
+        assert_eq!(
+            message.attempt_outcomes,
+            [CheckOutcome::Timeout, CheckOutcome::Down, CheckOutcome::Up]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_are_bounded() {
+        let attempts = [(CheckOutcome::Timeout, false); 3];
+        let message = confirm(policy(2, RetryOn::AllFailures), &attempts).await;
+
+        assert_eq!(message.outcome, CheckOutcome::Timeout);
+        assert_eq!(message.attempts, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn assertion_failures_are_only_retried_on_all_failures() {
+        let attempts = [(CheckOutcome::Down, true)];
+        let message = confirm(policy(2, RetryOn::NetworkErrors), &attempts).await;
+        assert_eq!(message.attempts, 1);
+
+        let attempts = [(CheckOutcome::Down, true), (CheckOutcome::Up, true)];
+        let message = confirm(policy(2, RetryOn::AllFailures), &attempts).await;
+        assert_eq!(message.attempts, 2);
+    }
+
+    #[test]
+    fn retries_fit_within_the_interval() {
+        let timeout = Duration::from_millis(900);
+        let interval = Duration::from_secs(3);
+
+        // 0.9s for the first attempt, then 1s per retry
+        assert_eq!(
+            fit_retries(policy(5, RetryOn::AllFailures), timeout, interval).retries,
+            2
+        );
+        assert_eq!(
+            fit_retries(policy(2, RetryOn::AllFailures), timeout, interval).retries,
+            2
+        );
+        // the first attempt alone fills the interval
+        assert_eq!(
+            fit_retries(policy(5, RetryOn::AllFailures), interval, interval).retries,
+            0
+        );
+        assert_eq!(
+            fit_retries(
+                policy(5, RetryOn::AllFailures),
+                Duration::from_millis(2500),
+                interval
+            )
+            .retries,
+            0
+        );
+        assert_eq!(
+            fit_retries(policy(5, RetryOn::AllFailures), interval * 2, interval).retries,
+            0
+        );
+    }
+}
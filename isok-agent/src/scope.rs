@@ -0,0 +1,122 @@
+use std::collections::{BTreeMap, HashSet};
+
+use isok_data::check::CheckOutput;
+
+/// Regions and labels of an agent, deciding which checks it runs
+#[derive(Debug, Clone, Default)]
+pub struct AgentScope {
+    /// Regions served by the agent, every region if empty
+    regions: HashSet<String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl AgentScope {
+    pub fn new(regions: HashSet<String>, labels: BTreeMap<String, String>) -> Self {
+        Self { regions, labels }
+    }
+
+    /// Parse a comma separated region list and a `key=value` comma separated label list
+    pub fn parse(regions: &str, labels: &str) -> Result<Self, String> {
+        let regions = regions
+            .split(',')
+            .map(str::trim)
+            .filter(|region| !region.is_empty())
+            .map(ToString::to_string)
+            .collect();
+
+        let labels = labels
+            .split(',')
+            .map(str::trim)
+            .filter(|label| !label.is_empty())
+            .map(|label| match label.split_once('=') {
+                Some((key, value)) => Ok((key.trim().to_string(), value.trim().to_string())),
+                None => Err(format!("invalid label {label}, expected key=value")),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self::new(regions, labels))
+    }
+
+    /// Regions served by the agent, sorted
+    pub fn regions(&self) -> Vec<String> {
+        let mut regions: Vec<String> = self.regions.iter().cloned().collect();
+        regions.sort();
+        regions
+    }
+
+    /// Whether the check region is served and its selector matches the agent labels
+    pub fn accepts(&self, check: &CheckOutput) -> bool {
+        let region_match = self.regions.is_empty() || self.regions.contains(&check.region);
+
+        region_match
+            && check
+                .selector
+                .iter()
+                .all(|(key, value)| self.labels.get(key) == Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn check(region: &str, selector: serde_json::Value) -> CheckOutput {
+        serde_json::from_value(json!({
+            "id": Uuid::new_v4(),
+            "owner_id": Uuid::new_v4(),
+            "kind": {"type": "tcp", "data": {"host": {"IpAddr": "127.0.0.1"}, "port": 80}},
+            "max_latency": {"secs": 1, "nanos": 0},
+            "interval": 60,
+            "region": region,
+            "selector": selector,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let scope = AgentScope::parse(" eu-west, ,us-east ", "zone = a,network=private,").unwrap();
+        assert_eq!(scope.regions(), ["eu-west", "us-east"]);
+        assert_eq!(
+            scope.labels,
+            BTreeMap::from([
+                ("network".to_string(), "private".to_string()),
+                ("zone".to_string(), "a".to_string()),
+            ])
+        );
+
+        assert!(AgentScope::parse("eu-west", "zone=a,private").is_err());
+    }
+
+    #[test]
+    fn no_region_accepts_every_region() {
+        let scope = AgentScope::parse("", "").unwrap();
+
+        assert!(scope.accepts(&check("eu-west", json!({}))));
+        assert!(scope.accepts(&check("us-east", json!({}))));
+    }
+
+    #[test]
+    fn regions() {
+        let scope = AgentScope::parse("eu-west", "").unwrap();
+
+        assert!(scope.accepts(&check("eu-west", json!({}))));
+        assert!(!scope.accepts(&check("us-east", json!({}))));
+    }
+
+    #[test]
+    fn selectors() {
+        let scope = AgentScope::parse("", "zone=a,network=private").unwrap();
+
+        assert!(scope.accepts(&check("eu-west", json!({"zone": "a"}))));
+        assert!(scope.accepts(&check(
+            "eu-west",
+            json!({"zone": "a", "network": "private"})
+        )));
+        assert!(!scope.accepts(&check("eu-west", json!({"zone": "a", "gpu": "true"}))));
+        assert!(!scope.accepts(&check("eu-west", json!({"zone": "b"}))));
+    }
+}
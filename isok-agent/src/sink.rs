@@ -0,0 +1,511 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use url::Url;
+
+use isok_data::pulsar_messages::{CheckMessage, CheckType};
+
+use crate::http::HttpClient;
+use crate::metrics::AgentMetrics;
+
+/// Results queued toward each sink, past it the results are dropped for this sink only
+const SINK_QUEUE: usize = 512;
+/// Delay between two [tick](ResultSink::tick)s of a sink
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// Results posted at once to a webhook
+const WEBHOOK_BATCH: usize = 100;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Destination of the check results
+///
+/// Every sink runs in its own task, fed through its own queue.
+pub trait ResultSink: Send {
+    /// Name of the sink, for logs
+    fn name(&self) -> &'static str;
+
+    /// Deliver a result, failures are handled by the sink itself
+    fn send(&mut self, check_type: CheckType, message: CheckMessage) -> BoxFuture<'_, ()>;
+
+    /// Periodic work, such as retrying failed deliveries
+    fn tick(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    /// Wait for the results sent so far to be delivered
+    fn flush(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Kinds of sinks, as listed in `RESULT_SINKS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkKind {
+    Pulsar,
+    Stdout,
+    File,
+    Webhook,
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pulsar" => Ok(SinkKind::Pulsar),
+            "stdout" => Ok(SinkKind::Stdout),
+            "file" => Ok(SinkKind::File),
+            "webhook" => Ok(SinkKind::Webhook),
+            other => Err(format!("unknown result sink {other}")),
+        }
+    }
+}
+
+impl SinkKind {
+    /// Parse a comma separated list of sinks
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        let mut kinds = Vec::new();
+        for kind in s.split(',').map(str::trim).filter(|kind| !kind.is_empty()) {
+            let kind = kind.parse()?;
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+
+        if kinds.is_empty() {
+            return Err("no result sink".to_string());
+        }
+        Ok(kinds)
+    }
+}
+
+/// Sinks selected and their settings
+#[derive(Debug, Clone)]
+pub struct SinkOptions {
+    pub kinds: Vec<SinkKind>,
+    pub results_file: Option<PathBuf>,
+    /// Size of the results file past which it is rotated
+    pub file_max_bytes: u64,
+    /// Rotated results files kept
+    pub file_keep: usize,
+    pub webhook_url: Option<String>,
+}
+
+impl SinkOptions {
+    /// Open the selected sinks, apart from pulsar which needs a connection
+    pub fn open(&self) -> Result<Vec<Box<dyn ResultSink>>, String> {
+        let mut sinks: Vec<Box<dyn ResultSink>> = Vec::new();
+
+        for kind in &self.kinds {
+            match kind {
+                SinkKind::Pulsar => {}
+                SinkKind::Stdout => sinks.push(Box::new(StdoutSink)),
+                SinkKind::File => {
+                    let path = self
+                        .results_file
+                        .clone()
+                        .ok_or("file sink needs a results file")?;
+                    let sink = FileSink::open(path, self.file_max_bytes, self.file_keep)
+                        .map_err(|e| format!("can't open results file : {e}"))?;
+                    sinks.push(Box::new(sink));
+                }
+                SinkKind::Webhook => {
+                    let url = self
+                        .webhook_url
+                        .clone()
+                        .ok_or("webhook sink needs an url")?;
+                    let sink = WebhookSink::new(&url)
+                        .map_err(|e| format!("invalid webhook url {url} : {e}"))?;
+                    sinks.push(Box::new(sink));
+                }
+            }
+        }
+
+        Ok(sinks)
+    }
+}
+
+/// A check result, as written by the sinks other than pulsar
+#[derive(Serialize)]
+struct ResultLine<'a> {
+    check_type: CheckType,
+    #[serde(flatten)]
+    message: &'a CheckMessage,
+}
+
+fn json_line(check_type: CheckType, message: &CheckMessage) -> serde_json::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(&ResultLine {
+        check_type,
+        message,
+    })?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Writes results to stdout as json lines
+pub struct StdoutSink;
+
+impl ResultSink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn send(&mut self, check_type: CheckType, message: CheckMessage) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let written = json_line(check_type, &message)
+                .map_err(io::Error::from)
+                .and_then(|line| {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&line)?;
+                    stdout.flush()
+                });
+
+            if let Err(e) = written {
+                error!("Could not write result of check {} : {e}", message.check_id);
+            }
+        })
+    }
+}
+
+/// Appends results as json lines to a file, rotated once past `max_bytes`
+///
+/// Rotated files are renamed `<file>.1` up to `<file>.<keep>`, the oldest first dropped.
+pub struct FileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl FileSink {
+    pub fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_bytes,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl ResultSink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn send(&mut self, check_type: CheckType, message: CheckMessage) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let written = json_line(check_type, &message)
+                .map_err(io::Error::from)
+                .and_then(|line| self.write(&line));
+
+            if let Err(e) = written {
+                error!(
+                    "Could not write result of check {} to {} : {e}",
+                    message.check_id,
+                    self.path.display()
+                );
+            }
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Err(e) = self.file.sync_data() {
+                error!("Could not sync results file {} : {e}", self.path.display());
+            }
+        })
+    }
+}
+
+/// Posts results in batches to an http webhook, as a json array
+///
+/// A batch that can't be posted is dropped.
+pub struct WebhookSink {
+    client: HttpClient,
+    url: Url,
+    batch: Vec<serde_json::Value>,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str) -> Result<Self, url::ParseError> {
+        Ok(Self {
+            client: HttpClient::new(),
+            url: Url::parse(url)?,
+            batch: Vec::with_capacity(WEBHOOK_BATCH),
+        })
+    }
+
+    async fn post(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = std::mem::take(&mut self.batch);
+
+        let posted = match serde_json::to_vec(&batch) {
+            Ok(body) => {
+                self.client
+                    .post_json(&self.url, body, WEBHOOK_TIMEOUT)
+                    .await
+            }
+            Err(e) => Err(e.to_string()),
+        }
+        .and_then(|status| match status.is_success() {
+            true => Ok(()),
+            false => Err(format!("got status {status}")),
+        });
+
+        if let Err(e) = posted {
+            warn!(
+                "Could not post {} results to webhook {}, dropping them : {e}",
+                batch.len(),
+                self.url
+            );
+        }
+    }
+}
+
+impl ResultSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn send(&mut self, check_type: CheckType, message: CheckMessage) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let line = ResultLine {
+                check_type,
+                message: &message,
+            };
+            match serde_json::to_value(&line) {
+                Ok(value) => self.batch.push(value),
+                Err(e) => error!(
+                    "Could not encode result of check {} : {e}",
+                    message.check_id
+                ),
+            }
+
+            if self.batch.len() >= WEBHOOK_BATCH {
+                self.post().await;
+            }
+        })
+    }
+
+    fn tick(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(self.post())
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(self.post())
+    }
+}
+
+async fn drive(mut sink: Box<dyn ResultSink>, mut receiver: Receiver<(CheckType, CheckMessage)>) {
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            received = receiver.recv() => {
+                let Some((check_type, check_msg)) = received else {
+                    break;
+                };
+                sink.send(check_type, check_msg).await;
+            }
+            _ = ticks.tick() => sink.tick().await,
+        }
+    }
+
+    sink.flush().await;
+    info!("Result sink {} flushed", sink.name());
+}
+
+/// Hand results over to every sink until `flush` is cancelled, then deliver the
+/// queued ones and wait for the sinks to be flushed
+pub async fn dispatch(
+    sinks: Vec<Box<dyn ResultSink>>,
+    mut receiver: Receiver<(CheckType, CheckMessage)>,
+    metrics: Arc<AgentMetrics>,
+    flush: CancellationToken,
+) {
+    let tasks = TaskTracker::new();
+    let senders: Vec<_> = sinks
+        .into_iter()
+        .map(|sink| {
+            let (sender, receiver) = mpsc::channel(SINK_QUEUE);
+            let name = sink.name();
+            tasks.spawn(drive(sink, receiver));
+            (name, sender)
+        })
+        .collect();
+    let mut flushing = false;
+
+    loop {
+        tokio::select! {
+            received = receiver.recv() => {
+                let Some((check_type, check_msg)) = received else {
+                    break;
+                };
+                metrics.record_probe(check_type, &check_msg);
+                for (name, sender) in &senders {
+                    let result = (check_type, check_msg.clone());
+                    // the queued results are all delivered when flushing, the
+                    // slow sinks are waited for until the shutdown timeout
+                    if flushing {
+                        _ = sender.send(result).await;
+                    } else if let Err(TrySendError::Full(_)) = sender.try_send(result) {
+                        warn!("Result sink {name} is full, dropping result of check {}", check_msg.check_id);
+                        metrics.record_sink_drop(name);
+                    }
+                }
+            }
+            _ = flush.cancelled(), if !flushing => {
+                info!("Flushing {} queued results...", receiver.len());
+                flushing = true;
+                receiver.close();
+            }
+        }
+    }
+
+    drop(senders);
+    tasks.close();
+    tasks.wait().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use chrono::Utc;
+    use isok_data::pulsar_messages::CheckOutcome;
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// Sink never done with the first result it gets
+    struct StuckSink;
+
+    impl ResultSink for StuckSink {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+
+        fn send(&mut self, _: CheckType, _: CheckMessage) -> BoxFuture<'_, ()> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    struct CountingSink(Arc<AtomicUsize>);
+
+    impl ResultSink for CountingSink {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+
+        fn send(&mut self, _: CheckType, _: CheckMessage) -> BoxFuture<'_, ()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async {})
+        }
+    }
+
+    fn message() -> CheckMessage {
+        CheckMessage {
+            check_id: Uuid::new_v4(),
+            agent_id: "agent".to_string(),
+            timestamp: Utc::now().fixed_offset(),
+            latency: Some(1),
+            outcome: CheckOutcome::Up,
+            attempts: 1,
+            attempt_outcomes: vec![CheckOutcome::Up],
+            fields: serde_json::Value::Null,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stuck_sink_does_not_hold_the_others_back() {
+        let sent = SINK_QUEUE * 2;
+        let counted = Arc::new(AtomicUsize::new(0));
+        let sinks: Vec<Box<dyn ResultSink>> = vec![
+            Box::new(StuckSink),
+            Box::new(CountingSink(Arc::clone(&counted))),
+        ];
+        let metrics = Arc::new(AgentMetrics::default());
+        let (sender, receiver) = mpsc::channel(sent);
+        let dispatched = tokio::spawn(dispatch(
+            sinks,
+            receiver,
+            Arc::clone(&metrics),
+            CancellationToken::new(),
+        ));
+
+        for _ in 0..sent {
+            sender.send((CheckType::Http, message())).await.unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while counted.load(Ordering::Relaxed) < sent {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("every result should reach the counting sink");
+
+        let drops = metrics.sink_drops();
+        assert!(drops.get("stuck").is_some_and(|dropped| *dropped > 0));
+        assert!(!drops.contains_key("counting"));
+        dispatched.abort();
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use isok_data::pulsar_messages::{CheckMessage, CheckType};
+
+const SEGMENT_EXTENSION: &str = "seg";
+/// Replay position, as `<segment id> <byte offset>`, kept across restarts
+const CURSOR_FILE: &str = "cursor";
+
+/// A result waiting in the spool for pulsar to be reachable again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpoolRecord {
+    pub check_type: CheckType,
+    pub spooled_at: DateTime<Utc>,
+    pub message: CheckMessage,
+}
+
+/// A record read back from the spool, with its end offset in the head segment
+#[derive(Debug, Clone)]
+pub struct SpooledRecord {
+    pub record: Option<SpoolRecord>,
+    end: u64,
+}
+
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    path: PathBuf,
+    size: u64,
+    records: u64,
+}
+
+/// Reader of the oldest segment, kept open between replays
+#[derive(Debug)]
+struct HeadReader {
+    id: u64,
+    reader: BufReader<File>,
+    /// Offset of the next line to read
+    end: u64,
+}
+
+/// Counters of a [`Spool`], readable without locking it while it does disk io
+#[derive(Debug, Default)]
+pub struct SpoolStats {
+    results: AtomicU64,
+    bytes: AtomicU64,
+    /// Milliseconds since the epoch, `i64::MIN` if the spool is empty
+    oldest: AtomicI64,
+    dropped: AtomicU64,
+}
+
+impl SpoolStats {
+    /// Number of results waiting
+    pub fn len(&self) -> u64 {
+        self.results.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of the segments on disk, in bytes
+    pub fn size(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Spool time of the oldest result waiting
+    pub fn oldest(&self) -> Option<DateTime<Utc>> {
+        match self.oldest.load(Ordering::Relaxed) {
+            i64::MIN => None,
+            millis => DateTime::from_timestamp_millis(millis),
+        }
+    }
+
+    /// Results dropped because the spool was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Bounded on-disk spool of results, an append-only log split in segments
+///
+/// Records are appended as json lines to the newest segment and replayed from
+/// the oldest one, which is deleted once fully replayed. Past `max_bytes` the
+/// oldest segments are dropped.
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    /// Oldest first, the last one receives appends
+    segments: VecDeque<Segment>,
+    writer: Option<File>,
+    reader: Option<HeadReader>,
+    /// Records read from the replay position on, not acknowledged yet
+    read_ahead: VecDeque<SpooledRecord>,
+    /// Replay position in the oldest segment
+    cursor: u64,
+    /// Spool time of the next record to replay
+    oldest: Option<DateTime<Utc>>,
+    dropped: u64,
+    stats: Arc<SpoolStats>,
+}
+
+fn segment_id(path: &Path) -> Option<u64> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+impl Spool {
+    /// Open the spool in `dir`, picking up the segments left by a previous run
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64, segment_bytes: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut ids: Vec<u64> = fs::read_dir(&dir)?
+            .filter_map(|entry| segment_id(&entry.ok()?.path()))
+            .collect();
+        ids.sort_unstable();
+
+        let mut segments = VecDeque::new();
+        for id in ids {
+            let path = dir.join(format!("{id:020}.{SEGMENT_EXTENSION}"));
+            let file = File::open(&path)?;
+            let size = file.metadata()?.len();
+            let records = BufReader::new(file).lines().count() as u64;
+            segments.push_back(Segment {
+                id,
+                path,
+                size,
+                records,
+            });
+        }
+
+        let mut spool = Self {
+            dir,
+            max_bytes,
+            segment_bytes: segment_bytes.min(max_bytes),
+            segments,
+            writer: None,
+            reader: None,
+            read_ahead: VecDeque::new(),
+            cursor: 0,
+            oldest: None,
+            dropped: 0,
+            stats: Arc::default(),
+        };
+        spool.restore_cursor();
+        if spool
+            .segments
+            .front()
+            .is_some_and(|head| spool.cursor >= head.size)
+        {
+            spool.remove_head()?;
+        }
+        spool.refresh_oldest()?;
+        spool.publish_stats();
+
+        if !spool.is_empty() {
+            info!(
+                "Spool {} holds {} results to replay",
+                spool.dir.display(),
+                spool.len()
+            );
+        }
+        Ok(spool)
+    }
+
+    fn restore_cursor(&mut self) {
+        let Ok(cursor) = fs::read_to_string(self.dir.join(CURSOR_FILE)) else {
+            return;
+        };
+        let Some((id, offset)) = cursor.trim().split_once(' ') else {
+            return;
+        };
+        let (Ok(id), Ok(offset)) = (id.parse::<u64>(), offset.parse::<u64>()) else {
+            return;
+        };
+
+        let Some(head) = self.segments.front_mut() else {
+            return;
+        };
+        if head.id != id || offset > head.size {
+            return;
+        }
+
+        self.cursor = offset;
+        // the replayed records are no longer counted
+        if let Ok(file) = File::open(&head.path) {
+            let replayed = BufReader::new(file.take(offset)).lines().count() as u64;
+            head.records = head.records.saturating_sub(replayed);
+        }
+    }
+
+    fn save_cursor(&self) -> io::Result<()> {
+        let id = self
+            .segments
+            .front()
+            .map(|head| head.id)
+            .unwrap_or_default();
+        let tmp = self.dir.join(format!("{CURSOR_FILE}.tmp"));
+        fs::write(&tmp, format!("{id} {}", self.cursor))?;
+        fs::rename(tmp, self.dir.join(CURSOR_FILE))
+    }
+
+    /// Number of results waiting
+    pub fn len(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.records).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of the segments on disk, in bytes
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    /// Spool time of the oldest result waiting
+    pub fn oldest(&self) -> Option<DateTime<Utc>> {
+        self.oldest
+    }
+
+    /// Results dropped because the spool was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Counters of the spool, kept up to date as results are pushed and replayed
+    pub fn stats(&self) -> Arc<SpoolStats> {
+        Arc::clone(&self.stats)
+    }
+
+    fn publish_stats(&self) {
+        let oldest = self
+            .oldest
+            .map_or(i64::MIN, |oldest| oldest.timestamp_millis());
+        self.stats.results.store(self.len(), Ordering::Relaxed);
+        self.stats.bytes.store(self.size(), Ordering::Relaxed);
+        self.stats.oldest.store(oldest, Ordering::Relaxed);
+        self.stats.dropped.store(self.dropped, Ordering::Relaxed);
+    }
+
+    fn refresh_oldest(&mut self) -> io::Result<()> {
+        self.oldest = self
+            .peek(1)?
+            .first()
+            .and_then(|spooled| spooled.record.as_ref())
+            .map(|record| record.spooled_at);
+        Ok(())
+    }
+
+    fn new_segment(&mut self) -> io::Result<()> {
+        let id = self
+            .segments
+            .back()
+            .map(|segment| segment.id + 1)
+            .unwrap_or_default();
+        let path = self.dir.join(format!("{id:020}.{SEGMENT_EXTENSION}"));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if let Some(writer) = self.writer.replace(file) {
+            writer.sync_data()?;
+        }
+        self.segments.push_back(Segment {
+            id,
+            path,
+            size: 0,
+            records: 0,
+        });
+        Ok(())
+    }
+
+    /// Append a result to the spool
+    pub fn push(&mut self, check_type: CheckType, message: CheckMessage) -> io::Result<()> {
+        let record = SpoolRecord {
+            check_type,
+            spooled_at: Utc::now(),
+            message,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let full = self
+            .segments
+            .back()
+            .map(|segment| segment.size + line.len() as u64 > self.segment_bytes)
+            .unwrap_or(true);
+        if self.writer.is_none() || full {
+            self.new_segment()?;
+        }
+
+        if let (Some(writer), Some(segment)) = (self.writer.as_mut(), self.segments.back_mut()) {
+            writer.write_all(&line)?;
+            segment.size += line.len() as u64;
+            segment.records += 1;
+        }
+        self.oldest = self.oldest.or(Some(record.spooled_at));
+
+        while self.size() > self.max_bytes && self.segments.len() > 1 {
+            self.drop_head()?;
+        }
+        self.publish_stats();
+        Ok(())
+    }
+
+    fn drop_head(&mut self) -> io::Result<()> {
+        if let Some(head) = self.segments.pop_front() {
+            warn!(
+                "Spool is full, dropping {} results of segment {}",
+                head.records, head.id
+            );
+            self.dropped += head.records;
+            fs::remove_file(&head.path)?;
+        }
+        self.close_reader();
+        self.refresh_oldest()?;
+        self.save_cursor()
+    }
+
+    /// Delete the oldest segment once replayed
+    fn remove_head(&mut self) -> io::Result<()> {
+        if let Some(head) = self.segments.pop_front() {
+            fs::remove_file(&head.path)?;
+        }
+        if self.segments.is_empty() {
+            self.writer = None;
+        }
+        self.close_reader();
+        Ok(())
+    }
+
+    /// Forget the head segment reader, the next replay starts from the new head
+    fn close_reader(&mut self) {
+        self.reader = None;
+        self.read_ahead.clear();
+        self.cursor = 0;
+    }
+
+    /// Read up to `max` results from the replay position, oldest first
+    ///
+    /// Records that can't be parsed are returned empty so that they get acknowledged.
+    pub fn peek(&mut self, max: usize) -> io::Result<Vec<SpooledRecord>> {
+        let Some(head) = self.segments.front() else {
+            return Ok(Vec::new());
+        };
+
+        let reader = match &mut self.reader {
+            Some(reader) if reader.id == head.id => reader,
+            reader => {
+                let mut file = File::open(&head.path)?;
+                file.seek(SeekFrom::Start(self.cursor))?;
+                self.read_ahead.clear();
+                reader.insert(HeadReader {
+                    id: head.id,
+                    reader: BufReader::new(file),
+                    end: self.cursor,
+                })
+            }
+        };
+
+        let mut line = String::new();
+        while self.read_ahead.len() < max {
+            line.clear();
+            let read = reader.reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            reader.end += read as u64;
+
+            // a line cut by a crash fails to parse as well
+            let record = serde_json::from_str(&line)
+                .map_err(|e| error!("Dropping unreadable spooled result : {e}"))
+                .ok();
+            self.read_ahead.push_back(SpooledRecord {
+                record,
+                end: reader.end,
+            });
+        }
+        Ok(self.read_ahead.iter().take(max).cloned().collect())
+    }
+
+    /// Mark results returned by [peek](Spool::peek) as replayed, up to `last` included
+    pub fn ack(&mut self, count: u64, last: &SpooledRecord) -> io::Result<()> {
+        let Some(head) = self.segments.front_mut() else {
+            return Ok(());
+        };
+        self.cursor = last.end;
+        head.records = head.records.saturating_sub(count);
+        self.read_ahead
+            .drain(..(count as usize).min(self.read_ahead.len()));
+
+        if self.cursor >= head.size {
+            self.remove_head()?;
+        }
+
+        self.refresh_oldest()?;
+        self.publish_stats();
+        self.save_cursor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use isok_data::pulsar_messages::CheckOutcome;
+    use uuid::Uuid;
+
+    use super::*;
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("isok-spool-{}", Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn message(agent_id: &str) -> CheckMessage {
+        CheckMessage {
+            check_id: Uuid::new_v4(),
+            agent_id: agent_id.to_string(),
+            timestamp: Utc::now().fixed_offset(),
+            latency: Some(1),
+            outcome: CheckOutcome::Up,
+            attempts: 1,
+            attempt_outcomes: vec![CheckOutcome::Up],
+            fields: serde_json::Value::Null,
+        }
+    }
+
+    fn agents(records: &[SpooledRecord]) -> Vec<String> {
+        records
+            .iter()
+            .map(|spooled| spooled.record.as_ref().unwrap().message.agent_id.clone())
+            .collect()
+    }
+
+    fn push(spool: &mut Spool, agents: &[&str]) {
+        for agent in agents {
+            spool.push(CheckType::Http, message(agent)).unwrap();
+        }
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let dir = Dir::new();
+        let mut spool = Spool::open(&dir.0, 1 << 20, 1 << 20).unwrap();
+        let stats = spool.stats();
+        push(&mut spool, &["a", "b", "c"]);
+
+        assert_eq!(stats.len(), 3);
+        assert!(stats.oldest().is_some());
+
+        let batch = spool.peek(2).unwrap();
+        assert_eq!(agents(&batch), ["a", "b"]);
+        // not acknowledged, the same records come back
+        assert_eq!(agents(&spool.peek(2).unwrap()), ["a", "b"]);
+
+        spool.ack(1, &batch[0]).unwrap();
+        assert_eq!(stats.len(), 2);
+        // the head reader picks up the records appended since
+        push(&mut spool, &["d"]);
+        let batch = spool.peek(10).unwrap();
+        assert_eq!(agents(&batch), ["b", "c", "d"]);
+
+        spool.ack(3, &batch[2]).unwrap();
+        assert!(stats.is_empty());
+        assert!(stats.oldest().is_none());
+        assert!(spool.peek(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn replays_across_segments() {
+        let dir = Dir::new();
+        let line = serde_json::to_vec(&SpoolRecord {
+            check_type: CheckType::Http,
+            spooled_at: Utc::now(),
+            message: message("a"),
+        })
+        .unwrap()
+        .len() as u64;
+        let mut spool = Spool::open(&dir.0, 1 << 20, 2 * line + 2).unwrap();
+        push(&mut spool, &["a", "b", "c", "d", "e"]);
+        assert_eq!(spool.segments.len(), 3);
+
+        let mut replayed = Vec::new();
+        loop {
+            let batch = spool.peek(10).unwrap();
+            let Some(last) = batch.last() else {
+                break;
+            };
+            spool.ack(batch.len() as u64, last).unwrap();
+            replayed.extend(agents(&batch));
+        }
+        assert_eq!(replayed, ["a", "b", "c", "d", "e"]);
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn resumes_from_cursor() {
+        let dir = Dir::new();
+        {
+            let mut spool = Spool::open(&dir.0, 1 << 20, 1 << 20).unwrap();
+            push(&mut spool, &["a", "b", "c"]);
+            let batch = spool.peek(2).unwrap();
+            spool.ack(2, &batch[1]).unwrap();
+        }
+
+        let mut spool = Spool::open(&dir.0, 1 << 20, 1 << 20).unwrap();
+        assert_eq!(spool.stats().len(), 1);
+        assert_eq!(agents(&spool.peek(10).unwrap()), ["c"]);
+    }
+
+    #[test]
+    fn drops_oldest_segments_when_full() {
+        let dir = Dir::new();
+        let mut spool = Spool::open(&dir.0, 1000, 400).unwrap();
+        let stats = spool.stats();
+        for _ in 0..20 {
+            push(&mut spool, &["a"]);
+        }
+
+        assert!(stats.size() <= 1000);
+        assert!(stats.dropped() > 0);
+        assert_eq!(stats.len() + stats.dropped(), 20);
+    }
+}
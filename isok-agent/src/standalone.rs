@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::{error, info, warn};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use isok_data::check::CheckOutput;
+
+use crate::job::JobsHandler;
+
+/// Checks run by a standalone agent
+#[derive(Debug, Default, Deserialize)]
+pub struct ChecksFile {
+    #[serde(default)]
+    pub checks: Vec<CheckOutput>,
+}
+
+impl ChecksFile {
+    /// Parse a checks file, in the format given by its extension : toml, yaml or json
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+            Some("json") => serde_json::from_str(&content).map_err(|e| e.to_string()),
+            _ => Err("unknown format, expected a .toml, .yaml or .json file".to_string()),
+        }
+    }
+}
+
+/// Operations a checks file reload drives, those of [`JobsHandler`]
+pub trait ChecksHandler {
+    fn add_check(&mut self, check: &CheckOutput);
+    fn update_check(&mut self, check: &CheckOutput);
+    fn remove_check(&mut self, id: Uuid);
+}
+
+impl ChecksHandler for JobsHandler {
+    fn add_check(&mut self, check: &CheckOutput) {
+        JobsHandler::add_check(self, check)
+    }
+
+    fn update_check(&mut self, check: &CheckOutput) {
+        JobsHandler::update_check(self, check)
+    }
+
+    fn remove_check(&mut self, id: Uuid) {
+        JobsHandler::remove_check(self, id)
+    }
+}
+
+/// Keeps the jobs of a standalone agent in line with its checks file
+pub struct ChecksWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    /// Definitions of the checks loaded, serialized to spot the changed ones
+    checks: HashMap<Uuid, String>,
+}
+
+impl ChecksWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            modified: None,
+            checks: HashMap::new(),
+        }
+    }
+
+    /// Reload the checks file if it changed since the last poll
+    pub fn poll(&mut self, handler: &mut impl ChecksHandler) {
+        let modified = match fs::metadata(&self.path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                error!("Can't read checks file {} : {e}", self.path.display());
+                return;
+            }
+        };
+        if self.modified == Some(modified) {
+            return;
+        }
+
+        match ChecksFile::load(&self.path) {
+            Ok(file) => {
+                self.modified = Some(modified);
+                self.apply(handler, file);
+            }
+            // a file being written is loaded at the next poll
+            Err(e) => error!("Can't load checks file {} : {e}", self.path.display()),
+        }
+    }
+
+    fn apply(&mut self, handler: &mut impl ChecksHandler, file: ChecksFile) {
+        let mut checks = HashMap::with_capacity(file.checks.len());
+
+        for check in file.checks {
+            let definition = serde_json::to_string(&check).unwrap_or_default();
+            // a check defined twice is compared to its first definition, already applied
+            let previous = match checks.insert(check.id, definition.clone()) {
+                Some(first) => {
+                    warn!("Check {} is defined twice, keeping the last one", check.id);
+                    Some(first)
+                }
+                None => self.checks.remove(&check.id),
+            };
+            match previous {
+                None => handler.add_check(&check),
+                Some(previous) if previous != definition => handler.update_check(&check),
+                Some(_) => {}
+            }
+        }
+
+        for id in self.checks.keys() {
+            handler.remove_check(*id);
+        }
+
+        info!(
+            "Loaded {} checks from {}",
+            checks.len(),
+            self.path.display()
+        );
+        self.checks = checks;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "6f1c3b1e-9a0a-4c1e-8f0e-2b7d4c1a9e11";
+    const OTHER_ID: &str = "0b5e7f0c-3a61-4d2e-9c55-7a2f1e8d4b90";
+
+    /// Operations called on the handler, in order
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl ChecksHandler for Recorder {
+        fn add_check(&mut self, check: &CheckOutput) {
+            self.0.push(format!("add {} {}", check.id, check.interval));
+        }
+
+        fn update_check(&mut self, check: &CheckOutput) {
+            self.0
+                .push(format!("update {} {}", check.id, check.interval));
+        }
+
+        fn remove_check(&mut self, id: Uuid) {
+            self.0.push(format!("remove {id}"));
+        }
+    }
+
+    struct ChecksPath(PathBuf);
+
+    impl ChecksPath {
+        fn new(extension: &str, content: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("isok-checks-{}.{extension}", Uuid::new_v4()));
+            fs::write(&path, content).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for ChecksPath {
+        fn drop(&mut self) {
+            _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn check(id: &str, interval: u32) -> CheckOutput {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "owner_id": Uuid::nil(),
+            "kind": {"type": "http", "data": {"uri": "https://example.com/", "headers": {}}},
+            "max_latency": {"secs": 1, "nanos": 0},
+            "interval": interval,
+            "region": "eu",
+        }))
+        .unwrap()
+    }
+
+    fn load(extension: &str, content: &str) -> Vec<CheckOutput> {
+        let path = ChecksPath::new(extension, content);
+        ChecksFile::load(&path.0).unwrap().checks
+    }
+
+    fn assert_loaded(checks: Vec<CheckOutput>) {
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].id.to_string(), ID);
+        assert_eq!(checks[0].interval, 30);
+        assert_eq!(checks[0].max_latency, std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn load_toml() {
+        assert_loaded(load(
+            "toml",
+            &format!(
+                r#"
+                [[checks]]
+                id = "{ID}"
+                owner_id = "{}"
+                interval = 30
+                region = "eu"
+                max_latency = {{ secs = 1, nanos = 0 }}
+                kind = {{ type = "http", data = {{ uri = "https://example.com/", headers = {{}} }} }}
+                "#,
+                Uuid::nil()
+            ),
+        ));
+    }
+
+    #[test]
+    fn load_yaml() {
+        let content = format!(
+            r#"
+            checks:
+              - id: {ID}
+                owner_id: {}
+                interval: 30
+                region: eu
+                max_latency:
+                  secs: 1
+                  nanos: 0
+                kind:
+                  type: http
+                  data:
+                    uri: https://example.com/
+                    headers: {{}}
+            "#,
+            Uuid::nil()
+        );
+        assert_loaded(load("yaml", &content));
+        assert_loaded(load("yml", &content));
+    }
+
+    #[test]
+    fn load_json() {
+        let content = serde_json::json!({ "checks": [check(ID, 30)] }).to_string();
+        assert_loaded(load("json", &content));
+    }
+
+    #[test]
+    fn load_unknown_format() {
+        let path = ChecksPath::new("ini", "");
+        assert!(ChecksFile::load(&path.0).is_err());
+    }
+
+    #[test]
+    fn reloads_apply_the_changes() {
+        let mut watcher = ChecksWatcher::new(PathBuf::from("checks.json"));
+        let mut handler = Recorder::default();
+        let mut apply = |checks: Vec<CheckOutput>| {
+            watcher.apply(&mut handler, ChecksFile { checks });
+            std::mem::take(&mut handler.0)
+        };
+
+        assert_eq!(
+            apply(vec![check(ID, 30), check(OTHER_ID, 60)]),
+            [format!("add {ID} 30"), format!("add {OTHER_ID} 60")]
+        );
+        // unchanged checks are left alone
+        assert!(apply(vec![check(ID, 30), check(OTHER_ID, 60)]).is_empty());
+        assert_eq!(
+            apply(vec![check(ID, 10), check(OTHER_ID, 60)]),
+            [format!("update {ID} 10")]
+        );
+        assert_eq!(apply(vec![check(ID, 10)]), [format!("remove {OTHER_ID}")]);
+    }
+
+    #[test]
+    fn duplicated_checks_keep_the_last_definition() {
+        let mut watcher = ChecksWatcher::new(PathBuf::from("checks.json"));
+        let mut handler = Recorder::default();
+
+        let checks = vec![check(ID, 30), check(ID, 10)];
+        watcher.apply(&mut handler, ChecksFile { checks });
+        assert_eq!(
+            handler.0,
+            [format!("add {ID} 30"), format!("update {ID} 10")]
+        );
+
+        // the second definition is the one kept
+        handler.0.clear();
+        watcher.apply(
+            &mut handler,
+            ChecksFile {
+                checks: vec![check(ID, 10)],
+            },
+        );
+        assert!(handler.0.is_empty());
+
+        let checks = vec![check(ID, 10), check(ID, 10)];
+        watcher.apply(&mut handler, ChecksFile { checks });
+        assert!(handler.0.is_empty());
+    }
+}
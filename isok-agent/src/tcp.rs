@@ -1 +1,211 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, FixedOffset, Local};
+use isok_data::check::{Host, TcpCheck};
+use isok_data::check_kinds::tcp::{TcpFailure, TcpFields};
+use isok_data::pulsar_messages::{CheckOutcome, CheckResult};
+use tokio::net::TcpStream;
+
+use crate::resolve::resolve_host;
+
+/// Map a connection error to a [`TcpFailure`]
+pub fn classify_io_error(error: &io::Error) -> TcpFailure {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => TcpFailure::Refused,
+        io::ErrorKind::TimedOut => TcpFailure::Timeout,
+        _ => match error.raw_os_error() {
+            Some(libc::ENETUNREACH) | Some(libc::EHOSTUNREACH) => TcpFailure::Unreachable,
+            _ => TcpFailure::Other,
+        },
+    }
+}
+
+/// Context of a tcp [Job](crate::job::Job)
+#[derive(Debug, Clone)]
+pub struct TcpContext {
+    host: Host,
+    port: u16,
+}
+
+impl TcpContext {
+    pub fn new(host: Host, port: u16) -> Self {
+        Self { host, port }
+    }
+
+    pub fn target(&self) -> String {
+        match &self.host {
+            Host::IpAddr(ip) => SocketAddr::new(*ip, self.port).to_string(),
+            Host::Domain(domain) => format!("{}:{}", domain.host(), self.port),
+        }
+    }
+
+    /// Resolve host and open a tcp connection, closed right after the handshake,
+    /// both have to be done within `timeout`
+    pub async fn run(&self, timeout: Duration) -> TcpResult {
+        let datetime = Local::now().fixed_offset();
+        let started = Instant::now();
+        let timed_out = || {
+            Err((
+                TcpFailure::Timeout,
+                format!("no answer after {}ms", timeout.as_millis()),
+            ))
+        };
+
+        let ip = match tokio::time::timeout(timeout, resolve_host(&self.host)).await {
+            Ok(Ok(ip)) => ip,
+            Ok(Err(e)) => {
+                return TcpResult {
+                    datetime,
+                    elapsed: started.elapsed(),
+                    outcome: Err((TcpFailure::DnsFailure, e.to_string())),
+                }
+            }
+            Err(_) => {
+                return TcpResult {
+                    datetime,
+                    elapsed: started.elapsed(),
+                    outcome: timed_out(),
+                }
+            }
+        };
+
+        let before = Instant::now();
+        let outcome = match tokio::time::timeout(
+            timeout.saturating_sub(started.elapsed()),
+            TcpStream::connect(SocketAddr::new(ip, self.port)),
+        )
+        .await
+        {
+            Ok(Ok(_stream)) => Ok(()),
+            Ok(Err(e)) => Err((classify_io_error(&e), e.to_string())),
+            Err(_) => timed_out(),
+        };
+
+        TcpResult {
+            datetime,
+            elapsed: before.elapsed(),
+            outcome,
+        }
+    }
+}
+
+impl From<TcpCheck> for TcpContext {
+    fn from(value: TcpCheck) -> Self {
+        Self::new(value.host, value.port)
+    }
+}
+
+/// Result of a tcp connection ready to be send to warp10
+pub struct TcpResult {
+    pub datetime: DateTime<FixedOffset>,
+    pub elapsed: Duration,
+    pub outcome: Result<(), (TcpFailure, String)>,
+}
+
+impl Into<CheckResult<TcpFields>> for TcpResult {
+    fn into(self) -> CheckResult<TcpFields> {
+        CheckResult {
+            timestamp: self.datetime,
+            latency: Some(self.elapsed),
+            outcome: match &self.outcome {
+                Ok(()) => CheckOutcome::Up,
+                Err((TcpFailure::Timeout, _)) => CheckOutcome::Timeout,
+                Err(_) => CheckOutcome::Down,
+            },
+            fields: match self.outcome {
+                Ok(()) => TcpFields::connected(self.elapsed.as_micros() as u64),
+                Err((failure, error)) => TcpFields::failed(failure, error),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use socket2::{Domain, Socket, Type};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn localhost(port: u16) -> TcpContext {
+        TcpContext::new(Host::IpAddr(IpAddr::V4(Ipv4Addr::LOCALHOST)), port)
+    }
+
+    #[tokio::test]
+    async fn connected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let result: CheckResult<TcpFields> =
+            localhost(port).run(Duration::from_secs(5)).await.into();
+        assert_eq!(result.outcome, CheckOutcome::Up);
+        assert!(result.fields.connected);
+        assert!(result.fields.connect_time.is_some());
+        assert_eq!(result.fields.failure, None);
+    }
+
+    #[tokio::test]
+    async fn refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let result: CheckResult<TcpFields> =
+            localhost(port).run(Duration::from_secs(5)).await.into();
+        assert_eq!(result.outcome, CheckOutcome::Down);
+        assert!(!result.fields.connected);
+        assert_eq!(result.fields.failure, Some(TcpFailure::Refused));
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        // a listener never accepting, with a full backlog, drops the handshakes : unlike a
+        // non-routable address, this does not depend on the routes of the host running the tests
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0).into())
+            .unwrap();
+        socket.listen(0).unwrap();
+        let address = socket.local_addr().unwrap().as_socket().unwrap();
+
+        let mut backlog = Vec::new();
+        for _ in 0..4 {
+            if let Ok(Ok(stream)) =
+                tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(address)).await
+            {
+                backlog.push(stream);
+            }
+        }
+
+        let timeout = Duration::from_millis(200);
+        let result: CheckResult<TcpFields> = localhost(address.port()).run(timeout).await.into();
+        assert_eq!(result.outcome, CheckOutcome::Timeout);
+        assert_eq!(result.fields.failure, Some(TcpFailure::Timeout));
+        assert!(result.latency.is_some_and(|latency| latency >= timeout));
+    }
+
+    #[test]
+    fn io_errors() {
+        let error = |kind| io::Error::new(kind, "");
+        assert_eq!(
+            classify_io_error(&error(io::ErrorKind::ConnectionRefused)),
+            TcpFailure::Refused
+        );
+        assert_eq!(
+            classify_io_error(&error(io::ErrorKind::TimedOut)),
+            TcpFailure::Timeout
+        );
+        assert_eq!(
+            classify_io_error(&io::Error::from_raw_os_error(libc::ENETUNREACH)),
+            TcpFailure::Unreachable
+        );
+        assert_eq!(
+            classify_io_error(&error(io::ErrorKind::Other)),
+            TcpFailure::Other
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use isok_data::check::TargetLimits;
+
+use crate::job::SchedulerMetrics;
+
+/// Delay between two sweeps of the idle hosts
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Probes in flight and rate budget toward a host
+struct HostState {
+    limits: TargetLimits,
+    in_flight: u32,
+    tokens: f64,
+    refilled: Instant,
+    released: Arc<Notify>,
+    /// Checks with a probe waiting for the limits, at most one probe per check
+    waiting: HashSet<Uuid>,
+}
+
+impl HostState {
+    fn new(limits: TargetLimits, now: Instant) -> Self {
+        Self {
+            limits,
+            in_flight: 0,
+            tokens: burst(&limits),
+            refilled: now,
+            released: Arc::new(Notify::new()),
+            waiting: HashSet::new(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = rate(&self.limits) {
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(burst(&self.limits));
+        }
+        self.refilled = now;
+    }
+
+    /// Nothing in flight or waiting, and the rate budget full again
+    fn idle(&self) -> bool {
+        self.in_flight == 0 && self.waiting.is_empty() && self.tokens >= burst(&self.limits)
+    }
+}
+
+fn rate(limits: &TargetLimits) -> Option<f64> {
+    limits.rate.filter(|rate| *rate > 0.0)
+}
+
+fn burst(limits: &TargetLimits) -> f64 {
+    limits.burst.unwrap_or(1).max(1) as f64
+}
+
+/// What a probe waits for before starting
+enum Wait {
+    /// Another probe of the host to finish
+    Released(Arc<Notify>),
+    /// The rate budget of the host to refill
+    Refill(Duration),
+    /// Nothing, a previous probe of the check is still waiting
+    Skip,
+}
+
+struct Hosts {
+    states: HashMap<String, HostState>,
+    next_prune: Instant,
+}
+
+/// Caps the probes in flight and the probes started per second toward every
+/// target host, a host being an authority or an ip
+///
+/// Probes over the limits are delayed and their delay is accounted as
+/// scheduling lag. A probe is only dropped when the previous probe of its check
+/// is still waiting, so a check never has more than one probe waiting. A host
+/// probed by checks with different limits gets the limits of the last probe started.
+pub struct TargetLimiter {
+    defaults: TargetLimits,
+    hosts: Mutex<Hosts>,
+    throttled: AtomicU64,
+    skipped: AtomicU64,
+    /// Lags in microseconds
+    total_lag: AtomicU64,
+    max_lag: AtomicU64,
+}
+
+impl TargetLimiter {
+    pub fn new(defaults: TargetLimits) -> Self {
+        Self {
+            defaults,
+            hosts: Mutex::new(Hosts {
+                states: HashMap::new(),
+                next_prune: Instant::now() + PRUNE_INTERVAL,
+            }),
+            throttled: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            total_lag: AtomicU64::new(0),
+            max_lag: AtomicU64::new(0),
+        }
+    }
+
+    /// Limits of a check toward `target`, its unset limits taken from the defaults,
+    /// delays being accounted as lag of the `scheduler` of the check
+    pub fn throttle(
+        self: &Arc<Self>,
+        check_id: Uuid,
+        target: String,
+        limits: TargetLimits,
+        scheduler: Arc<SchedulerMetrics>,
+    ) -> Throttle {
+        Throttle {
+            limiter: Arc::clone(self),
+            check_id,
+            target,
+            limits: limits.or(self.defaults),
+            scheduler,
+        }
+    }
+
+    /// Take a slot toward `target`, or tell what to wait for, registering the
+    /// check as waiting unless it already is from a previous probe
+    fn try_acquire(
+        &self,
+        check_id: Uuid,
+        target: &str,
+        limits: TargetLimits,
+        waiting: bool,
+    ) -> Result<(), Wait> {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+
+        if now >= hosts.next_prune {
+            hosts.states.retain(|_, state| {
+                state.refill(now);
+                !state.idle()
+            });
+            hosts.next_prune = now + PRUNE_INTERVAL;
+        }
+
+        let state = hosts
+            .states
+            .entry(target.to_string())
+            .or_insert_with(|| HostState::new(limits, now));
+        state.refill(now);
+        state.limits = limits;
+
+        if !waiting && state.waiting.contains(&check_id) {
+            return Err(Wait::Skip);
+        }
+
+        let wait = match limits.max_concurrent.filter(|max| *max > 0) {
+            Some(max_concurrent) if state.in_flight >= max_concurrent => {
+                Some(Wait::Released(Arc::clone(&state.released)))
+            }
+            _ => rate(&limits)
+                .filter(|_| state.tokens < 1.0)
+                .map(|rate| Wait::Refill(Duration::from_secs_f64((1.0 - state.tokens) / rate))),
+        };
+        if let Some(wait) = wait {
+            state.waiting.insert(check_id);
+            return Err(wait);
+        }
+
+        if rate(&limits).is_some() {
+            state.tokens -= 1.0;
+        }
+        state.in_flight += 1;
+        Ok(())
+    }
+
+    /// The probe of `check_id` stopped waiting, acquired or cancelled
+    fn stop_waiting(&self, check_id: &Uuid, target: &str) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(state) = hosts.states.get_mut(target) {
+            state.waiting.remove(check_id);
+        }
+    }
+
+    fn release(&self, target: &str) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(state) = hosts.states.get_mut(target) {
+            state.in_flight = state.in_flight.saturating_sub(1);
+            state.released.notify_one();
+        }
+    }
+
+    fn record_lag(&self, lag: Duration) {
+        let lag = lag.as_micros() as u64;
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        self.total_lag.fetch_add(lag, Ordering::Relaxed);
+        self.max_lag.fetch_max(lag, Ordering::Relaxed);
+    }
+
+    /// Probes delayed by the limits so far
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Probes dropped as the previous probe of their check was still waiting
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// Delay added to the delayed probes, summed
+    pub fn total_lag(&self) -> Duration {
+        Duration::from_micros(self.total_lag.load(Ordering::Relaxed))
+    }
+
+    pub fn max_lag(&self) -> Duration {
+        Duration::from_micros(self.max_lag.load(Ordering::Relaxed))
+    }
+
+    /// Hosts currently tracked
+    pub fn hosts(&self) -> usize {
+        self.hosts
+            .lock()
+            .map(|hosts| hosts.states.len())
+            .unwrap_or_default()
+    }
+}
+
+/// Limits a probe waits for before starting, see [`TargetLimiter`]
+pub struct Throttle {
+    limiter: Arc<TargetLimiter>,
+    check_id: Uuid,
+    target: String,
+    limits: TargetLimits,
+    scheduler: Arc<SchedulerMetrics>,
+}
+
+/// Registration of a probe waiting for its target, cleared when dropped
+struct Waiting<'a> {
+    throttle: &'a Throttle,
+    registered: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.registered {
+            let throttle = self.throttle;
+            throttle
+                .limiter
+                .stop_waiting(&throttle.check_id, &throttle.target);
+        }
+    }
+}
+
+impl Throttle {
+    /// Wait for the target to be under its limits, the returned permit holds
+    /// a concurrency slot until dropped, none if the probe is skipped
+    pub async fn acquire(self) -> Option<TargetPermit> {
+        let start = Instant::now();
+        let mut waiting = Waiting {
+            throttle: &self,
+            registered: false,
+        };
+
+        loop {
+            let acquired = self.limiter.try_acquire(
+                self.check_id,
+                &self.target,
+                self.limits,
+                waiting.registered,
+            );
+            match acquired {
+                Ok(()) => break,
+                Err(Wait::Skip) => {
+                    warn!(
+                        "Skipping probe of check {} toward {}, the previous one is still waiting for target limits",
+                        self.check_id, self.target
+                    );
+                    self.limiter.skipped.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                Err(Wait::Released(released)) => {
+                    waiting.registered = true;
+                    released.notified().await
+                }
+                Err(Wait::Refill(delay)) => {
+                    waiting.registered = true;
+                    tokio::time::sleep(delay).await
+                }
+            }
+        }
+
+        if waiting.registered {
+            let lag = start.elapsed();
+            debug!(
+                "Probe of {} delayed {}ms by target limits",
+                self.target,
+                lag.as_millis()
+            );
+            self.limiter.record_lag(lag);
+            self.scheduler.record_throttle(lag);
+        }
+        drop(waiting);
+
+        Some(TargetPermit {
+            limiter: self.limiter,
+            target: self.target,
+        })
+    }
+}
+
+/// Slot of a probe in flight toward a target
+pub struct TargetPermit {
+    limiter: Arc<TargetLimiter>,
+    target: String,
+}
+
+impl Drop for TargetPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    const HOST: &str = "example.com:443";
+
+    fn one_at_a_time() -> TargetLimits {
+        TargetLimits {
+            max_concurrent: Some(1),
+            ..Default::default()
+        }
+    }
+
+    fn acquire(limiter: &TargetLimiter, check_id: Uuid, waiting: bool) -> Result<(), Wait> {
+        limiter.try_acquire(check_id, HOST, one_at_a_time(), waiting)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_check_has_one_probe_waiting_at_most() {
+        let limiter = Arc::new(TargetLimiter::new(TargetLimits::default()));
+        let waiter = Uuid::new_v4();
+
+        assert!(acquire(&limiter, Uuid::new_v4(), false).is_ok());
+        assert!(matches!(
+            acquire(&limiter, waiter, false),
+            Err(Wait::Released(_))
+        ));
+        assert!(matches!(acquire(&limiter, waiter, false), Err(Wait::Skip)));
+        assert!(matches!(
+            acquire(&limiter, waiter, true),
+            Err(Wait::Released(_))
+        ));
+        assert!(matches!(
+            acquire(&limiter, Uuid::new_v4(), false),
+            Err(Wait::Released(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_probes_stop_waiting() {
+        let limiter = Arc::new(TargetLimiter::new(TargetLimits::default()));
+        let check_id = Uuid::new_v4();
+        let scheduler = Arc::new(SchedulerMetrics::default());
+        let throttle = |check_id| {
+            limiter.throttle(
+                check_id,
+                HOST.to_string(),
+                one_at_a_time(),
+                Arc::clone(&scheduler),
+            )
+        };
+
+        let _permit = throttle(Uuid::new_v4()).acquire().await.unwrap();
+        assert!(throttle(check_id).acquire().now_or_never().is_none());
+
+        assert!(matches!(
+            acquire(&limiter, check_id, false),
+            Err(Wait::Released(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hosts_with_waiters_are_not_pruned() {
+        let limiter = Arc::new(TargetLimiter::new(TargetLimits::default()));
+        let waiter = Uuid::new_v4();
+
+        assert!(acquire(&limiter, Uuid::new_v4(), false).is_ok());
+        assert!(acquire(&limiter, waiter, false).is_err());
+        limiter.release(HOST);
+
+        tokio::time::advance(PRUNE_INTERVAL).await;
+        assert!(limiter
+            .try_acquire(Uuid::new_v4(), "other", one_at_a_time(), false)
+            .is_ok());
+        assert_eq!(limiter.hosts(), 2);
+        assert!(acquire(&limiter, waiter, true).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_are_scheduler_lag() {
+        let limiter = Arc::new(TargetLimiter::new(TargetLimits::default()));
+        let scheduler = Arc::new(SchedulerMetrics::default());
+        let limits = TargetLimits {
+            rate: Some(1.0),
+            ..Default::default()
+        };
+        let throttle =
+            |check_id| limiter.throttle(check_id, HOST.to_string(), limits, Arc::clone(&scheduler));
+
+        drop(throttle(Uuid::new_v4()).acquire().await);
+        drop(throttle(Uuid::new_v4()).acquire().await);
+
+        assert_eq!(limiter.throttled(), 1);
+        assert!(scheduler.last_lag() >= Duration::from_secs(1));
+        assert!(scheduler.max_lag() >= Duration::from_secs(1));
+    }
+}
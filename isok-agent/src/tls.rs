@@ -0,0 +1,429 @@
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, FixedOffset, Local};
+use isok_data::check::{Host, TlsCheck, TlsExpiryThresholds};
+use isok_data::check_kinds::tls::{TlsExpiryStatus, TlsFields};
+use isok_data::pulsar_messages::{CheckOutcome, CheckResult};
+use openssl::asn1::Asn1Time;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameRef, X509Ref, X509VerifyResult};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+use crate::resolve::resolve_host;
+
+/// Format a distinguished name as `CN=example.com, O=Example`
+fn name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            format!(
+                "{}={}",
+                entry.object().nid().short_name().unwrap_or("?"),
+                String::from_utf8_lossy(entry.data().as_slice())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Match a server name against a certificate name, supporting left-most label wildcards
+fn name_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => name
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == name,
+    }
+}
+
+/// Whether the certificate is valid for `server_name`, through its SANs or its common name
+fn certificate_matches(cert: &X509Ref, server_name: &str) -> bool {
+    let ip = server_name.parse::<IpAddr>().ok();
+
+    match cert.subject_alt_names() {
+        Some(names) => names.iter().any(|name| match ip {
+            Some(IpAddr::V4(ip)) => name.ipaddress() == Some(&ip.octets()[..]),
+            Some(IpAddr::V6(ip)) => name.ipaddress() == Some(&ip.octets()[..]),
+            None => name
+                .dnsname()
+                .is_some_and(|dns| name_matches(dns, server_name)),
+        }),
+        None => cert
+            .subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .any(|entry| {
+                name_matches(
+                    &String::from_utf8_lossy(entry.data().as_slice()),
+                    server_name,
+                )
+            }),
+    }
+}
+
+fn subject_alt_names(cert: &X509Ref) -> Vec<String> {
+    cert.subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| {
+                    name.dnsname().map(ToString::to_string).or_else(|| {
+                        name.ipaddress().and_then(|ip| match ip.len() {
+                            4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?).to_string()),
+                            16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?).to_string()),
+                            _ => None,
+                        })
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Certificate and session details gathered after a handshake
+pub struct TlsInfo {
+    pub protocol: String,
+    pub days_until_expiry: Option<i64>,
+    pub not_after: String,
+    pub issuer: String,
+    pub subject: String,
+    pub subject_alt_names: Vec<String>,
+    pub hostname_match: bool,
+    /// Chain verification error, none if the chain is valid
+    pub chain_error: Option<String>,
+}
+
+/// Context of a tls [Job](crate::job::Job)
+#[derive(Debug, Clone)]
+pub struct TlsContext {
+    host: Host,
+    port: u16,
+    sni: Option<String>,
+    thresholds: TlsExpiryThresholds,
+}
+
+impl TlsContext {
+    pub fn new(
+        host: Host,
+        port: u16,
+        sni: Option<String>,
+        thresholds: TlsExpiryThresholds,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            sni,
+            thresholds,
+        }
+    }
+
+    /// Name expected in the certificate : sni, host domain or host ip
+    pub fn server_name(&self) -> String {
+        match (&self.sni, &self.host) {
+            (Some(sni), _) => sni.clone(),
+            (None, Host::Domain(domain)) => domain.host().to_string(),
+            (None, Host::IpAddr(ip)) => ip.to_string(),
+        }
+    }
+
+    pub fn target(&self) -> String {
+        format!("{}:{}", self.server_name(), self.port)
+    }
+
+    async fn handshake(&self) -> Result<TlsInfo, String> {
+        let server_name = self.server_name();
+        let ip = resolve_host(&self.host)
+            .await
+            .map_err(|e| format!("dns failure : {e}"))?;
+
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+        // the handshake must succeed with invalid certificates to report on them
+        builder.set_verify(SslVerifyMode::NONE);
+        let use_sni = self.sni.is_some() || matches!(self.host, Host::Domain(_));
+        let ssl = builder
+            .build()
+            .configure()
+            .map_err(|e| e.to_string())?
+            .use_server_name_indication(use_sni)
+            .verify_hostname(false)
+            .into_ssl(&server_name)
+            .map_err(|e| e.to_string())?;
+
+        let tcp = TcpStream::connect(SocketAddr::new(ip, self.port))
+            .await
+            .map_err(|e| format!("connection failure : {e}"))?;
+        let mut stream = SslStream::new(ssl, tcp).map_err(|e| e.to_string())?;
+        Pin::new(&mut stream)
+            .connect()
+            .await
+            .map_err(|e| format!("handshake failure : {e}"))?;
+
+        let ssl = stream.ssl();
+        let cert = ssl
+            .peer_certificate()
+            .ok_or_else(|| "no peer certificate".to_string())?;
+        let days_until_expiry = Asn1Time::days_from_now(0)
+            .and_then(|now| now.diff(cert.not_after()))
+            .map(|diff| diff.days as i64)
+            .ok();
+        let chain_error = match ssl.verify_result() {
+            X509VerifyResult::OK => None,
+            e => Some(e.error_string().to_string()),
+        };
+
+        Ok(TlsInfo {
+            protocol: ssl.version_str().to_string(),
+            days_until_expiry,
+            not_after: cert.not_after().to_string(),
+            issuer: name_to_string(cert.issuer_name()),
+            subject: name_to_string(cert.subject_name()),
+            subject_alt_names: subject_alt_names(&cert),
+            hostname_match: certificate_matches(&cert, &server_name),
+            chain_error,
+        })
+    }
+
+    /// Connect and handshake, giving up after `timeout`
+    pub async fn run(&self, timeout: Duration) -> TlsResult {
+        let datetime = Local::now().fixed_offset();
+        let before = Instant::now();
+
+        let mut timed_out = false;
+        let outcome = match tokio::time::timeout(timeout, self.handshake()).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                timed_out = true;
+                Err(format!("no handshake after {}ms", timeout.as_millis()))
+            }
+        };
+
+        TlsResult {
+            datetime,
+            elapsed: before.elapsed(),
+            thresholds: self.thresholds,
+            outcome,
+            timed_out,
+        }
+    }
+}
+
+impl From<TlsCheck> for TlsContext {
+    fn from(value: TlsCheck) -> Self {
+        Self::new(value.host, value.port, value.sni, value.thresholds)
+    }
+}
+
+/// Result of a tls handshake ready to be send to warp10
+pub struct TlsResult {
+    pub datetime: DateTime<FixedOffset>,
+    pub elapsed: Duration,
+    pub thresholds: TlsExpiryThresholds,
+    pub outcome: Result<TlsInfo, String>,
+    /// Whether the handshake failed because of the timeout
+    pub timed_out: bool,
+}
+
+impl Into<CheckResult<TlsFields>> for TlsResult {
+    fn into(self) -> CheckResult<TlsFields> {
+        let fields = match self.outcome {
+            Ok(info) => TlsFields {
+                protocol: Some(info.protocol),
+                days_until_expiry: info.days_until_expiry,
+                not_after: Some(info.not_after),
+                issuer: Some(info.issuer),
+                subject: Some(info.subject),
+                subject_alt_names: info.subject_alt_names,
+                hostname_match: Some(info.hostname_match),
+                chain_valid: Some(info.chain_error.is_none()),
+                chain_error: info.chain_error,
+                thresholds: self.thresholds,
+                error: None,
+            },
+            Err(e) => TlsFields::failed(self.thresholds, e),
+        };
+
+        let outcome = match (&fields.error, self.timed_out) {
+            (Some(_), true) => CheckOutcome::Timeout,
+            (Some(_), false) => CheckOutcome::Down,
+            (None, _)
+                if fields.chain_valid == Some(false) || fields.hostname_match == Some(false) =>
+            {
+                CheckOutcome::Down
+            }
+            (None, _) if fields.expiry_status() == TlsExpiryStatus::Expired => CheckOutcome::Down,
+            (None, _) => CheckOutcome::Up,
+        };
+
+        CheckResult {
+            timestamp: self.datetime,
+            latency: Some(self.elapsed),
+            outcome,
+            fields,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::{Ssl, SslAcceptor, SslVersion};
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    /// Self-signed certificate for `names`, valid from yesterday for `days`
+    fn self_signed(names: &[&str], days: i64) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, names[0]).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::from_unix(now - DAY).unwrap())
+            .unwrap();
+        // an hour of margin, so that the days until expiry don't round down
+        builder
+            .set_not_after(&Asn1Time::from_unix(now + days * DAY + 3600).unwrap())
+            .unwrap();
+
+        let mut san = SubjectAlternativeName::new();
+        for name in names {
+            san.dns(name);
+        }
+        let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (builder.build(), key)
+    }
+
+    /// Tls server on 127.0.0.1 presenting `cert`, returns its port
+    async fn serve(cert: X509, key: PKey<Private>, max_version: Option<SslVersion>) -> u16 {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_max_proto_version(max_version).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let ssl = Ssl::new(acceptor.context()).unwrap();
+                let mut stream = SslStream::new(ssl, tcp).unwrap();
+                tokio::spawn(async move {
+                    let _ = Pin::new(&mut stream).accept().await;
+                });
+            }
+        });
+
+        port
+    }
+
+    async fn probe(port: u16, sni: &str) -> CheckResult<TlsFields> {
+        let ctx = TlsContext::new(
+            Host::IpAddr(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            port,
+            Some(sni.to_string()),
+            TlsExpiryThresholds::default(),
+        );
+        ctx.run(Duration::from_secs(5)).await.into()
+    }
+
+    #[test]
+    fn wildcards_match_a_single_label() {
+        assert!(name_matches("*.example.com", "www.example.com"));
+        assert!(name_matches("Example.com.", "example.COM"));
+        assert!(!name_matches("*.example.com", "example.com"));
+        assert!(!name_matches("*.example.com", "a.b.example.com"));
+    }
+
+    #[tokio::test]
+    async fn self_signed_certificate() {
+        let (cert, key) = self_signed(&["localhost", "isok.test"], 20);
+        let port = serve(cert, key, None).await;
+
+        let result = probe(port, "isok.test").await;
+        let fields = result.fields;
+        assert_eq!(fields.error, None);
+        assert_eq!(fields.protocol.as_deref(), Some("TLSv1.3"));
+        assert_eq!(fields.days_until_expiry, Some(20));
+        assert_eq!(fields.expiry_status(), TlsExpiryStatus::Warning);
+        assert_eq!(fields.subject.as_deref(), Some("CN=localhost"));
+        assert_eq!(fields.issuer.as_deref(), Some("CN=localhost"));
+        assert_eq!(fields.subject_alt_names, vec!["localhost", "isok.test"]);
+        assert_eq!(fields.hostname_match, Some(true));
+        assert_eq!(fields.chain_valid, Some(false));
+        assert!(fields.chain_error.is_some());
+        // self-signed certificates never verify against the agent trust store
+        assert_eq!(result.outcome, CheckOutcome::Down);
+    }
+
+    #[tokio::test]
+    async fn hostname_mismatch() {
+        let (cert, key) = self_signed(&["localhost"], 90);
+        let port = serve(cert, key, None).await;
+
+        let fields = probe(port, "other.example.com").await.fields;
+        assert_eq!(fields.error, None);
+        assert_eq!(fields.hostname_match, Some(false));
+        assert_eq!(fields.expiry_status(), TlsExpiryStatus::Ok);
+    }
+
+    #[tokio::test]
+    async fn expired_certificate() {
+        let (cert, key) = self_signed(&["localhost"], -3);
+        let port = serve(cert, key, None).await;
+
+        let result = probe(port, "localhost").await;
+        assert!(result.fields.days_until_expiry.is_some_and(|days| days < 0));
+        assert_eq!(result.fields.expiry_status(), TlsExpiryStatus::Expired);
+        assert_eq!(result.outcome, CheckOutcome::Down);
+    }
+
+    #[tokio::test]
+    async fn negotiated_protocol() {
+        let (cert, key) = self_signed(&["localhost"], 90);
+        let port = serve(cert, key, Some(SslVersion::TLS1_2)).await;
+
+        let fields = probe(port, "localhost").await.fields;
+        assert_eq!(fields.protocol.as_deref(), Some("TLSv1.2"));
+    }
+
+    #[tokio::test]
+    async fn connection_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let result = probe(port, "localhost").await;
+        assert!(result.fields.error.is_some());
+        assert_eq!(result.outcome, CheckOutcome::Down);
+    }
+}
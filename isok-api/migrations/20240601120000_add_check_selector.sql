@@ -0,0 +1 @@
+alter table checks add column selector jsonb not null default '{}'::jsonb;
@@ -0,0 +1,11 @@
+create table agents (
+  agent_id character varying primary key not null,
+  regions jsonb not null,
+  pool character varying,
+  version character varying not null,
+  started_at timestamp with time zone not null,
+  last_seen timestamp with time zone not null,
+  jobs jsonb not null,
+  scheduler_lag bigint not null,
+  queue_depth bigint not null
+);
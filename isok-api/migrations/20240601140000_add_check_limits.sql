@@ -0,0 +1 @@
+alter table checks add column limits jsonb not null default '{}'::jsonb;
@@ -0,0 +1 @@
+alter table checks add column retry jsonb not null default '{}'::jsonb;
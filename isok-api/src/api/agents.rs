@@ -0,0 +1,21 @@
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::Utc;
+
+use isok_data::agent::AgentOutput;
+
+use super::ServerState;
+
+pub async fn list_agents(
+    State(state): State<ServerState>,
+) -> Result<Json<Vec<AgentOutput>>, impl IntoResponse> {
+    let now = Utc::now();
+    state.db.get_agents().await.map(|agents| {
+        agents
+            .into_iter()
+            .map(|agent| agent.into_output(now, state.agent_stale_after))
+            .collect::<Vec<AgentOutput>>()
+            .into()
+    })
+}
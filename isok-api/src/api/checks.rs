@@ -59,10 +59,13 @@ pub async fn change_check_kind(
     Path((organization_id, id)): Path<(Uuid, Uuid)>,
     Json(check_kind): Json<CheckKind>,
 ) -> Result<(), impl IntoResponse> {
-    state
+    let mut pulsar_client = state.pulsar_client.lock().await;
+    let check = state
         .db
         .change_check_kind(id, check_kind, organization_id)
-        .await
+        .await?;
+    pulsar_client.update_check(check).await;
+    Ok::<(), RequestError>(())
 }
 
 pub async fn change_check_interval(
@@ -70,10 +73,13 @@ pub async fn change_check_interval(
     Path((organization_id, id)): Path<(Uuid, Uuid)>,
     Json(interval): Json<Duration>,
 ) -> Result<(), impl IntoResponse> {
-    state
+    let mut pulsar_client = state.pulsar_client.lock().await;
+    let check = state
         .db
         .change_check_interval(id, interval, organization_id)
-        .await
+        .await?;
+    pulsar_client.update_check(check).await;
+    Ok::<(), RequestError>(())
 }
 
 pub async fn change_check_max_latency(
@@ -81,10 +87,13 @@ pub async fn change_check_max_latency(
     Path((organization_id, id)): Path<(Uuid, Uuid)>,
     Json(max_latency): Json<Duration>,
 ) -> Result<(), impl IntoResponse> {
-    state
+    let mut pulsar_client = state.pulsar_client.lock().await;
+    let check = state
         .db
         .change_check_max_latency(id, max_latency, organization_id)
-        .await
+        .await?;
+    pulsar_client.update_check(check).await;
+    Ok::<(), RequestError>(())
 }
 
 pub async fn delete_check(
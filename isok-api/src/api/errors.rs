@@ -27,7 +27,7 @@ impl IntoResponse for RequestError {
             RequestError::Sqlx(e) => {
                 match e.as_database_error() {
                     Some(e) => error!(target: "DB", "{e}"),
-                    None => error!(target: "DB", "unknown database error"),
+                    None => error!(target: "DB", "{e}"),
                 };
                 internal_error
             }
@@ -2,9 +2,12 @@ use std::sync::Arc;
 
 use tokio::sync::Mutex;
 
+use isok_data::pulsar_connector::ConnectionStates;
+
 use crate::db::DbHandler;
 use crate::pulsar::PulsarClient;
 
+pub mod agents;
 pub mod auth;
 pub mod checks;
 pub mod errors;
@@ -14,4 +17,8 @@ pub mod routes;
 pub struct ServerState {
     pub db: Arc<DbHandler>,
     pub pulsar_client: Arc<Mutex<PulsarClient>>,
+    /// State of the pulsar connections, reported by `/healthz`
+    pub pulsar_connections: ConnectionStates,
+    /// Delay without heartbeat after which an agent is reported stale
+    pub agent_stale_after: chrono::Duration,
 }
@@ -1,8 +1,10 @@
 use axum::body::Body;
-use axum::http::Response;
+use axum::extract::State;
+use axum::http::{Response, StatusCode};
 pub use axum::routing::{delete, get, post, put};
 pub use axum::Router;
 
+use super::agents::list_agents;
 use super::checks::{
     change_check_interval, change_check_kind, change_check_max_latency, create_check, delete_check,
     get_check, list_checks,
@@ -13,6 +15,8 @@ pub fn app(server_state: ServerState) -> Router<()> {
     Router::new()
         .route("/ping", get(ping))
         .route("/teapot", get(teapot))
+        .route("/healthz", get(healthz).with_state(server_state.clone()))
+        .nest("/agents", agents_router(server_state.clone()))
         .nest("/checks/:organization_id", checks_router(server_state))
 }
 
@@ -20,6 +24,20 @@ pub async fn ping() -> &'static str {
     "PONG !"
 }
 
+/// Healthy while every pulsar connection is up
+pub async fn healthz(State(state): State<ServerState>) -> (StatusCode, String) {
+    let disconnected = state.pulsar_connections.disconnected();
+
+    if disconnected.is_empty() {
+        (StatusCode::OK, "OK".to_string())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("pulsar disconnected : {}", disconnected.join(", ")),
+        )
+    }
+}
+
 pub async fn teapot() -> Response<Body> {
     Response::builder()
         .status(418)
@@ -27,6 +45,12 @@ pub async fn teapot() -> Response<Body> {
         .unwrap()
 }
 
+pub fn agents_router(server_state: ServerState) -> Router<()> {
+    Router::new()
+        .route("/", get(list_agents))
+        .with_state(server_state)
+}
+
 pub fn checks_router(server_state: ServerState) -> Router<()> {
     Router::new()
         .route("/", get(list_checks))
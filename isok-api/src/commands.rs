@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use log::{error, info};
+use isok_data::{check::Check, pulsar_commands::Command};
+use isok_data::bus::{self, BusError, MessageBus, Topic};
+
+/// Sends the checks commands to the agents, through the message bus
+pub struct PulsarClient {
+    pub bus: Arc<dyn MessageBus>,
+}
+
+impl PulsarClient {
+    pub fn new(bus: Arc<dyn MessageBus>) -> Self {
+        PulsarClient { bus }
+    }
+
+    /// Send a command, keyed by check id for the topic compaction
+    async fn send_command(&mut self, command: Command) -> Result<(), BusError> {
+        let key = Some(command.id().to_string());
+
+        bus::publish_json(self.bus.as_ref(), Topic::Commands, key, &command).await?.await
+    }
+
+    pub async fn add_check(&mut self, check: Check) {
+        match self.send_command(Command::new_add_command(check.clone())).await {
+            Ok(_) => info!("Check {} sent to agent !", check.check_id),
+            Err(e) => error!("Check {} could not be sent to agent : {e}", check.check_id),
+        };
+    }
+
+    pub async fn update_check(&mut self, check: Check) {
+        match self.send_command(Command::new_update_command(check.clone())).await {
+            Ok(_) => info!("Check {} update sent to agent !", check.check_id),
+            Err(e) => error!("Check {} update could not be sent to agent : {e}", check.check_id),
+        };
+    }
+
+    pub async fn remove_check(&mut self, check: Check) {
+        match self.send_command(Command::new_remove_command(check.clone())).await {
+            Ok(_) => info!("Check {} deleted from agent !", check.check_id),
+            Err(e) => error!("Check {} could not be deleted from agent : {e}", check.check_id),
+        }
+    }
+}
@@ -7,7 +7,9 @@ use sqlx::types::chrono::Utc;
 use sqlx::PgPool;
 use uuid::Uuid;
 
+use isok_data::agent::Agent;
 use isok_data::check::{Check, CheckInput, CheckKind};
+use isok_data::pulsar_heartbeats::Heartbeat;
 
 use crate::api::errors::RequestError;
 
@@ -59,7 +61,7 @@ impl DbHandler {
     pub async fn get_checks(&self) -> Result<Vec<Check>, RequestError> {
         sqlx::query!(
             r#"
-                SELECT check_id, owner_id, kind, max_latency, interval, region, created_at, updated_at, deleted_at
+                SELECT check_id, owner_id, kind, max_latency, interval, region, selector, limits, retry, created_at, updated_at, deleted_at
                 FROM checks
                 WHERE deleted_at IS NULL
             "#
@@ -70,6 +72,9 @@ impl DbHandler {
             max_latency: pg_interval_to_duration(row.max_latency),
             interval: pg_interval_to_duration(row.interval),
             region: row.region,
+            selector: serde_json::from_value(row.selector).unwrap(),
+            limits: serde_json::from_value(row.limits).unwrap(),
+            retry: serde_json::from_value(row.retry).unwrap(),
             created_at: row.created_at,
             updated_at: row.updated_at,
             deleted_at: row.deleted_at,
@@ -79,7 +84,7 @@ impl DbHandler {
     pub async fn get_check(&self, check_id: Uuid) -> Result<Check, RequestError> {
         sqlx::query!(
             r#"
-                SELECT check_id, owner_id, kind, max_latency, interval, region, created_at, updated_at, deleted_at
+                SELECT check_id, owner_id, kind, max_latency, interval, region, selector, limits, retry, created_at, updated_at, deleted_at
                 FROM checks
                 WHERE deleted_at IS NULL
                 AND check_id = $1
@@ -92,6 +97,9 @@ impl DbHandler {
             max_latency: pg_interval_to_duration(row.max_latency),
             interval: pg_interval_to_duration(row.interval),
             region: row.region,
+            selector: serde_json::from_value(row.selector).unwrap(),
+            limits: serde_json::from_value(row.limits).unwrap(),
+            retry: serde_json::from_value(row.retry).unwrap(),
             created_at: row.created_at,
             updated_at: row.updated_at,
             deleted_at: row.deleted_at,
@@ -104,15 +112,18 @@ impl DbHandler {
 
         sqlx::query!(
             r#"
-                INSERT INTO checks(owner_id, kind, max_latency, interval, region, created_at, updated_at)
-                VALUES ($1, $2, $3, $4, $5, $6, $7)
-                RETURNING check_id,owner_id, kind, max_latency, interval, region, created_at, updated_at, deleted_at
+                INSERT INTO checks(owner_id, kind, max_latency, interval, region, selector, limits, retry, created_at, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                RETURNING check_id,owner_id, kind, max_latency, interval, region, selector, limits, retry, created_at, updated_at, deleted_at
             "#,
             check.owner_id,
             serde_json::to_value(check.kind).unwrap(),
             duration_to_pg_interval(check.max_latency),
             duration_to_pg_interval(Duration::from_secs(check.interval as u64)),
             check.region,
+            serde_json::to_value(check.selector).unwrap(),
+            serde_json::to_value(check.limits).unwrap(),
+            serde_json::to_value(check.retry).unwrap(),
             now,
             now
         ).map(|row| Check {
@@ -122,6 +133,9 @@ impl DbHandler {
             max_latency: pg_interval_to_duration(row.max_latency),
             interval: pg_interval_to_duration(row.interval),
             region: row.region,
+            selector: serde_json::from_value(row.selector).unwrap(),
+            limits: serde_json::from_value(row.limits).unwrap(),
+            retry: serde_json::from_value(row.retry).unwrap(),
             created_at: row.created_at,
             updated_at: row.updated_at,
             deleted_at: row.deleted_at,
@@ -134,19 +148,33 @@ impl DbHandler {
         check_id: Uuid,
         check_kind: CheckKind,
         organization_id: Uuid,
-    ) -> Result<(), RequestError> {
+    ) -> Result<Check, RequestError> {
         sqlx::query!(
             r#"
 UPDATE checks SET kind = $1, updated_at = $2 WHERE check_id = $3 AND deleted_at IS NULL AND owner_id = $4
+RETURNING check_id,owner_id, kind, max_latency, interval, region, selector, limits, retry, created_at, updated_at, deleted_at
         "#,
             serde_json::to_value(check_kind).unwrap(),
             Utc::now(),
             check_id,
             organization_id
         )
-        .execute(&self.pool)
+        .map(|row| Check {
+            check_id: row.check_id,
+            owner_id: row.owner_id,
+            kind: serde_json::from_value(row.kind).unwrap(),
+            max_latency: pg_interval_to_duration(row.max_latency),
+            interval: pg_interval_to_duration(row.interval),
+            region: row.region,
+            selector: serde_json::from_value(row.selector).unwrap(),
+            limits: serde_json::from_value(row.limits).unwrap(),
+            retry: serde_json::from_value(row.retry).unwrap(),
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            deleted_at: row.deleted_at,
+        })
+        .fetch_one(&self.pool)
         .await
-        .map(|_| ())
         .map_err(|e| map_row_not_found(e, "check", check_id))
     }
 
@@ -155,19 +183,33 @@ UPDATE checks SET kind = $1, updated_at = $2 WHERE check_id = $3 AND deleted_at
         check_id: Uuid,
         interval: Duration,
         organization_id: Uuid,
-    ) -> Result<(), RequestError> {
+    ) -> Result<Check, RequestError> {
         sqlx::query!(
             r#"
 UPDATE checks SET interval = $1, updated_at = $2 WHERE check_id = $3 AND deleted_at IS NULL AND owner_id = $4
+RETURNING check_id,owner_id, kind, max_latency, interval, region, selector, limits, retry, created_at, updated_at, deleted_at
         "#,
             duration_to_pg_interval(interval),
             Utc::now(),
             check_id,
             organization_id
         )
-        .execute(&self.pool)
+        .map(|row| Check {
+            check_id: row.check_id,
+            owner_id: row.owner_id,
+            kind: serde_json::from_value(row.kind).unwrap(),
+            max_latency: pg_interval_to_duration(row.max_latency),
+            interval: pg_interval_to_duration(row.interval),
+            region: row.region,
+            selector: serde_json::from_value(row.selector).unwrap(),
+            limits: serde_json::from_value(row.limits).unwrap(),
+            retry: serde_json::from_value(row.retry).unwrap(),
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            deleted_at: row.deleted_at,
+        })
+        .fetch_one(&self.pool)
         .await
-        .map(|_| ())
         .map_err(|e| map_row_not_found(e, "check", check_id))
     }
 
@@ -176,19 +218,33 @@ UPDATE checks SET interval = $1, updated_at = $2 WHERE check_id = $3 AND deleted
         check_id: Uuid,
         max_latency: Duration,
         organization_id: Uuid,
-    ) -> Result<(), RequestError> {
+    ) -> Result<Check, RequestError> {
         sqlx::query!(
             r#"
 UPDATE checks SET max_latency = $1, updated_at = $2 WHERE check_id = $3 AND deleted_at IS NULL AND owner_id = $4
+RETURNING check_id,owner_id, kind, max_latency, interval, region, selector, limits, retry, created_at, updated_at, deleted_at
         "#,
             duration_to_pg_interval(max_latency),
             Utc::now(),
             check_id,
             organization_id
         )
-        .execute(&self.pool)
+        .map(|row| Check {
+            check_id: row.check_id,
+            owner_id: row.owner_id,
+            kind: serde_json::from_value(row.kind).unwrap(),
+            max_latency: pg_interval_to_duration(row.max_latency),
+            interval: pg_interval_to_duration(row.interval),
+            region: row.region,
+            selector: serde_json::from_value(row.selector).unwrap(),
+            limits: serde_json::from_value(row.limits).unwrap(),
+            retry: serde_json::from_value(row.retry).unwrap(),
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            deleted_at: row.deleted_at,
+        })
+        .fetch_one(&self.pool)
         .await
-        .map(|_| ())
         .map_err(|e| map_row_not_found(e, "check", check_id))
     }
 
@@ -200,7 +256,7 @@ UPDATE checks SET max_latency = $1, updated_at = $2 WHERE check_id = $3 AND dele
         sqlx::query!(
             r#"
             UPDATE checks SET deleted_at = $1 WHERE check_id = $2 AND deleted_at IS NULL AND owner_id = $3
-            RETURNING check_id,owner_id, kind, max_latency, interval, region, created_at, updated_at, deleted_at
+            RETURNING check_id,owner_id, kind, max_latency, interval, region, selector, limits, retry, created_at, updated_at, deleted_at
         "#,
             Utc::now(),
             check_id,
@@ -213,6 +269,9 @@ UPDATE checks SET max_latency = $1, updated_at = $2 WHERE check_id = $3 AND dele
             max_latency: pg_interval_to_duration(row.max_latency),
             interval: pg_interval_to_duration(row.interval),
             region: row.region,
+            selector: serde_json::from_value(row.selector).unwrap(),
+            limits: serde_json::from_value(row.limits).unwrap(),
+            retry: serde_json::from_value(row.retry).unwrap(),
             created_at: row.created_at,
             updated_at: row.updated_at,
             deleted_at: row.deleted_at,
@@ -221,4 +280,63 @@ UPDATE checks SET max_latency = $1, updated_at = $2 WHERE check_id = $3 AND dele
         .await
         .map_err(|e| map_row_not_found(e, "check", check_id))
     }
+
+    /// Store the latest heartbeat of an agent, older ones are ignored
+    pub async fn upsert_agent(&self, heartbeat: Heartbeat) -> Result<(), RequestError> {
+        let agent = Agent::from(heartbeat);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO agents(agent_id, regions, pool, version, started_at, last_seen, jobs, scheduler_lag, queue_depth)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (agent_id) DO UPDATE SET
+                regions = EXCLUDED.regions,
+                pool = EXCLUDED.pool,
+                version = EXCLUDED.version,
+                started_at = EXCLUDED.started_at,
+                last_seen = EXCLUDED.last_seen,
+                jobs = EXCLUDED.jobs,
+                scheduler_lag = EXCLUDED.scheduler_lag,
+                queue_depth = EXCLUDED.queue_depth
+            WHERE agents.last_seen < EXCLUDED.last_seen
+        "#,
+            agent.agent_id,
+            serde_json::to_value(agent.regions).unwrap(),
+            agent.pool,
+            agent.version,
+            agent.started_at,
+            agent.last_seen,
+            serde_json::to_value(agent.jobs).unwrap(),
+            agent.scheduler_lag as i64,
+            agent.queue_depth as i64
+        )
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(RequestError::Sqlx)
+    }
+
+    pub async fn get_agents(&self) -> Result<Vec<Agent>, RequestError> {
+        sqlx::query!(
+            r#"
+            SELECT agent_id, regions, pool, version, started_at, last_seen, jobs, scheduler_lag, queue_depth
+            FROM agents
+            ORDER BY agent_id
+        "#
+        )
+        .try_map(|row| Ok(Agent {
+            agent_id: row.agent_id,
+            regions: serde_json::from_value(row.regions).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            pool: row.pool,
+            version: row.version,
+            started_at: row.started_at,
+            last_seen: row.last_seen,
+            jobs: serde_json::from_value(row.jobs).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            scheduler_lag: row.scheduler_lag as u64,
+            queue_depth: row.queue_depth as u64,
+        }))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(RequestError::Sqlx)
+    }
 }
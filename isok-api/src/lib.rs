@@ -0,0 +1,3 @@
+//! Parts of the api the tests of the other components run against
+
+pub mod commands;
@@ -6,6 +6,9 @@ use log::{error, info};
 use tokio::sync::Mutex;
 
 use db::DbHandler;
+use isok_data::bus::MessageBus;
+use isok_data::pulsar_bus::PulsarBus;
+use isok_data::pulsar_connector::PulsarConnector;
 
 use crate::api::ServerState;
 use crate::pulsar::{PulsarClient, PulsarConnectionData};
@@ -51,6 +54,7 @@ async fn main() {
     let pulsar_tenant = env_get("PULSAR_TENANT");
     let pulsar_namespace = env_get("PULSAR_NAMESPACE");
     let pulsar_topic = env_get("PULSAR_TOPIC");
+    let agent_stale_after = chrono::Duration::seconds(env_get_num("AGENT_STALE_AFTER", 30));
 
     init_logger();
 
@@ -78,20 +82,28 @@ async fn main() {
         "Connecting to pulsar topic {}...",
         pulsar::pulsar_link(&pulsar_connection_data)
     );
-    let pulsar_client = match PulsarClient::new(pulsar_connection_data).await {
-        Some(pc) => {
-            info!("Connected to pulsar topic !");
-            pc
-        }
-        None => {
-            error!("Failed to connect to pulsar topic");
-            std::process::exit(1);
-        }
-    };
+    let connector = PulsarConnector::new(
+        &pulsar_connection_data.pulsar_address,
+        &pulsar_connection_data.pulsar_token,
+    );
+    let pulsar_connections = connector.states();
+    let bus = PulsarBus::new(
+        connector.clone(),
+        &pulsar_connection_data.pulsar_tenant,
+        &pulsar_connection_data.pulsar_namespace,
+    )
+    .with_commands_topic(&pulsar_connection_data.pulsar_topic);
+    let bus: Arc<dyn MessageBus> = Arc::new(bus);
+    let pulsar_client = PulsarClient::new(Arc::clone(&bus));
+
+    let db = Arc::new(db);
+    tokio::task::spawn(pulsar::consume_heartbeats(Arc::clone(&db), bus));
 
     let app = api::routes::app(ServerState {
-        db: Arc::new(db),
+        db,
         pulsar_client: Arc::new(Mutex::new(pulsar_client)),
+        pulsar_connections,
+        agent_stale_after,
     });
 
     let listener = tokio::net::TcpListener::bind(format!("{address}:{port}"))
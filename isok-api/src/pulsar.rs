@@ -1,9 +1,13 @@
-use log::{error, info};
-use isok_data::{check::Check, pulsar_commands::Command};
-use pulsar::{
-    compression::Compression, executor::TokioExecutor, proto, Authentication, Producer,
-    ProducerOptions, Pulsar,
-};
+use std::sync::Arc;
+
+use log::error;
+use isok_data::pulsar_heartbeats::Heartbeat;
+use isok_data::bus::{MessageBus, Topic};
+
+pub use isok_api::commands::PulsarClient;
+
+use crate::api::errors::RequestError;
+use crate::db::DbHandler;
 
 /// Helper to make topic link from tenant, namespace and topic
 pub fn pulsar_link(connection_data: &PulsarConnectionData) -> String {
@@ -25,66 +29,33 @@ pub struct PulsarConnectionData {
     pub pulsar_topic: String,
 }
 
-pub struct PulsarClient {
-    pub producer: Producer<TokioExecutor>,
-}
-
-impl PulsarClient {
-    pub async fn new(connection_data: PulsarConnectionData) -> Option<Self> {
-        let client = Pulsar::builder(&connection_data.pulsar_address, TokioExecutor)
-            .with_auth(Authentication {
-                name: "token".to_owned(),
-                data: Vec::from(connection_data.pulsar_token.as_bytes()),
-            })
-            .build()
-            .await
-            .ok()?;
-
-        let producer = client
-            .producer()
-            .with_topic(pulsar_link(&connection_data))
-            .with_options(ProducerOptions {
-                schema: Some(proto::Schema {
-                    r#type: proto::schema::Type::String as i32,
-                    ..Default::default()
-                }),
-                compression: Some(Compression::Zstd(pulsar::compression::CompressionZstd {
-                    ..Default::default()
-                })),
-                ..Default::default()
-            })
-            .build()
-            .await
-            .ok()?;
-
-        Some(PulsarClient { producer })
-    }
-
-    pub async fn add_check(&mut self, check: Check) {
-        let a = self
-            .producer
-            .send_non_blocking(Command::new_add_command(check.clone()))
-            .await;
-        match a {
-            Ok(a) => match a.await {
-                Ok(_) => info!("Check {} sent to agent !", check.check_id),
-                Err(_) => error!("Check {} could not be sent to agent.", check.check_id),
-            },
-            Err(_) => error!("Check {} could not be sent to agent.", check.check_id),
-        };
-    }
-
-    pub async fn remove_check(&mut self, check: Check) {
-        let a = self
-            .producer
-            .send_non_blocking(Command::new_remove_command(check.clone()))
-            .await;
-        match a {
-            Ok(_) => match a {
-                Ok(_) => info!("Check {} deleted from agent !", check.check_id),
-                Err(_) => error!("Check {} could not be deleted from agent.", check.check_id),
-            },
-            Err(_) => error!("Check {} could not be deleted from agent.", check.check_id),
+/// Store agents heartbeats into the agents table, subscribing again when disconnected
+///
+/// The subscription is shared between api instances, each heartbeat being stored once.
+pub async fn consume_heartbeats(db: Arc<DbHandler>, bus: Arc<dyn MessageBus>) {
+    loop {
+        let mut heartbeats = bus.subscribe(Topic::Heartbeats, "isok-api").await;
+
+        while let Some(payload) = heartbeats.next().await {
+            match serde_json::from_slice::<Heartbeat>(&payload) {
+                Ok(heartbeat) => {
+                    let agent_id = heartbeat.agent_id.clone();
+                    match db.upsert_agent(heartbeat).await {
+                        Ok(()) => heartbeats.ack().await,
+                        // negatively acked, the heartbeat is delivered again
+                        Err(e) => {
+                            if let RequestError::Sqlx(e) = e {
+                                error!("Could not store heartbeat of agent {agent_id} : {e}");
+                            }
+                            heartbeats.nack().await;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Can't deserialize heartbeat : {e}");
+                    heartbeats.ack().await;
+                }
+            }
         }
     }
 }
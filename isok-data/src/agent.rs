@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::check::{Deserialize, Serialize};
+use crate::pulsar_heartbeats::Heartbeat;
+
+/// Last known state of an agent, from its latest heartbeat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Agent {
+    pub agent_id: String,
+    pub regions: Vec<String>,
+    pub pool: Option<String>,
+    pub version: String,
+    pub started_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Number of jobs by check interval in seconds
+    pub jobs: BTreeMap<u64, u64>,
+    /// Highest tick lag of the agent schedulers, in milliseconds
+    pub scheduler_lag: u64,
+    pub queue_depth: u64,
+}
+
+impl From<Heartbeat> for Agent {
+    fn from(value: Heartbeat) -> Self {
+        Self {
+            agent_id: value.agent_id,
+            regions: value.regions,
+            pool: value.pool,
+            version: value.version,
+            started_at: value.started_at,
+            last_seen: value.timestamp,
+            jobs: value.jobs,
+            scheduler_lag: value.scheduler_lag,
+            queue_depth: value.queue_depth,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentOutput {
+    #[serde(flatten)]
+    pub agent: Agent,
+    /// No heartbeat received for longer than the stale delay
+    pub stale: bool,
+}
+
+impl Agent {
+    pub fn into_output(self, now: DateTime<Utc>, stale_after: chrono::Duration) -> AgentOutput {
+        AgentOutput {
+            stale: now - self.last_seen > stale_after,
+            agent: self,
+        }
+    }
+}
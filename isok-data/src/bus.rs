@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::pulsar_messages::CheckType;
+
+/// Topics carried by the message bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    /// Checks commands sent by the api to the agents, compacted by check id
+    Commands,
+    /// Results of a check type, sent by the agents
+    Results(CheckType),
+    /// Aggregated http results, sent by the offloader
+    AggregatedHttp,
+    /// Liveness of the agents, keyed by agent id
+    Heartbeats,
+}
+
+impl Display for Topic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Topic::Commands => write!(f, "commands"),
+            Topic::Results(check_type) => write!(f, "{check_type} results"),
+            Topic::AggregatedHttp => write!(f, "aggregated http results"),
+            Topic::Heartbeats => write!(f, "heartbeats"),
+        }
+    }
+}
+
+/// Implementation of the message bus a component runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusKind {
+    /// Pulsar topics, shared by every component
+    Pulsar,
+    /// In-process channels, for single node deployments and tests, see [`MemoryBus`]
+    Memory,
+}
+
+impl FromStr for BusKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pulsar" => Ok(BusKind::Pulsar),
+            "memory" => Ok(BusKind::Memory),
+            other => Err(format!("unknown message bus {other}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BusError {
+    Pulsar(pulsar::Error),
+    Json(serde_json::Error),
+    /// The topic has no connection, until the next attempt
+    Disconnected(Topic),
+    /// The topic is not configured on this bus
+    UnknownTopic(Topic),
+}
+
+impl Display for BusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusError::Pulsar(e) => write!(f, "pulsar error : {e}"),
+            BusError::Json(e) => write!(f, "json error : {e}"),
+            BusError::Disconnected(topic) => write!(f, "{topic} topic is disconnected"),
+            BusError::UnknownTopic(topic) => write!(f, "{topic} topic is not configured"),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl From<pulsar::Error> for BusError {
+    fn from(e: pulsar::Error) -> Self {
+        BusError::Pulsar(e)
+    }
+}
+
+impl From<serde_json::Error> for BusError {
+    fn from(e: serde_json::Error) -> Self {
+        BusError::Json(e)
+    }
+}
+
+/// Resolves once the bus stored the message it was returned for
+pub type Receipt = BoxFuture<'static, Result<(), BusError>>;
+
+/// Messaging between the api, the agents and the offloader
+///
+/// Commands subscriptions are exclusive and start with the latest command of
+/// every check. Other subscriptions are shared by name, each message going to
+/// one subscriber of every subscription, and only get the messages sent once
+/// the subscription exists.
+pub trait MessageBus: Send + Sync {
+    /// Send a message, `key` being the compaction key
+    fn publish(
+        &self,
+        topic: Topic,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'_, Result<Receipt, BusError>>;
+
+    /// Subscribe to a topic, retrying until subscribed
+    fn subscribe<'a>(
+        &'a self,
+        topic: Topic,
+        subscription: &'a str,
+    ) -> BoxFuture<'a, Box<dyn Subscriber>>;
+}
+
+/// Messages of a subscription, in order
+pub trait Subscriber: Send {
+    /// Payload of the next message, none once the subscription is lost
+    fn next(&mut self) -> BoxFuture<'_, Option<Vec<u8>>>;
+
+    /// Acknowledge the last message returned by [next](Subscriber::next)
+    fn ack(&mut self) -> BoxFuture<'_, ()>;
+
+    /// Give back the last message returned by [next](Subscriber::next), to be delivered again
+    fn nack(&mut self) -> BoxFuture<'_, ()>;
+}
+
+/// Send a message as json
+pub async fn publish_json<T: Serialize>(
+    bus: &dyn MessageBus,
+    topic: Topic,
+    key: Option<String>,
+    message: &T,
+) -> Result<Receipt, BusError> {
+    let payload = serde_json::to_vec(message)?;
+    bus.publish(topic, key, payload).await
+}
+
+type Queue = (
+    UnboundedSender<Vec<u8>>,
+    Arc<tokio::sync::Mutex<UnboundedReceiver<Vec<u8>>>>,
+);
+
+#[derive(Default)]
+struct MemoryTopics {
+    /// Latest command of every check, in publish order
+    commands: Vec<(Option<String>, Vec<u8>)>,
+    subscriptions: HashMap<(Topic, String), Queue>,
+}
+
+/// In-process bus, for tests and single node deployments
+///
+/// Messages are kept in memory, unbounded, and every publish is stored at once.
+#[derive(Clone, Default)]
+pub struct MemoryBus {
+    topics: Arc<Mutex<MemoryTopics>>,
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn publish_now(&self, topic: Topic, key: Option<String>, payload: Vec<u8>) {
+        let mut topics = self.topics.lock().unwrap_or_else(|e| e.into_inner());
+
+        if topic == Topic::Commands {
+            if key.is_some() {
+                topics.commands.retain(|(retained, _)| *retained != key);
+            }
+            topics.commands.push((key, payload.clone()));
+        }
+
+        for ((subscribed, _), (sender, _)) in &topics.subscriptions {
+            if *subscribed == topic {
+                _ = sender.send(payload.clone());
+            }
+        }
+    }
+
+    fn subscribe_now(&self, topic: Topic, subscription: &str) -> MemorySubscriber {
+        let mut topics = self.topics.lock().unwrap_or_else(|e| e.into_inner());
+        let name = (topic, subscription.to_string());
+
+        let receiver = if topic == Topic::Commands {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            for (_, payload) in &topics.commands {
+                _ = sender.send(payload.clone());
+            }
+            let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+            topics
+                .subscriptions
+                .insert(name, (sender, Arc::clone(&receiver)));
+            receiver
+        } else {
+            let (_, receiver) = topics.subscriptions.entry(name).or_insert_with(|| {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                (sender, Arc::new(tokio::sync::Mutex::new(receiver)))
+            });
+            Arc::clone(receiver)
+        };
+
+        MemorySubscriber {
+            receiver,
+            last: None,
+            nacked: None,
+        }
+    }
+}
+
+impl MessageBus for MemoryBus {
+    fn publish(
+        &self,
+        topic: Topic,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'_, Result<Receipt, BusError>> {
+        self.publish_now(topic, key, payload);
+        Box::pin(async { Ok(Box::pin(async { Ok(()) }) as Receipt) })
+    }
+
+    fn subscribe<'a>(
+        &'a self,
+        topic: Topic,
+        subscription: &'a str,
+    ) -> BoxFuture<'a, Box<dyn Subscriber>> {
+        let subscriber = self.subscribe_now(topic, subscription);
+        Box::pin(async { Box::new(subscriber) as Box<dyn Subscriber> })
+    }
+}
+
+struct MemorySubscriber {
+    receiver: Arc<tokio::sync::Mutex<UnboundedReceiver<Vec<u8>>>>,
+    last: Option<Vec<u8>>,
+    /// Message given back, delivered again before the next ones
+    nacked: Option<Vec<u8>>,
+}
+
+impl Subscriber for MemorySubscriber {
+    fn next(&mut self) -> BoxFuture<'_, Option<Vec<u8>>> {
+        Box::pin(async {
+            let payload = match self.nacked.take() {
+                Some(payload) => payload,
+                None => self.receiver.lock().await.recv().await?,
+            };
+            self.last = Some(payload.clone());
+            Some(payload)
+        })
+    }
+
+    fn ack(&mut self) -> BoxFuture<'_, ()> {
+        self.last = None;
+        Box::pin(async {})
+    }
+
+    fn nack(&mut self) -> BoxFuture<'_, ()> {
+        self.nacked = self.last.take();
+        Box::pin(async {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn next(subscriber: &mut Box<dyn Subscriber>) -> String {
+        let payload = subscriber.next().await.unwrap();
+        String::from_utf8(payload).unwrap()
+    }
+
+    async fn publish(bus: &MemoryBus, topic: Topic, key: &str, payload: &str) {
+        let receipt = bus
+            .publish(topic, Some(key.to_string()), payload.as_bytes().to_vec())
+            .await
+            .unwrap();
+        receipt.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands_subscriptions_start_with_the_latest_command_of_every_key() {
+        let bus = MemoryBus::new();
+        publish(&bus, Topic::Commands, "a", "add a").await;
+        publish(&bus, Topic::Commands, "b", "add b").await;
+        publish(&bus, Topic::Commands, "a", "update a").await;
+
+        let mut commands = bus.subscribe(Topic::Commands, "agent").await;
+        assert_eq!(next(&mut commands).await, "add b");
+        assert_eq!(next(&mut commands).await, "update a");
+    }
+
+    #[tokio::test]
+    async fn subscriptions_get_the_messages_sent_once_subscribed() {
+        let bus = MemoryBus::new();
+        publish(&bus, Topic::Heartbeats, "a", "before").await;
+        let mut first = bus.subscribe(Topic::Heartbeats, "first").await;
+        let mut second = bus.subscribe(Topic::Heartbeats, "second").await;
+        publish(&bus, Topic::Heartbeats, "a", "after").await;
+
+        assert_eq!(next(&mut first).await, "after");
+        assert_eq!(next(&mut second).await, "after");
+    }
+
+    #[tokio::test]
+    async fn nacked_messages_are_delivered_again() {
+        let bus = MemoryBus::new();
+        let mut subscriber = bus.subscribe(Topic::Heartbeats, "api").await;
+        publish(&bus, Topic::Heartbeats, "a", "first").await;
+        publish(&bus, Topic::Heartbeats, "a", "second").await;
+
+        assert_eq!(next(&mut subscriber).await, "first");
+        subscriber.nack().await;
+        assert_eq!(next(&mut subscriber).await, "first");
+        subscriber.ack().await;
+        assert_eq!(next(&mut subscriber).await, "second");
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
 pub use std::net::IpAddr;
 use std::time::Duration;
@@ -33,6 +33,12 @@ impl Domain {
     pub fn as_str(&self) -> &str {
         &self.inner.as_str()
     }
+
+    /// Host part of the domain, without port
+    #[inline]
+    pub fn host(&self) -> &str {
+        self.inner.host()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -176,20 +182,84 @@ pub enum Host {
     Domain(Domain),
 }
 
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DnsRecordType {
+    #[default]
+    A,
+    Aaaa,
+    Cname,
+    Mx,
+    Txt,
+    Ns,
+}
+
+impl DnsRecordType {
+    /// Record type code, as defined in RFC 1035 and RFC 3596
+    pub fn code(&self) -> u16 {
+        match self {
+            DnsRecordType::A => 1,
+            DnsRecordType::Ns => 2,
+            DnsRecordType::Cname => 5,
+            DnsRecordType::Mx => 15,
+            DnsRecordType::Txt => 16,
+            DnsRecordType::Aaaa => 28,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            1 => Some(DnsRecordType::A),
+            2 => Some(DnsRecordType::Ns),
+            5 => Some(DnsRecordType::Cname),
+            15 => Some(DnsRecordType::Mx),
+            16 => Some(DnsRecordType::Txt),
+            28 => Some(DnsRecordType::Aaaa),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DnsCheck {
     pub domain: Domain,
+    /// Dns server to query, the agent resolver is used if none
     pub dns_server: Option<IpAddr>,
+    #[serde(default)]
+    pub record_type: DnsRecordType,
+}
+
+fn default_icmp_probes() -> u16 {
+    3
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct IcmpCheck {
     host: Host,
+    /// Number of echo requests sent for each check
+    #[serde(default = "default_icmp_probes")]
+    probes: u16,
 }
 
 impl IcmpCheck {
     pub fn new(host: Host) -> Self {
-        Self { host }
+        Self {
+            host,
+            probes: default_icmp_probes(),
+        }
+    }
+
+    pub fn with_probes(mut self, probes: u16) -> Self {
+        self.probes = probes;
+        self
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    pub fn probes(&self) -> u16 {
+        self.probes
     }
 }
 
@@ -264,10 +334,105 @@ impl Serialize for Uri {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    #[default]
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+}
+
+impl From<HttpMethod> for http::Method {
+    fn from(value: HttpMethod) -> Self {
+        match value {
+            HttpMethod::Get => http::Method::GET,
+            HttpMethod::Head => http::Method::HEAD,
+            HttpMethod::Post => http::Method::POST,
+            HttpMethod::Put => http::Method::PUT,
+            HttpMethod::Patch => http::Method::PATCH,
+            HttpMethod::Delete => http::Method::DELETE,
+            HttpMethod::Options => http::Method::OPTIONS,
+        }
+    }
+}
+
+/// Healthy http status, either a single code (`401`) or an inclusive range (`{"from": 200, "to": 299}`)
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ExpectedStatus {
+    Code(u16),
+    Range { from: u16, to: u16 },
+}
+
+impl ExpectedStatus {
+    pub fn contains(&self, status: u16) -> bool {
+        match self {
+            ExpectedStatus::Code(code) => *code == status,
+            ExpectedStatus::Range { from, to } => (*from..=*to).contains(&status),
+        }
+    }
+}
+
+fn default_expected_status() -> Vec<ExpectedStatus> {
+    vec![ExpectedStatus::Range { from: 200, to: 299 }]
+}
+
+/// Assertion on an http response, evaluated by the agent after the status check
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HttpAssertion {
+    BodyContains {
+        value: String,
+    },
+    BodyNotContains {
+        value: String,
+    },
+    /// Body matches a regular expression
+    BodyMatches {
+        pattern: String,
+    },
+    /// Value at a JSONPath (e.g. `$.data.items[0].status`) of a json body equals `value`
+    JsonPathEquals {
+        path: String,
+        value: serde_json::Value,
+    },
+    /// Header value equals `value`, header names are case insensitive
+    HeaderEquals {
+        name: String,
+        value: String,
+    },
+    /// Body size in bytes is within bounds
+    BodySize {
+        min: Option<u64>,
+        max: Option<u64>,
+    },
+}
+
+fn default_follow_redirects() -> bool {
+    true
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct HttpCheck {
     pub uri: Uri,
     pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub method: HttpMethod,
+    pub body: Option<String>,
+    /// Status codes for which the check is up, 2xx if not set
+    #[serde(default = "default_expected_status")]
+    pub expected_status: Vec<ExpectedStatus>,
+    #[serde(default = "default_follow_redirects")]
+    pub follow_redirects: bool,
+    /// Request timeout in milliseconds, capped by the check max latency
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub assertions: Vec<HttpAssertion>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -276,6 +441,37 @@ pub struct TcpCheck {
     pub port: u16,
 }
 
+/// Days before certificate expiry at which a tls check result is flagged
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TlsExpiryThresholds {
+    pub warning_days: i64,
+    pub critical_days: i64,
+}
+
+impl Default for TlsExpiryThresholds {
+    fn default() -> Self {
+        Self {
+            warning_days: 30,
+            critical_days: 7,
+        }
+    }
+}
+
+fn default_tls_port() -> u16 {
+    443
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsCheck {
+    pub host: Host,
+    #[serde(default = "default_tls_port")]
+    pub port: u16,
+    /// Server name sent in the handshake, the host domain is used if none
+    pub sni: Option<String>,
+    #[serde(default)]
+    pub thresholds: TlsExpiryThresholds,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data")]
 #[serde(rename_all = "lowercase")]
@@ -284,6 +480,57 @@ pub enum CheckKind {
     Icmp(IcmpCheck),
     Http(HttpCheck),
     Tcp(TcpCheck),
+    Tls(TlsCheck),
+}
+
+/// Labels an agent must have to run a check, such as `provider` or `isp`
+pub type AgentSelector = BTreeMap<String, String>;
+
+/// Limits on the probes an agent sends to a single target host, 0 for no limit
+///
+/// Unset limits of a check are taken from the agent settings.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct TargetLimits {
+    /// Probes in flight at once
+    pub max_concurrent: Option<u32>,
+    /// Probes started per second
+    pub rate: Option<f64>,
+    /// Probes started at once before the rate applies
+    pub burst: Option<u32>,
+}
+
+impl TargetLimits {
+    /// Fill the unset limits from `defaults`
+    pub fn or(self, defaults: TargetLimits) -> Self {
+        Self {
+            max_concurrent: self.max_concurrent.or(defaults.max_concurrent),
+            rate: self.rate.or(defaults.rate),
+            burst: self.burst.or(defaults.burst),
+        }
+    }
+}
+
+/// Failures a check confirms by probing again
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// Failures without an answer from the target : timeouts, connection or resolution errors
+    #[default]
+    NetworkErrors,
+    /// Every failure, unexpected answers and failed assertions included
+    AllFailures,
+}
+
+/// Probes run again right after a failed one, before the failure is reported
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Probes run again at most, 0 to report failures at once
+    pub retries: u32,
+    /// Delay between two attempts in milliseconds
+    pub delay: u64,
+    pub on: RetryOn,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -294,6 +541,9 @@ pub struct Check {
     pub max_latency: Duration,
     pub interval: Duration,
     pub region: String,
+    pub selector: AgentSelector,
+    pub limits: TargetLimits,
+    pub retry: RetryPolicy,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub deleted_at: Option<DateTime<Utc>>,
@@ -308,6 +558,9 @@ impl Into<CheckOutput> for Check {
             max_latency: self.max_latency,
             interval: self.interval.as_secs() as u32,
             region: self.region,
+            selector: self.selector,
+            limits: self.limits,
+            retry: self.retry,
         }
     }
 }
@@ -320,6 +573,12 @@ pub struct CheckInput {
     /// Interval in seconds
     pub interval: u32,
     pub region: String,
+    #[serde(default)]
+    pub selector: AgentSelector,
+    #[serde(default)]
+    pub limits: TargetLimits,
+    #[serde(default)]
+    pub retry: RetryPolicy,
 }
 
 impl CheckInput {
@@ -329,6 +588,9 @@ impl CheckInput {
         max_latency: Duration,
         interval: u32,
         region: String,
+        selector: AgentSelector,
+        limits: TargetLimits,
+        retry: RetryPolicy,
     ) -> Self {
         Self {
             kind,
@@ -336,6 +598,9 @@ impl CheckInput {
             max_latency,
             interval,
             region,
+            selector,
+            limits,
+            retry,
         }
     }
 }
@@ -349,6 +614,9 @@ impl Into<Check> for CheckInput {
             max_latency: self.max_latency,
             interval: Duration::from_secs(self.interval as u64),
             region: self.region,
+            selector: self.selector,
+            limits: self.limits,
+            retry: self.retry,
             created_at: Utc::now(),
             updated_at: Utc::now(),
             deleted_at: None,
@@ -364,4 +632,10 @@ pub struct CheckOutput {
     pub max_latency: Duration,
     pub interval: u32,
     pub region: String,
+    #[serde(default)]
+    pub selector: AgentSelector,
+    #[serde(default)]
+    pub limits: TargetLimits,
+    #[serde(default)]
+    pub retry: RetryPolicy,
 }
@@ -0,0 +1,49 @@
+use crate::check::{Deserialize, DnsRecordType, Serialize};
+
+/// Response code of a dns answer (RFC 1035)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DnsRcode {
+    NoError,
+    FormErr,
+    ServFail,
+    NxDomain,
+    NotImp,
+    Refused,
+    Other(u8),
+}
+
+impl From<u8> for DnsRcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => DnsRcode::NoError,
+            1 => DnsRcode::FormErr,
+            2 => DnsRcode::ServFail,
+            3 => DnsRcode::NxDomain,
+            4 => DnsRcode::NotImp,
+            5 => DnsRcode::Refused,
+            code => DnsRcode::Other(code),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DnsAnswer {
+    pub name: String,
+    /// Record type, none for types the agent does not decode
+    pub record_type: Option<DnsRecordType>,
+    /// Time to live in seconds
+    pub ttl: u32,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DnsFields {
+    /// Address of the queried dns server
+    pub server: String,
+    pub record_type: DnsRecordType,
+    /// Response code, none if no response was received
+    pub rcode: Option<DnsRcode>,
+    pub answers: Vec<DnsAnswer>,
+    pub error: Option<String>,
+}
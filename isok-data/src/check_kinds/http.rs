@@ -1,12 +1,156 @@
-use crate::check::{Deserialize, Serialize};
+use crate::check::{Deserialize, HttpAssertion, IpAddr, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HttpAssertionResult {
+    pub assertion: HttpAssertion,
+    pub passed: bool,
+    /// Why the assertion failed, none if it passed
+    pub message: Option<String>,
+}
+
+impl HttpAssertionResult {
+    pub fn passed(assertion: HttpAssertion) -> Self {
+        Self {
+            assertion,
+            passed: true,
+            message: None,
+        }
+    }
+
+    pub fn failed(assertion: HttpAssertion, message: String) -> Self {
+        Self {
+            assertion,
+            passed: false,
+            message: Some(message),
+        }
+    }
+}
+
+/// Request phases durations in microseconds, summed over redirects, none if a phase did not happen
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct HttpTimings {
+    pub dns: Option<u64>,
+    pub connect: Option<u64>,
+    pub tls: Option<u64>,
+    /// Time to first byte, from request sent to response headers received
+    pub ttfb: Option<u64>,
+    pub download: Option<u64>,
+}
+
+/// Why an http check got no response
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpFailure {
+    DnsFailure,
+    Refused,
+    Unreachable,
+    Tls,
+    Timeout,
+    TooManyRedirects,
+    Other,
+}
+
+impl HttpFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpFailure::DnsFailure => "dns_failure",
+            HttpFailure::Refused => "refused",
+            HttpFailure::Unreachable => "unreachable",
+            HttpFailure::Tls => "tls",
+            HttpFailure::Timeout => "timeout",
+            HttpFailure::TooManyRedirects => "too_many_redirects",
+            HttpFailure::Other => "other",
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HttpFields {
-    pub status_code: u16,
+    /// Status of the final response, none if there was no response
+    pub status_code: Option<u16>,
+    /// Whether the status code is one of the check expected ones and all assertions passed
+    #[serde(default)]
+    pub up: bool,
+    #[serde(default)]
+    pub assertions: Vec<HttpAssertionResult>,
+    #[serde(default)]
+    pub timings: HttpTimings,
+    /// Address of the server that sent the final response
+    pub remote_ip: Option<IpAddr>,
+    /// Http version of the final response (e.g. HTTP/1.1)
+    pub http_version: Option<String>,
+    pub failure: Option<HttpFailure>,
+    pub error: Option<String>,
 }
 
 impl HttpFields {
-    pub fn new(status_code: u16) -> Self {
-        Self { status_code }
+    pub fn new(status_code: u16, up: bool, assertions: Vec<HttpAssertionResult>) -> Self {
+        Self {
+            status_code: Some(status_code),
+            up,
+            assertions,
+            timings: HttpTimings::default(),
+            remote_ip: None,
+            http_version: None,
+            failure: None,
+            error: None,
+        }
+    }
+
+    pub fn failed(failure: HttpFailure, error: String) -> Self {
+        Self {
+            status_code: None,
+            up: false,
+            assertions: Vec::new(),
+            timings: HttpTimings::default(),
+            remote_ip: None,
+            http_version: None,
+            failure: Some(failure),
+            error: Some(error),
+        }
+    }
+
+    pub fn with_connection(
+        mut self,
+        timings: HttpTimings,
+        remote_ip: Option<IpAddr>,
+        http_version: Option<String>,
+    ) -> Self {
+        self.timings = timings;
+        self.remote_ip = remote_ip;
+        self.http_version = http_version;
+        self
+    }
+
+    /// Assertions that did not pass
+    pub fn failed_assertions(&self) -> impl Iterator<Item = &HttpAssertionResult> {
+        self.assertions.iter().filter(|assertion| !assertion.passed)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::pulsar_messages::{CheckData, CheckMessage, CheckOutcome};
+
+    use super::*;
+
+    #[test]
+    fn fields_of_agents_without_assertions() {
+        let message: CheckMessage = serde_json::from_str(
+            r#"{
+                "check_id": "6f1c3b1e-9a0a-4c1e-8f0e-2b7d4c1a9e11",
+                "agent_id": "agent",
+                "timestamp": "2024-05-02T10:00:00+00:00",
+                "latency": 12,
+                "fields": { "status_code": 200, "up": true }
+            }"#,
+        )
+        .unwrap();
+
+        let data = CheckData::<HttpFields>::try_from(message).unwrap();
+        assert_eq!(data.outcome, CheckOutcome::Up);
+        assert_eq!(data.fields.status_code, Some(200));
+        assert!(data.fields.up);
+        assert!(data.fields.assertions.is_empty());
+        assert_eq!(data.fields.timings.download, None);
+    }
+}
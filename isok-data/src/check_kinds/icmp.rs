@@ -0,0 +1,41 @@
+use crate::check::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IcmpFields {
+    /// Number of echo requests sent
+    pub sent: u16,
+    /// Number of echo replies received
+    pub received: u16,
+    /// Packet loss in percent
+    pub packet_loss: f64,
+    /// Minimum round trip time in microseconds
+    pub rtt_min: Option<u64>,
+    /// Average round trip time in microseconds
+    pub rtt_avg: Option<u64>,
+    /// Maximum round trip time in microseconds
+    pub rtt_max: Option<u64>,
+    /// TTL (hop limit for IPv6) of the last received reply
+    pub ttl: Option<u8>,
+}
+
+impl IcmpFields {
+    /// Summarize probes, `rtts` holds the round trip time of each received reply
+    pub fn new(sent: u16, rtts: &[u64], ttl: Option<u8>) -> Self {
+        let received = rtts.len() as u16;
+        let packet_loss = if sent == 0 {
+            100.0
+        } else {
+            (sent.saturating_sub(received)) as f64 * 100.0 / sent as f64
+        };
+
+        Self {
+            sent,
+            received,
+            packet_loss,
+            rtt_min: rtts.iter().min().copied(),
+            rtt_avg: (!rtts.is_empty()).then(|| rtts.iter().sum::<u64>() / rtts.len() as u64),
+            rtt_max: rtts.iter().max().copied(),
+            ttl,
+        }
+    }
+}
@@ -1 +1,5 @@
-pub mod http;
\ No newline at end of file
+pub mod dns;
+pub mod http;
+pub mod icmp;
+pub mod tcp;
+pub mod tls;
\ No newline at end of file
@@ -0,0 +1,41 @@
+use crate::check::{Deserialize, Serialize};
+
+/// Why a tcp connection could not be established
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpFailure {
+    DnsFailure,
+    Refused,
+    Timeout,
+    Unreachable,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TcpFields {
+    pub connected: bool,
+    /// Connection latency in microseconds
+    pub connect_time: Option<u64>,
+    pub failure: Option<TcpFailure>,
+    pub error: Option<String>,
+}
+
+impl TcpFields {
+    pub fn connected(connect_time: u64) -> Self {
+        Self {
+            connected: true,
+            connect_time: Some(connect_time),
+            failure: None,
+            error: None,
+        }
+    }
+
+    pub fn failed(failure: TcpFailure, error: String) -> Self {
+        Self {
+            connected: false,
+            connect_time: None,
+            failure: Some(failure),
+            error: Some(error),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::check::{Deserialize, Serialize, TlsExpiryThresholds};
+
+/// Certificate expiry state, compared to the check [`TlsExpiryThresholds`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsExpiryStatus {
+    Ok,
+    Warning,
+    Critical,
+    Expired,
+    Unknown,
+}
+
+impl TlsExpiryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsExpiryStatus::Ok => "ok",
+            TlsExpiryStatus::Warning => "warning",
+            TlsExpiryStatus::Critical => "critical",
+            TlsExpiryStatus::Expired => "expired",
+            TlsExpiryStatus::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TlsFields {
+    /// Negotiated protocol version (e.g. TLSv1.3), none if the handshake failed
+    pub protocol: Option<String>,
+    pub days_until_expiry: Option<i64>,
+    pub not_after: Option<String>,
+    pub issuer: Option<String>,
+    pub subject: Option<String>,
+    pub subject_alt_names: Vec<String>,
+    /// Whether the certificate is valid for the expected server name
+    pub hostname_match: Option<bool>,
+    /// Whether the certificate chain verifies against the agent trust store
+    pub chain_valid: Option<bool>,
+    pub chain_error: Option<String>,
+    pub thresholds: TlsExpiryThresholds,
+    pub error: Option<String>,
+}
+
+impl TlsFields {
+    pub fn failed(thresholds: TlsExpiryThresholds, error: String) -> Self {
+        Self {
+            protocol: None,
+            days_until_expiry: None,
+            not_after: None,
+            issuer: None,
+            subject: None,
+            subject_alt_names: Vec::new(),
+            hostname_match: None,
+            chain_valid: None,
+            chain_error: None,
+            thresholds,
+            error: Some(error),
+        }
+    }
+
+    pub fn expiry_status(&self) -> TlsExpiryStatus {
+        match self.days_until_expiry {
+            None => TlsExpiryStatus::Unknown,
+            Some(days) if days < 0 => TlsExpiryStatus::Expired,
+            Some(days) if days <= self.thresholds.critical_days => TlsExpiryStatus::Critical,
+            Some(days) if days <= self.thresholds.warning_days => TlsExpiryStatus::Warning,
+            Some(_) => TlsExpiryStatus::Ok,
+        }
+    }
+}
@@ -1,5 +1,10 @@
+pub mod agent;
+pub mod bus;
 pub mod check;
 pub mod check_kinds;
 pub mod owner;
+pub mod pulsar_bus;
 pub mod pulsar_commands;
+pub mod pulsar_connector;
+pub mod pulsar_heartbeats;
 pub mod pulsar_messages;
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use log::{error, info, warn};
+use pulsar::compression::{Compression, CompressionZstd};
+use pulsar::consumer::{InitialPosition, Message};
+use pulsar::{
+    producer, proto, Consumer, ConsumerOptions, DeserializeMessage, Payload, Producer,
+    ProducerOptions, Pulsar, SubType, TokioExecutor,
+};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::bus::{BusError, MessageBus, Receipt, Subscriber, Topic};
+use crate::pulsar_connector::{Backoff, PulsarConnector};
+
+/// Message payload, left as is
+struct Raw;
+
+impl DeserializeMessage for Raw {
+    type Output = Vec<u8>;
+
+    fn deserialize_message(payload: &Payload) -> Self::Output {
+        payload.data.clone()
+    }
+}
+
+/// Producer of a topic, built again once broken
+struct ProducerSlot {
+    producer: Option<Producer<TokioExecutor>>,
+    backoff: Backoff,
+    next_attempt: Instant,
+}
+
+impl Default for ProducerSlot {
+    fn default() -> Self {
+        Self {
+            producer: None,
+            backoff: Backoff::default(),
+            next_attempt: Instant::now(),
+        }
+    }
+}
+
+/// [MessageBus] backed by pulsar topics of a tenant namespace
+///
+/// Producers are built on the first message of their topic. A producer that
+/// fails to send is built again at once, then with backoff, messages failing
+/// meanwhile.
+pub struct PulsarBus {
+    connector: PulsarConnector,
+    tenant: String,
+    namespace: String,
+    commands_topic: Option<String>,
+    producers: Mutex<HashMap<Topic, ProducerSlot>>,
+}
+
+impl PulsarBus {
+    pub fn new(connector: PulsarConnector, tenant: &str, namespace: &str) -> Self {
+        Self {
+            connector,
+            tenant: tenant.to_string(),
+            namespace: namespace.to_string(),
+            commands_topic: None,
+            producers: Default::default(),
+        }
+    }
+
+    pub fn with_commands_topic(mut self, topic: &str) -> Self {
+        self.commands_topic = Some(topic.to_string());
+        self
+    }
+
+    pub fn connector(&self) -> &PulsarConnector {
+        &self.connector
+    }
+
+    /// Full name of a pulsar topic
+    pub fn topic_link(&self, topic: Topic) -> Result<String, BusError> {
+        let name = match topic {
+            Topic::Commands => self
+                .commands_topic
+                .clone()
+                .ok_or(BusError::UnknownTopic(topic))?,
+            Topic::Results(check_type) => check_type.to_string(),
+            Topic::AggregatedHttp => "aggregated-http".to_string(),
+            Topic::Heartbeats => "heartbeats".to_string(),
+        };
+
+        Ok(format!(
+            "persistent://{}/{}/{name}",
+            self.tenant, self.namespace
+        ))
+    }
+
+    async fn build_producer(
+        client: Pulsar<TokioExecutor>,
+        topic: Topic,
+        link: String,
+    ) -> Result<Producer<TokioExecutor>, pulsar::Error> {
+        let builder = client.producer().with_topic(link);
+
+        match topic {
+            Topic::Commands => {
+                builder
+                    .with_options(ProducerOptions {
+                        schema: Some(proto::Schema {
+                            r#type: proto::schema::Type::String as i32,
+                            ..Default::default()
+                        }),
+                        compression: Some(Compression::Zstd(CompressionZstd {
+                            ..Default::default()
+                        })),
+                        ..Default::default()
+                    })
+                    .build()
+                    .await
+            }
+            Topic::AggregatedHttp => builder.with_name("aggregated-http").build().await,
+            // every agent has its own producers, named by pulsar
+            Topic::Results(_) | Topic::Heartbeats => builder.build().await,
+        }
+    }
+
+    async fn send(
+        &self,
+        topic: Topic,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<Receipt, BusError> {
+        let link = self.topic_link(topic)?;
+        let name = format!("producer {link}");
+        let mut producers = self.producers.lock().await;
+        let slot = producers.entry(topic).or_default();
+        let message = producer::Message {
+            payload,
+            partition_key: key,
+            ..Default::default()
+        };
+
+        if let Some(producer) = slot.producer.as_mut() {
+            match producer.send_non_blocking(message.clone()).await {
+                Ok(receipt) => return Ok(receipt_of(receipt)),
+                Err(e) => {
+                    warn!("Could not send to pulsar topic {link}, reconnecting : {e}");
+                    slot.producer = None;
+                    self.connector.disconnected(&name);
+                }
+            }
+        } else if Instant::now() < slot.next_attempt {
+            return Err(BusError::Disconnected(topic));
+        }
+
+        let built = self
+            .connector
+            .try_connect(&name, |client| Self::build_producer(client, topic, link))
+            .await;
+        let sent = match built {
+            Ok(producer) => slot
+                .producer
+                .insert(producer)
+                .send_non_blocking(message)
+                .await
+                .map_err(|e| {
+                    slot.producer = None;
+                    self.connector.disconnected(&name);
+                    e
+                }),
+            Err(e) => Err(e),
+        };
+
+        match sent {
+            Ok(receipt) => {
+                slot.backoff.reset();
+                Ok(receipt_of(receipt))
+            }
+            Err(e) => {
+                slot.next_attempt = Instant::now() + slot.backoff.next_delay();
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn build_consumer(
+        client: Pulsar<TokioExecutor>,
+        topic: Topic,
+        link: String,
+        subscription: String,
+    ) -> Result<Consumer<Raw, TokioExecutor>, pulsar::Error> {
+        let builder = client.consumer().with_topic(link);
+
+        match topic {
+            Topic::Commands => {
+                builder
+                    .with_subscription_type(SubType::Exclusive)
+                    .with_consumer_name("consumer")
+                    .with_subscription(subscription)
+                    .with_options(ConsumerOptions {
+                        read_compacted: Some(true),
+                        initial_position: InitialPosition::Earliest,
+                        ..Default::default()
+                    })
+                    .build()
+                    .await
+            }
+            Topic::Results(_) | Topic::AggregatedHttp => {
+                builder
+                    .with_subscription_type(SubType::Failover)
+                    .with_consumer_name(&subscription)
+                    .with_subscription(&subscription)
+                    .with_options(ConsumerOptions {
+                        durable: Some(true),
+                        read_compacted: Some(true),
+                        ..Default::default()
+                    })
+                    .build()
+                    .await
+            }
+            Topic::Heartbeats => {
+                builder
+                    .with_subscription_type(SubType::Shared)
+                    .with_subscription(subscription)
+                    .with_options(ConsumerOptions {
+                        initial_position: InitialPosition::Latest,
+                        ..Default::default()
+                    })
+                    .build()
+                    .await
+            }
+        }
+    }
+
+    async fn connect_subscriber(&self, topic: Topic, subscription: &str) -> Box<dyn Subscriber> {
+        let link = match self.topic_link(topic) {
+            Ok(link) => link,
+            Err(e) => {
+                error!("Can't subscribe to {topic} : {e}");
+                return Box::new(ClosedSubscriber);
+            }
+        };
+        let name = format!("consumer {link}");
+        info!("Starting consumer with subscription id : {subscription}");
+
+        let consumer = self
+            .connector
+            .connect(&name, |client| {
+                Self::build_consumer(client, topic, link.clone(), subscription.to_string())
+            })
+            .await;
+
+        Box::new(PulsarSubscriber {
+            consumer,
+            last: None,
+            connector: self.connector.clone(),
+            name,
+        })
+    }
+}
+
+fn receipt_of(receipt: producer::SendFuture) -> Receipt {
+    Box::pin(async move {
+        receipt.await?;
+        Ok(())
+    })
+}
+
+impl MessageBus for PulsarBus {
+    fn publish(
+        &self,
+        topic: Topic,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'_, Result<Receipt, BusError>> {
+        Box::pin(self.send(topic, key, payload))
+    }
+
+    fn subscribe<'a>(
+        &'a self,
+        topic: Topic,
+        subscription: &'a str,
+    ) -> BoxFuture<'a, Box<dyn Subscriber>> {
+        Box::pin(self.connect_subscriber(topic, subscription))
+    }
+}
+
+struct PulsarSubscriber {
+    consumer: Consumer<Raw, TokioExecutor>,
+    last: Option<Message<Raw>>,
+    connector: PulsarConnector,
+    name: String,
+}
+
+impl Subscriber for PulsarSubscriber {
+    fn next(&mut self) -> BoxFuture<'_, Option<Vec<u8>>> {
+        Box::pin(async {
+            match self.consumer.try_next().await {
+                Ok(Some(message)) => {
+                    let payload = message.payload.data.clone();
+                    self.last = Some(message);
+                    Some(payload)
+                }
+                Ok(None) => {
+                    self.connector.disconnected(&self.name);
+                    None
+                }
+                Err(e) => {
+                    error!("Can't receive pulsar message : {e}");
+                    self.connector.disconnected(&self.name);
+                    None
+                }
+            }
+        })
+    }
+
+    fn ack(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async {
+            let Some(message) = self.last.take() else {
+                return;
+            };
+            if let Err(e) = self.consumer.ack(&message).await {
+                error!("Can't acknowledge pulsar message : {e}");
+            }
+        })
+    }
+
+    fn nack(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async {
+            let Some(message) = self.last.take() else {
+                return;
+            };
+            if let Err(e) = self.consumer.nack(&message).await {
+                error!("Can't negatively acknowledge pulsar message : {e}");
+            }
+        })
+    }
+}
+
+/// Subscriber of a topic that can't be subscribed to
+struct ClosedSubscriber;
+
+impl Subscriber for ClosedSubscriber {
+    fn next(&mut self) -> BoxFuture<'_, Option<Vec<u8>>> {
+        Box::pin(std::future::pending())
+    }
+
+    fn ack(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    fn nack(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+}
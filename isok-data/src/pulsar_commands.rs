@@ -10,9 +10,16 @@ pub struct AddCommand {
     pub check: CheckOutput,
 }
 
+/// Replace the definition of a running check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateCommand {
+    pub check: CheckOutput,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CommandKind {
     Add(AddCommand),
+    Update(UpdateCommand),
     Remove(Uuid),
 }
 
@@ -52,6 +59,15 @@ impl Command {
         }
     }
 
+    pub fn new_update_command(check: Check) -> Self {
+        Self {
+            id: check.check_id,
+            kind: CommandKind::Update(UpdateCommand {
+                check: check.into(),
+            }),
+        }
+    }
+
     pub fn new_remove_command(check: Check) -> Self {
         Self {
             id: check.check_id,
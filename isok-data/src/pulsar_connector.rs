@@ -0,0 +1,218 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use pulsar::error::{ConsumerError, ProducerError, ServiceDiscoveryError};
+use pulsar::{Authentication, Pulsar, TokioExecutor};
+
+/// Random number in `[0, 1)`, enough to spread the reconnections of a fleet
+fn random_unit() -> f64 {
+    // std hashers are randomly keyed
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Whether `error` comes from the connection to pulsar rather than from a topic
+fn is_connection_error(error: &pulsar::Error) -> bool {
+    matches!(
+        error,
+        pulsar::Error::Connection(_)
+            | pulsar::Error::Consumer(ConsumerError::Connection(_))
+            | pulsar::Error::Producer(ProducerError::Connection(_))
+            | pulsar::Error::ServiceDiscovery(ServiceDiscoveryError::Connection(_))
+    )
+}
+
+/// Exponential backoff between connection attempts, with jitter
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempts: 0,
+        }
+    }
+
+    /// Delay before the next attempt, between half and all of the exponential delay
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .initial
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(self.max);
+        self.attempts += 1;
+
+        let half = ceiling / 2;
+        half + half.mul_f64(random_unit())
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Connection state of named pulsar consumers and producers
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStates(Arc<Mutex<BTreeMap<String, bool>>>);
+
+impl ConnectionStates {
+    /// Record the state of a connection, logging its transitions
+    pub fn set(&self, name: &str, connected: bool) {
+        let Ok(mut states) = self.0.lock() else {
+            return;
+        };
+
+        match (states.insert(name.to_string(), connected), connected) {
+            (None, true) => info!("Connected to pulsar {name} !"),
+            (Some(false), true) => info!("Reconnected to pulsar {name} !"),
+            (None | Some(true), false) => warn!("Lost connection to pulsar {name}"),
+            _ => {}
+        }
+    }
+
+    /// Names of the connections currently down
+    pub fn disconnected(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .map(|states| {
+                states
+                    .iter()
+                    .filter(|(_, connected)| !**connected)
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn all_connected(&self) -> bool {
+        self.disconnected().is_empty()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, bool> {
+        self.0
+            .lock()
+            .map(|states| states.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Pulsar client shared by consumers and producers, built again when connections fail
+///
+/// Consumers and producers are built through [connect](PulsarConnector::connect),
+/// which retries with backoff, and flagged with
+/// [disconnected](PulsarConnector::disconnected) when they break so that their
+/// owner builds them again.
+#[derive(Clone)]
+pub struct PulsarConnector {
+    address: String,
+    token: String,
+    client: Arc<tokio::sync::Mutex<Option<Pulsar<TokioExecutor>>>>,
+    backoff: Backoff,
+    states: ConnectionStates,
+}
+
+impl PulsarConnector {
+    pub fn new(address: &str, token: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            token: token.to_string(),
+            client: Default::default(),
+            backoff: Backoff::default(),
+            states: ConnectionStates::default(),
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn states(&self) -> ConnectionStates {
+        self.states.clone()
+    }
+
+    async fn client(&self) -> Result<Pulsar<TokioExecutor>, pulsar::Error> {
+        let mut client = self.client.lock().await;
+        if let Some(client) = client.as_ref() {
+            return Ok(client.clone());
+        }
+
+        let built = Pulsar::builder(&self.address, TokioExecutor)
+            .with_auth(Authentication {
+                name: "token".to_owned(),
+                data: Vec::from(self.token.as_bytes()),
+            })
+            .build()
+            .await?;
+        *client = Some(built.clone());
+        Ok(built)
+    }
+
+    /// Build the `name` consumer or producer once
+    pub async fn try_connect<T, F, Fut>(&self, name: &str, build: F) -> Result<T, pulsar::Error>
+    where
+        F: FnOnce(Pulsar<TokioExecutor>) -> Fut,
+        Fut: Future<Output = Result<T, pulsar::Error>>,
+    {
+        let built = match self.client().await {
+            Ok(client) => build(client).await,
+            Err(e) => Err(e),
+        };
+
+        match &built {
+            Ok(_) => self.states.set(name, true),
+            Err(e) => {
+                self.states.set(name, false);
+                // the client itself is broken, the next attempt builds a new one,
+                // others like a missing topic leave the client to the other topics
+                if is_connection_error(e) {
+                    *self.client.lock().await = None;
+                }
+            }
+        }
+        built
+    }
+
+    /// Build the `name` consumer or producer, retrying with backoff until it succeeds
+    pub async fn connect<T, F, Fut>(&self, name: &str, build: F) -> T
+    where
+        F: Fn(Pulsar<TokioExecutor>) -> Fut,
+        Fut: Future<Output = Result<T, pulsar::Error>>,
+    {
+        let mut backoff = self.backoff.clone();
+        loop {
+            match self.try_connect(name, &build).await {
+                Ok(built) => return built,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!(
+                        "Failed to connect to pulsar {name}, retrying in {}ms : {e}",
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Flag the `name` consumer or producer as broken, before building it again
+    pub fn disconnected(&self, name: &str) {
+        self.states.set(name, false);
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use pulsar::producer::Message;
+use pulsar::{DeserializeMessage, Error, Payload, SerializeMessage};
+use serde::{Deserialize, Serialize};
+
+/// Periodic liveness message published by every agent
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Heartbeat {
+    pub agent_id: String,
+    /// Pool sharing its checks between agents, none if the agent runs every check
+    pub pool: Option<String>,
+    /// Regions served by the agent, every region if empty
+    #[serde(default)]
+    pub regions: Vec<String>,
+    #[serde(default)]
+    pub version: String,
+    pub timestamp: DateTime<Utc>,
+    pub started_at: DateTime<Utc>,
+    /// Number of jobs by check interval in seconds
+    #[serde(default)]
+    pub jobs: BTreeMap<u64, u64>,
+    /// Highest lag of the agent schedulers, from late ticks or probes delayed by
+    /// target limits, in milliseconds
+    #[serde(default)]
+    pub scheduler_lag: u64,
+    /// Results waiting to be sent to pulsar
+    #[serde(default)]
+    pub queue_depth: u64,
+    /// Set by an agent shutting down, its checks are handed over to the rest of the pool
+    #[serde(default)]
+    pub leaving: bool,
+}
+
+impl SerializeMessage for Heartbeat {
+    fn serialize_message(input: Self) -> Result<Message, Error> {
+        let payload = serde_json::to_vec(&input).map_err(|e| Error::Custom(e.to_string()))?;
+
+        Ok(Message {
+            partition_key: Some(input.agent_id),
+            payload,
+            ..Default::default()
+        })
+    }
+}
+
+impl DeserializeMessage for Heartbeat {
+    type Output = Result<Heartbeat, Error>;
+
+    fn deserialize_message(payload: &Payload) -> Self::Output {
+        serde_json::from_slice(payload.data.as_slice()).map_err(|e| Error::Custom(e.to_string()))
+    }
+}
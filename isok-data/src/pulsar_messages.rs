@@ -8,16 +8,53 @@ use std::str::FromStr;
 use std::time::Duration;
 use uuid::Uuid;
 
+/// Verdict of a check execution
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckOutcome {
+    /// Also the verdict of results sent before outcomes existed
+    #[default]
+    Up,
+    /// Up, but slower than the degraded latency threshold
+    Degraded,
+    Down,
+    /// No result before the check max latency
+    Timeout,
+}
+
+impl CheckOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckOutcome::Up => "up",
+            CheckOutcome::Degraded => "degraded",
+            CheckOutcome::Down => "down",
+            CheckOutcome::Timeout => "timeout",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CheckMessage {
     pub check_id: Uuid,
     pub agent_id: String,
     pub timestamp: DateTime<FixedOffset>,
-    /// Latency in milliseconds
-    pub latency: u64,
+    /// Latency in milliseconds, none if the check failed before it could be measured
+    pub latency: Option<u64>,
+    #[serde(default)]
+    pub outcome: CheckOutcome,
+    /// Probes run, more than one when failures were retried
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// Outcome of every probe run, the last one being the reported outcome
+    #[serde(default)]
+    pub attempt_outcomes: Vec<CheckOutcome>,
     pub fields: serde_json::Value,
 }
 
+fn default_attempts() -> u32 {
+    1
+}
+
 impl SerializeMessage for CheckMessage {
     fn serialize_message(input: Self) -> Result<Message, Error> {
         let payload = serde_json::to_vec(&input).map_err(|e| Error::Custom(e.to_string()))?;
@@ -38,9 +75,24 @@ impl DeserializeMessage for CheckMessage {
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
 pub enum CheckType {
     Http,
+    Icmp,
+    Tcp,
+    Dns,
+    Tls,
+}
+
+impl CheckType {
+    pub const ALL: [CheckType; 5] = [
+        CheckType::Http,
+        CheckType::Icmp,
+        CheckType::Tcp,
+        CheckType::Dns,
+        CheckType::Tls,
+    ];
 }
 
 impl Display for CheckType {
@@ -50,6 +102,10 @@ impl Display for CheckType {
             "{}",
             match self {
                 CheckType::Http => "http",
+                CheckType::Icmp => "icmp",
+                CheckType::Tcp => "tcp",
+                CheckType::Dns => "dns",
+                CheckType::Tls => "tls",
             }
         )
     }
@@ -76,6 +132,10 @@ impl FromStr for CheckType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "http" => Ok(CheckType::Http),
+            "icmp" => Ok(CheckType::Icmp),
+            "tcp" => Ok(CheckType::Tcp),
+            "dns" => Ok(CheckType::Dns),
+            "tls" => Ok(CheckType::Tls),
             _ => Err(CheckTypeParseError(s.to_string())),
         }
     }
@@ -83,17 +143,29 @@ impl FromStr for CheckType {
 
 pub struct CheckResult<A: Serialize + DeserializeOwned> {
     pub timestamp: DateTime<FixedOffset>,
-    pub latency: Duration,
+    pub latency: Option<Duration>,
+    pub outcome: CheckOutcome,
     pub fields: A,
 }
 
 impl<A: Serialize + DeserializeOwned> CheckResult<A> {
+    /// Flag an up result as degraded if its latency is above `threshold`
+    pub fn with_degraded_threshold(mut self, threshold: Duration) -> Self {
+        if self.outcome == CheckOutcome::Up && self.latency.is_some_and(|l| l > threshold) {
+            self.outcome = CheckOutcome::Degraded;
+        }
+        self
+    }
+
     pub fn to_message(&self, check_id: Uuid, agent_id: String) -> CheckMessage {
         CheckMessage {
             check_id,
             agent_id,
             timestamp: self.timestamp,
-            latency: self.latency.as_millis() as u64,
+            latency: self.latency.map(|latency| latency.as_millis() as u64),
+            outcome: self.outcome,
+            attempts: 1,
+            attempt_outcomes: vec![self.outcome],
             fields: serde_json::to_value(&self.fields).unwrap(), //cannot fail
         }
     }
@@ -104,18 +176,23 @@ pub struct CheckData<A: Serialize + DeserializeOwned + Debug> {
     pub check_id: Uuid,
     pub agent_id: String,
     pub timestamp: DateTime<FixedOffset>,
-    pub latency: Duration,
+    pub latency: Option<Duration>,
+    pub outcome: CheckOutcome,
     pub fields: A,
 }
 
-impl<A: Serialize + DeserializeOwned + Debug> Into<CheckData<A>> for CheckMessage {
-    fn into(self) -> CheckData<A> {
-        CheckData {
-            check_id: self.check_id,
-            agent_id: self.agent_id,
-            timestamp: self.timestamp,
-            latency: Duration::from_millis(self.latency),
-            fields: serde_json::from_value(self.fields).unwrap(), //cannot fail
-        }
+/// Fails when the message fields do not match the fields of the check type
+impl<A: Serialize + DeserializeOwned + Debug> TryFrom<CheckMessage> for CheckData<A> {
+    type Error = serde_json::Error;
+
+    fn try_from(message: CheckMessage) -> Result<Self, Self::Error> {
+        Ok(CheckData {
+            check_id: message.check_id,
+            agent_id: message.agent_id,
+            timestamp: message.timestamp,
+            latency: message.latency.map(Duration::from_millis),
+            outcome: message.outcome,
+            fields: serde_json::from_value(message.fields)?,
+        })
     }
 }
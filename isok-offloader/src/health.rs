@@ -0,0 +1,55 @@
+use std::fmt::Write;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use isok_data::pulsar_connector::ConnectionStates;
+use log::{error, info};
+
+/// Connection state of every pulsar consumer and producer, one per line
+fn render(connections: &ConnectionStates) -> String {
+    let mut out = String::new();
+    for (name, connected) in connections.snapshot() {
+        let state = if connected {
+            "connected"
+        } else {
+            "disconnected"
+        };
+        let _ = writeln!(out, "{name} {state}");
+    }
+    out
+}
+
+async fn healthz(State(connections): State<ConnectionStates>) -> (StatusCode, String) {
+    if connections.snapshot().is_empty() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "pulsar not connected yet".to_string(),
+        )
+    } else if connections.all_connected() {
+        (StatusCode::OK, render(&connections))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, render(&connections))
+    }
+}
+
+/// Serve `/healthz` on `address`
+pub async fn serve(address: String, connections: ConnectionStates) {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .with_state(connections);
+
+    let listener = match tokio::net::TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Could not start health listener on {address} : {e}");
+            return;
+        }
+    };
+
+    info!("Serving health at http://{address}/healthz");
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("Health listener stopped : {e}");
+    }
+}
@@ -1,15 +1,22 @@
 use chrono::{DateTime, FixedOffset};
-use isok_data::check_kinds::http::HttpFields;
+use futures::FutureExt;
+use isok_data::bus::{self, MessageBus, Receipt, Topic};
+use isok_data::check_kinds::http::{HttpFailure, HttpFields};
 use isok_data::pulsar_messages::CheckData;
-use log::info;
+use log::{error, info, warn};
 use pulsar::producer::Message;
-use pulsar::{DeserializeMessage, Error, Payload, Producer, SerializeMessage, TokioExecutor};
+use pulsar::{DeserializeMessage, Error, Payload, SerializeMessage};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Receiver;
 use uuid::Uuid;
 
+/// Aggregates kept to be sent again while pulsar is unreachable, the oldest are dropped past it
+const MAX_UNSENT: usize = 4096;
+
 #[derive(Serialize, Deserialize)]
 pub struct AggregatedCheckMessage {
     check_id: Uuid,
@@ -17,6 +24,7 @@ pub struct AggregatedCheckMessage {
     /// Latency in milliseconds
     latency: u64,
     status_codes: StatusCodeCount,
+    failures: FailureCount,
 }
 
 impl SerializeMessage for AggregatedCheckMessage {
@@ -42,16 +50,21 @@ impl DeserializeMessage for AggregatedCheckMessage {
 pub struct AggregateValues {
     pub latency: Duration,
     pub status_codes: StatusCodeCount,
+    pub failures: FailureCount,
 }
 
 impl AggregateValues {
-    pub fn aggregate(&mut self, latency: Duration, status_code: u16) {
-        self.latency = self.latency.max(latency);
-        match status_code {
-            0..=299 => self.status_codes._200 += 1,
-            300..=399 => self.status_codes._300 += 1,
-            400..=499 => self.status_codes._400 += 1,
-            500..=599 => self.status_codes._500 += 1,
+    pub fn aggregate(&mut self, latency: Option<Duration>, fields: &HttpFields) {
+        if let Some(latency) = latency {
+            self.latency = self.latency.max(latency);
+        }
+
+        match (fields.status_code, fields.failure) {
+            (Some(0..=299), _) => self.status_codes._200 += 1,
+            (Some(300..=399), _) => self.status_codes._300 += 1,
+            (Some(400..=499), _) => self.status_codes._400 += 1,
+            (Some(500..=599), _) => self.status_codes._500 += 1,
+            (None, Some(failure)) => self.failures.add(failure),
             _ => {}
         }
     }
@@ -62,6 +75,7 @@ impl Default for AggregateValues {
         Self {
             latency: Duration::from_nanos(0),
             status_codes: StatusCodeCount::default(),
+            failures: FailureCount::default(),
         }
     }
 }
@@ -74,6 +88,32 @@ pub struct StatusCodeCount {
     pub _500: usize,
 }
 
+/// Checks without response, by [`HttpFailure`]
+#[derive(Default, Copy, Clone, Serialize, Deserialize)]
+pub struct FailureCount {
+    pub dns_failure: usize,
+    pub refused: usize,
+    pub unreachable: usize,
+    pub tls: usize,
+    pub timeout: usize,
+    pub too_many_redirects: usize,
+    pub other: usize,
+}
+
+impl FailureCount {
+    pub fn add(&mut self, failure: HttpFailure) {
+        match failure {
+            HttpFailure::DnsFailure => self.dns_failure += 1,
+            HttpFailure::Refused => self.refused += 1,
+            HttpFailure::Unreachable => self.unreachable += 1,
+            HttpFailure::Tls => self.tls += 1,
+            HttpFailure::Timeout => self.timeout += 1,
+            HttpFailure::TooManyRedirects => self.too_many_redirects += 1,
+            HttpFailure::Other => self.other += 1,
+        }
+    }
+}
+
 pub struct AggregateBuffer {
     pub timestamp: DateTime<FixedOffset>,
     pub responded_agents: Vec<String>,
@@ -89,49 +129,127 @@ impl AggregateBuffer {
         }
     }
 
+    fn message(&self, check_id: Uuid) -> AggregatedCheckMessage {
+        AggregatedCheckMessage {
+            check_id,
+            timestamp: self.timestamp,
+            latency: self.aggregated_message.latency.as_millis() as u64,
+            status_codes: self.aggregated_message.status_codes,
+            failures: self.aggregated_message.failures,
+        }
+    }
+
     fn add_check(&mut self, check_data: &CheckData<HttpFields>) {
         self.responded_agents.push(check_data.agent_id.clone());
         self.aggregated_message
-            .aggregate(check_data.latency, check_data.fields.status_code)
+            .aggregate(check_data.latency, &check_data.fields)
     }
 }
 
 pub struct Aggregator {
     http_receiver: Receiver<CheckData<HttpFields>>,
-    pulsar_sink: Producer<TokioExecutor>,
+    bus: Arc<dyn MessageBus>,
     buffer: HashMap<Uuid, AggregateBuffer>,
+    /// Aggregates sent and waiting for their receipt, oldest first
+    in_flight: VecDeque<(AggregatedCheckMessage, Receipt)>,
+    /// Aggregates which failed to be sent, oldest first
+    unsent: VecDeque<AggregatedCheckMessage>,
 }
 
 impl Aggregator {
-    pub fn new(
-        http_receiver: Receiver<CheckData<HttpFields>>,
-        pulsar_sink: Producer<TokioExecutor>,
-    ) -> Self {
+    pub fn new(http_receiver: Receiver<CheckData<HttpFields>>, bus: Arc<dyn MessageBus>) -> Self {
         Self {
             http_receiver,
-            pulsar_sink,
+            bus,
             buffer: Default::default(),
+            in_flight: VecDeque::new(),
+            unsent: VecDeque::new(),
+        }
+    }
+
+    fn keep_unsent(&mut self, message: AggregatedCheckMessage) {
+        if self.unsent.len() >= MAX_UNSENT {
+            if let Some(dropped) = self.unsent.pop_front() {
+                error!(
+                    "Too many aggregates waiting for pulsar, dropping the one of check {}",
+                    dropped.check_id
+                );
+            }
+        }
+        self.unsent.push_back(message);
+    }
+
+    /// Send an aggregate without waiting for its receipt, keeping it to send again on failure
+    async fn publish(&mut self, message: AggregatedCheckMessage) {
+        match bus::publish_json(self.bus.as_ref(), Topic::AggregatedHttp, None, &message).await {
+            Ok(receipt) => self.in_flight.push_back((message, receipt)),
+            Err(e) => {
+                warn!("Could not send aggregated data to pulsar : {e}");
+                self.keep_unsent(message);
+            }
+        }
+    }
+
+    /// Handle the receipts of the aggregates in flight, oldest first, keeping
+    /// the failed ones : all of them if `wait`, otherwise the ones already received
+    async fn settle(&mut self, wait: bool) {
+        while let Some((_, receipt)) = self.in_flight.front_mut() {
+            let received = match wait {
+                true => Some(receipt.await),
+                false => receipt.now_or_never(),
+            };
+            let (Some(received), Some((message, _))) = (received, self.in_flight.pop_front())
+            else {
+                return;
+            };
+
+            if let Err(e) = received {
+                warn!(
+                    "Aggregate of check {} was not stored : {e}",
+                    message.check_id
+                );
+                self.keep_unsent(message);
+            }
+        }
+    }
+
+    /// Send the aggregates which failed before, stopping at the first failure
+    async fn retry_unsent(&mut self) {
+        while let Some(message) = self.unsent.pop_front() {
+            match bus::publish_json(self.bus.as_ref(), Topic::AggregatedHttp, None, &message).await
+            {
+                Ok(receipt) => self.in_flight.push_back((message, receipt)),
+                Err(_) => {
+                    self.unsent.push_front(message);
+                    return;
+                }
+            }
         }
     }
 
     pub async fn run(&mut self) {
         info!("Started aggregator sink");
-        while let Some(check_data) = self.http_receiver.recv().await.ok() {
+        loop {
+            let check_data = match self.http_receiver.recv().await {
+                Ok(check_data) => check_data,
+                // closed once drained, when the offloader shuts down
+                Err(RecvError::Closed) => break,
+                Err(e) => {
+                    error!("reciever run into an error : {:?}", e);
+                    continue;
+                }
+            };
+
             if let Some(check_buffer) = self.buffer.get_mut(&check_data.check_id) {
                 if !check_buffer.responded_agents.contains(&check_data.agent_id) {
                     info!("Got data from a new agent, appending...");
                     check_buffer.add_check(&check_data)
                 } else {
                     info!("Sending data to pulsar...");
-                    let _ = self
-                        .pulsar_sink
-                        .send_non_blocking(AggregatedCheckMessage {
-                            check_id: check_data.check_id,
-                            timestamp: check_buffer.timestamp,
-                            latency: check_buffer.aggregated_message.latency.as_millis() as u64,
-                            status_codes: check_buffer.aggregated_message.status_codes.clone(),
-                        })
-                        .await;
+                    let message = check_buffer.message(check_data.check_id);
+                    self.settle(false).await;
+                    self.retry_unsent().await;
+                    self.publish(message).await;
                 }
             } else {
                 info!("Got data from a new check, inserting a new buffer...");
@@ -140,6 +258,37 @@ impl Aggregator {
                 self.buffer.insert(check_data.check_id, value);
             }
         }
+        self.flush().await;
         info!("Stopped aggregator sink");
     }
+
+    /// Send every buffered aggregate and wait for the receipts, when the offloader shuts down
+    async fn flush(&mut self) {
+        info!(
+            "Sending {} buffered aggregates to pulsar...",
+            self.buffer.len()
+        );
+        let messages: Vec<_> = self
+            .buffer
+            .drain()
+            .map(|(check_id, check_buffer)| check_buffer.message(check_id))
+            .collect();
+
+        self.settle(false).await;
+        self.retry_unsent().await;
+        for message in messages {
+            self.publish(message).await;
+        }
+        self.settle(true).await;
+        // one more attempt for the receipts which failed meanwhile
+        self.retry_unsent().await;
+        self.settle(true).await;
+
+        if !self.unsent.is_empty() {
+            error!(
+                "Could not send {} aggregates to pulsar before stopping, dropping them",
+                self.unsent.len()
+            );
+        }
+    }
 }
@@ -1,24 +1,19 @@
 use crate::http::aggregator::Aggregator;
 use crate::http::warp10::{Warp10Client, Warp10ConnectionData, Warp10HttpSink};
-use crate::pulsar_source::{pulsar_http_topic, PulsarConnectionData, PulsarSource};
+use crate::pulsar_source::{pulsar_topic, PulsarConnectionData, PulsarSource};
 use crate::{env_get, env_get_num};
+use isok_data::bus::MessageBus;
 use isok_data::check_kinds::http::HttpFields;
 use isok_data::pulsar_messages::{CheckData, CheckType};
 use log::{error, info};
-use pulsar::{Authentication, Producer, Pulsar, TokioExecutor};
+use std::sync::Arc;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::Receiver;
+use tokio_util::sync::CancellationToken;
 
 pub mod aggregator;
 pub mod warp10;
 
-pub fn pulsar_producer_topic(connection_data: &PulsarConnectionData) -> String {
-    format!(
-        "persistent://{}/{}/{}",
-        connection_data.pulsar_tenant, connection_data.pulsar_namespace, "aggregated-http"
-    )
-}
-
 pub async fn run_http_warp10_sink(warp10_receiver: Receiver<CheckData<HttpFields>>) {
     let warp10_address = env_get("WARP10_ADDRESS");
     let warp10_token = env_get("WARP10_TOKEN");
@@ -45,51 +40,19 @@ pub async fn run_http_warp10_sink(warp10_receiver: Receiver<CheckData<HttpFields
 }
 
 pub async fn run_http_aggregate_sink(
-    pulsar_connection_data: PulsarConnectionData,
+    bus: Arc<dyn MessageBus>,
     aggregator_receiver: Receiver<CheckData<HttpFields>>,
 ) {
-    let client = match Pulsar::builder(&pulsar_connection_data.pulsar_address, TokioExecutor)
-        .with_auth(Authentication {
-            name: "token".to_owned(),
-            data: Vec::from(pulsar_connection_data.pulsar_token.as_bytes()),
-        })
-        .build()
-        .await
-        .ok()
-    {
-        Some(c) => {
-            info!("Connected to pulsar client !");
-            c
-        }
-        None => {
-            error!("Failed to connect to pulsar topic");
-            std::process::exit(1);
-        }
-    };
-
-    let pulsar_aggregate_sink: Producer<TokioExecutor> = match client
-        .producer()
-        .with_topic(pulsar_producer_topic(&pulsar_connection_data))
-        .with_name("aggregated-http".to_string())
-        .build()
-        .await
-        .ok()
-    {
-        Some(pc) => {
-            info!("Connected to pulsar topic !");
-            pc
-        }
-        None => {
-            error!("Failed to connect to pulsar topic");
-            std::process::exit(1);
-        }
-    };
-
-    let mut aggregator_http_sink = Aggregator::new(aggregator_receiver, pulsar_aggregate_sink);
+    let mut aggregator_http_sink = Aggregator::new(aggregator_receiver, bus);
     aggregator_http_sink.run().await;
 }
 
-pub async fn run_http(pulsar_connection_data: PulsarConnectionData) {
+/// Consume http results until `shutdown` is cancelled, then wait for the sinks to handle the received ones
+pub async fn run_http(
+    bus: Arc<dyn MessageBus>,
+    pulsar_connection_data: PulsarConnectionData,
+    shutdown: CancellationToken,
+) {
     let channel_capacity = env_get_num("HTTP_CHANNEL_CAPACITY", 16);
 
     let (pulsar_sender, warp10_receiver) = broadcast::channel(channel_capacity);
@@ -98,26 +61,25 @@ pub async fn run_http(pulsar_connection_data: PulsarConnectionData) {
 
     info!(
         "Connecting to pulsar topic {}...",
-        pulsar_http_topic(&pulsar_connection_data)
+        pulsar_topic(&pulsar_connection_data, CheckType::Http)
+    );
+
+    let mut pulsar_source: PulsarSource<HttpFields> = PulsarSource::new(
+        Arc::clone(&bus),
+        &pulsar_connection_data,
+        pulsar_sender,
+        CheckType::Http,
     );
 
-    let mut pulsar_source: PulsarSource<HttpFields> =
-        match PulsarSource::new(&pulsar_connection_data, pulsar_sender, CheckType::Http).await {
-            Some(pc) => {
-                info!("Connected to pulsar topic !");
-                pc
-            }
-            None => {
-                error!("Failed to connect to pulsar topic");
-                std::process::exit(1);
-            }
-        };
+    let aggregate_sink = tokio::task::spawn(run_http_aggregate_sink(bus, aggregator_receiver));
+    let warp10_sink = tokio::task::spawn(run_http_warp10_sink(warp10_receiver));
 
-    tokio::task::spawn(run_http_aggregate_sink(
-        pulsar_connection_data,
-        aggregator_receiver,
-    ));
-    tokio::task::spawn(run_http_warp10_sink(warp10_receiver));
+    tokio::select! {
+        _ = pulsar_source.run() => {}
+        _ = shutdown.cancelled() => {}
+    }
 
-    pulsar_source.run().await;
+    // the sinks stop once the channel is drained, the source holding its sender
+    drop(pulsar_source);
+    _ = tokio::join!(aggregate_sink, warp10_sink);
 }
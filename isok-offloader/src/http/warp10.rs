@@ -1,6 +1,10 @@
 use isok_data::check_kinds::http::HttpFields;
 use isok_data::pulsar_messages::CheckData;
 use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Debug;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::Receiver;
 use warp10::{Client, Data as Warp10Data, Label, Value};
 
@@ -23,6 +27,19 @@ impl Warp10Client {
             token: connection_data.warp10_token,
         })
     }
+
+    pub async fn send(&self, data: Vec<Warp10Data>) -> Option<()> {
+        self.client
+            .get_writer(self.token.clone())
+            .post(data)
+            .await
+            .map_err(|e| {
+                error!("Unable to write to warp10 {:?}", e);
+                e
+            })
+            .ok()
+            .map(|_| ())
+    }
 }
 
 pub struct Warp10HttpSink {
@@ -30,7 +47,11 @@ pub struct Warp10HttpSink {
     http_receiver: Receiver<CheckData<HttpFields>>,
 }
 
-pub fn warp10_data(check_message: &CheckData<HttpFields>, name: &str, value: Value) -> Warp10Data {
+pub fn warp10_data<A: Serialize + DeserializeOwned + Debug>(
+    check_message: &CheckData<A>,
+    name: &str,
+    value: Value,
+) -> Warp10Data {
     Warp10Data::new(
         check_message.timestamp,
         None,
@@ -57,60 +78,103 @@ impl Warp10HttpSink {
         }
     }
 
+    /// Phase durations series, in milliseconds
+    fn timings_data(check_data: &CheckData<HttpFields>) -> Vec<Warp10Data> {
+        let timings = check_data.fields.timings;
+
+        [
+            ("http.dns_duration", timings.dns),
+            ("http.connect_duration", timings.connect),
+            ("http.tls_duration", timings.tls),
+            ("http.ttfb", timings.ttfb),
+            ("http.download_duration", timings.download),
+        ]
+        .into_iter()
+        .filter_map(|(name, micros)| {
+            micros
+                .map(|micros| warp10_data(check_data, name, Value::Double(micros as f64 / 1000.0)))
+        })
+        .collect()
+    }
+
     pub fn data(check_data: CheckData<HttpFields>) -> Vec<Warp10Data> {
-        vec![
-            warp10_data(
+        let mut data = Self::timings_data(&check_data);
+        if let Some(remote_ip) = check_data.fields.remote_ip {
+            data.push(warp10_data(
+                &check_data,
+                "http.remote_ip",
+                Value::String(remote_ip.to_string()),
+            ));
+        }
+        if let Some(http_version) = &check_data.fields.http_version {
+            data.push(warp10_data(
+                &check_data,
+                "http.version",
+                Value::String(http_version.clone()),
+            ));
+        }
+
+        if let Some(latency) = check_data.latency {
+            data.push(warp10_data(
                 &check_data,
                 "http.request_duration",
-                Value::Long(check_data.latency.as_millis() as i64),
-            ),
-            warp10_data(
+                Value::Long(latency.as_millis() as i64),
+            ));
+        }
+        if let Some(status_code) = check_data.fields.status_code {
+            data.push(warp10_data(
                 &check_data,
                 "http.request_status",
-                Value::Int(check_data.fields.status_code as i32),
-            ),
-        ]
-    }
+                Value::Int(status_code as i32),
+            ));
+        }
+        if let Some(failure) = check_data.fields.failure {
+            data.push(warp10_data(
+                &check_data,
+                "http.failure",
+                Value::String(failure.as_str().to_string()),
+            ));
+        }
+        data.push(warp10_data(
+            &check_data,
+            "http.up",
+            Value::Boolean(check_data.fields.up),
+        ));
+        data.push(warp10_data(
+            &check_data,
+            "http.outcome",
+            Value::String(check_data.outcome.as_str().to_string()),
+        ));
 
-    pub async fn send(&self, data: Vec<Warp10Data>) -> Option<()> {
-        self.warp10_client
-            .client
-            .get_writer(self.warp10_client.token.clone())
-            .post(data)
-            .await
-            .map_err(|e| {
-                error!("Unable to write to warp10 {:?}", e);
-                e
-            })
-            .ok()
-            .map(|_| ())
+        data
     }
 
     pub async fn run(mut self) -> Option<()> {
         info!("Started warp10 sink");
         loop {
-            while let Some(check_data) = self
-                .http_receiver
-                .recv()
-                .await
-                .map_err(|e| {
+            let check_data = match self.http_receiver.recv().await {
+                Ok(check_data) => check_data,
+                // closed once drained, when the offloader shuts down
+                Err(RecvError::Closed) => {
+                    info!("Stopped warp10 sink");
+                    return Some(());
+                }
+                Err(e) => {
                     error!("reciever run into an error : {:?}", e);
-                    ()
-                })
-                .ok()
-            {
-                let warp10_data = Self::data(check_data);
-                let _ = match self.send(warp10_data).await {
-                    None => {
-                        error!("Failed to send data to warp10");
-                        continue;
-                    }
-                    Some(_) => {
-                        info!("Sent data to warp10")
-                    }
-                };
-            }
-            info!("Restarting http warp10 loop");
+                    continue;
+                }
+            };
+
+            let warp10_data = Self::data(check_data);
+            let _ = match self.warp10_client.send(warp10_data).await {
+                None => {
+                    error!("Failed to send data to warp10");
+                    continue;
+                }
+                Some(_) => {
+                    info!("Sent data to warp10")
+                }
+            };
         }
     }
 }
@@ -0,0 +1,3 @@
+//! Parts of the offloader the tests of the other components run against
+
+pub mod pulsar_source;
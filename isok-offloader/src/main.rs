@@ -1,10 +1,20 @@
 use crate::http::run_http;
+use crate::tls::run_tls;
 use env_logger::{Builder as Logger, Env};
+use isok_data::pulsar_bus::PulsarBus;
+use isok_data::pulsar_connector::PulsarConnector;
+use log::{error, info, warn};
 use pulsar_source::PulsarConnectionData;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
 
+pub mod health;
 pub mod http;
-pub mod pulsar_source;
+pub use isok_offloader::pulsar_source;
+pub mod tls;
 
 /// Get env var as string or panic
 pub fn env_get(env: &'static str) -> String {
@@ -26,6 +36,26 @@ pub fn env_get_num<T: FromStr>(env: &'static str, other: T) -> T {
     }
 }
 
+/// Wait for SIGTERM or ctrl-c
+pub async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Can't listen for SIGTERM : {e}");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
 /// Start logger with default log level : info (overridden by env var LOG_LEVEL)
 pub fn init_logger() {
     let env = Env::new().filter_or("LOG_LEVEL", "info");
@@ -40,6 +70,8 @@ async fn main() {
     let pulsar_namespace = env_get("PULSAR_NAMESPACE");
 
     let subscription_uuid = env_get("SUBSCRIPTION_ID");
+    let shutdown_timeout = Duration::from_secs(env_get_num("SHUTDOWN_TIMEOUT", 20));
+    let health_address = std::env::var("HEALTH_ADDRESS").ok();
 
     init_logger();
 
@@ -51,5 +83,35 @@ async fn main() {
         subscription_uuid,
     };
 
-    run_http(pulsar_connection_data).await;
+    let connector = PulsarConnector::new(
+        &pulsar_connection_data.pulsar_address,
+        &pulsar_connection_data.pulsar_token,
+    );
+
+    let bus = Arc::new(PulsarBus::new(
+        connector,
+        &pulsar_connection_data.pulsar_tenant,
+        &pulsar_connection_data.pulsar_namespace,
+    ));
+
+    if let Some(address) = health_address {
+        tokio::task::spawn(health::serve(address, bus.connector().states()));
+    }
+
+    let shutdown = CancellationToken::new();
+    let tls = tokio::task::spawn(run_tls(
+        bus.clone(),
+        pulsar_connection_data.clone(),
+        shutdown.clone(),
+    ));
+    let http = tokio::task::spawn(run_http(bus, pulsar_connection_data, shutdown.clone()));
+
+    shutdown_signal().await;
+    info!("Shutting down, flushing sinks...");
+    shutdown.cancel();
+
+    match tokio::time::timeout(shutdown_timeout, async { tokio::join!(tls, http) }).await {
+        Ok(_) => info!("Offloader stopped"),
+        Err(_) => warn!("Offloader stopped before every sink was flushed"),
+    }
 }
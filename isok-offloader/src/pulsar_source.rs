@@ -1,10 +1,10 @@
-use futures::TryStreamExt;
+use isok_data::bus::{MessageBus, Topic};
 use isok_data::pulsar_messages::{CheckData, CheckMessage, CheckType};
 use log::{error, info};
-use pulsar::{Authentication, Consumer, ConsumerOptions, Pulsar, SubType, TokioExecutor};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fmt::Debug;
+use std::sync::Arc;
 use tokio::sync::broadcast::{error::SendError, Sender};
 
 /// Pulsar connection data, passed by env vars
@@ -17,95 +17,71 @@ pub struct PulsarConnectionData {
     pub subscription_uuid: String,
 }
 
-pub fn pulsar_http_topic(connection_data: &PulsarConnectionData) -> String {
+pub fn pulsar_topic(connection_data: &PulsarConnectionData, check_type: CheckType) -> String {
     format!(
-        "persistent://{}/{}/http",
-        connection_data.pulsar_tenant, connection_data.pulsar_namespace,
+        "persistent://{}/{}/{}",
+        connection_data.pulsar_tenant, connection_data.pulsar_namespace, check_type,
     )
 }
 
+/// Source of the results of a check type, read from the message bus
 pub struct PulsarSource<A: Serialize + DeserializeOwned + Debug> {
-    consumer: Consumer<CheckMessage, TokioExecutor>,
+    bus: Arc<dyn MessageBus>,
+    check_type: CheckType,
+    subscription_name: String,
     sender: Sender<CheckData<A>>,
 }
 
 impl<A: Serialize + DeserializeOwned + Debug> PulsarSource<A> {
-    pub async fn new(
+    pub fn new(
+        bus: Arc<dyn MessageBus>,
         connection_data: &PulsarConnectionData,
         sender: Sender<CheckData<A>>,
         check_type: CheckType,
-    ) -> Option<Self> {
-        let client = Pulsar::builder(&connection_data.pulsar_address, TokioExecutor)
-            .with_auth(Authentication {
-                name: "token".to_owned(),
-                data: Vec::from(connection_data.pulsar_token.as_bytes()),
-            })
-            .build()
-            .await
-            .ok()?;
-
+    ) -> Self {
         let subscription_name = format!("{}-{}", &connection_data.subscription_uuid, check_type);
 
-        info!(
-            "Starting consumer with subscription id : {}",
-            &subscription_name
-        );
-
-        let consumer = client
-            .consumer()
-            .with_topic(pulsar_http_topic(connection_data))
-            .with_subscription_type(SubType::Failover)
-            .with_consumer_name(&subscription_name)
-            .with_subscription(&subscription_name)
-            .with_options(ConsumerOptions {
-                durable: Some(true),
-                read_compacted: Some(true),
-                ..Default::default()
-            })
-            .build()
-            .await
-            .ok()?;
-
-        Some(Self { consumer, sender })
+        Self {
+            bus,
+            check_type,
+            subscription_name,
+            sender,
+        }
     }
 
     pub async fn run(&mut self) {
         loop {
-            while let Some(message) = self
-                .consumer
-                .try_next()
-                .await
-                .map_err(|e| {
-                    error!("Unable to read from pulsar {:?}", e);
-                    e
-                })
-                .ok()
-                .flatten()
-            {
+            let mut subscriber = self
+                .bus
+                .subscribe(Topic::Results(self.check_type), &self.subscription_name)
+                .await;
+
+            while let Some(payload) = subscriber.next().await {
                 info!("Received a message from pulsar");
 
-                let check_data: CheckData<A> = match message.deserialize() {
-                    Ok(data) => {
-                        info!(
-                            "Received a message from {} of check {}",
-                            data.agent_id, data.check_id
-                        );
-                        data.into()
-                    }
-                    Err(e) => {
-                        error!("Could not deserialize message... skipping : {:?}", e);
-                        let _ = self.consumer.ack(&message).await;
-                        continue;
-                    }
-                };
+                let check_data: CheckData<A> =
+                    match serde_json::from_slice::<CheckMessage>(&payload)
+                        .and_then(CheckData::try_from)
+                    {
+                        Ok(data) => {
+                            info!(
+                                "Received a message from {} of check {}",
+                                data.agent_id, data.check_id
+                            );
+                            data
+                        }
+                        Err(e) => {
+                            error!("Could not deserialize message... skipping : {:?}", e);
+                            subscriber.ack().await;
+                            continue;
+                        }
+                    };
 
                 let mut check_data = Some(check_data);
                 while let Some(data) = check_data.take() {
                     check_data = match self.sender.send(data) {
                         Ok(_) => {
-                            _ = self.consumer.ack(&message).await.map_err(|_| {
-                            error!("Pulsar consumer failed to acknowledge check data while sending it to broadcast channel");
-                        });
+                            subscriber.ack().await;
                             None
                         }
                         Err(SendError(returned_data)) => {
@@ -0,0 +1,70 @@
+use crate::env_get;
+use crate::env_get_num;
+use crate::http::warp10::{Warp10Client, Warp10ConnectionData};
+use crate::pulsar_source::{pulsar_topic, PulsarConnectionData, PulsarSource};
+use crate::tls::warp10::Warp10TlsSink;
+use isok_data::bus::MessageBus;
+use isok_data::check_kinds::tls::TlsFields;
+use isok_data::pulsar_messages::{CheckData, CheckType};
+use log::{error, info};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::Receiver;
+use tokio_util::sync::CancellationToken;
+
+pub mod warp10;
+
+pub async fn run_tls_warp10_sink(warp10_receiver: Receiver<CheckData<TlsFields>>) {
+    let warp10_address = env_get("WARP10_ADDRESS");
+    let warp10_token = env_get("WARP10_TOKEN");
+
+    let warp10_connection_data = Warp10ConnectionData {
+        warp10_address,
+        warp10_token,
+    };
+
+    info!("Connecting to warp10...",);
+    let warp10_client = match Warp10Client::new(warp10_connection_data) {
+        Some(pc) => {
+            info!("Connected to warp10 !");
+            pc
+        }
+        None => {
+            error!("Failed to connect to warp10");
+            std::process::exit(1);
+        }
+    };
+
+    let warp10_tls_sink = Warp10TlsSink::new(warp10_client, warp10_receiver);
+    warp10_tls_sink.run().await;
+}
+
+/// Consume tls results until `shutdown` is cancelled, then wait for the sink to write the received ones
+pub async fn run_tls(
+    bus: Arc<dyn MessageBus>,
+    pulsar_connection_data: PulsarConnectionData,
+    shutdown: CancellationToken,
+) {
+    let channel_capacity = env_get_num("TLS_CHANNEL_CAPACITY", 16);
+
+    let (pulsar_sender, warp10_receiver) = broadcast::channel(channel_capacity);
+
+    info!(
+        "Connecting to pulsar topic {}...",
+        pulsar_topic(&pulsar_connection_data, CheckType::Tls)
+    );
+
+    let mut pulsar_source: PulsarSource<TlsFields> =
+        PulsarSource::new(bus, &pulsar_connection_data, pulsar_sender, CheckType::Tls);
+
+    let warp10_sink = tokio::task::spawn(run_tls_warp10_sink(warp10_receiver));
+
+    tokio::select! {
+        _ = pulsar_source.run() => {}
+        _ = shutdown.cancelled() => {}
+    }
+
+    // the sink stops once the channel is drained, the source holding its sender
+    drop(pulsar_source);
+    _ = warp10_sink.await;
+}
@@ -0,0 +1,111 @@
+use crate::http::warp10::{warp10_data, Warp10Client};
+use isok_data::check_kinds::tls::{TlsExpiryStatus, TlsFields};
+use isok_data::pulsar_messages::CheckData;
+use log::{error, info, warn};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use warp10::{Data as Warp10Data, Value};
+
+pub struct Warp10TlsSink {
+    warp10_client: Warp10Client,
+    tls_receiver: Receiver<CheckData<TlsFields>>,
+}
+
+impl Warp10TlsSink {
+    pub fn new(warp10_client: Warp10Client, tls_receiver: Receiver<CheckData<TlsFields>>) -> Self {
+        Self {
+            warp10_client,
+            tls_receiver,
+        }
+    }
+
+    pub fn data(check_data: CheckData<TlsFields>) -> Vec<Warp10Data> {
+        let mut data = vec![warp10_data(
+            &check_data,
+            "tls.expiry_status",
+            Value::String(check_data.fields.expiry_status().as_str().to_string()),
+        )];
+
+        if let Some(latency) = check_data.latency {
+            data.push(warp10_data(
+                &check_data,
+                "tls.handshake_duration",
+                Value::Long(latency.as_millis() as i64),
+            ));
+        }
+        if let Some(days) = check_data.fields.days_until_expiry {
+            data.push(warp10_data(
+                &check_data,
+                "tls.days_until_expiry",
+                Value::Long(days),
+            ));
+        }
+        if let Some(chain_valid) = check_data.fields.chain_valid {
+            data.push(warp10_data(
+                &check_data,
+                "tls.chain_valid",
+                Value::Boolean(chain_valid),
+            ));
+        }
+        if let Some(hostname_match) = check_data.fields.hostname_match {
+            data.push(warp10_data(
+                &check_data,
+                "tls.hostname_match",
+                Value::Boolean(hostname_match),
+            ));
+        }
+        data.push(warp10_data(
+            &check_data,
+            "tls.outcome",
+            Value::String(check_data.outcome.as_str().to_string()),
+        ));
+
+        data
+    }
+
+    /// Log certificates close to expiry, according to the check thresholds
+    fn flag_expiry(check_data: &CheckData<TlsFields>) {
+        match check_data.fields.expiry_status() {
+            status @ (TlsExpiryStatus::Warning
+            | TlsExpiryStatus::Critical
+            | TlsExpiryStatus::Expired) => warn!(
+                "Certificate of check {} is {} : {} days until expiry (reported by {})",
+                check_data.check_id,
+                status.as_str(),
+                check_data.fields.days_until_expiry.unwrap_or_default(),
+                check_data.agent_id
+            ),
+            TlsExpiryStatus::Ok | TlsExpiryStatus::Unknown => {}
+        }
+    }
+
+    pub async fn run(mut self) -> Option<()> {
+        info!("Started tls warp10 sink");
+        loop {
+            let check_data = match self.tls_receiver.recv().await {
+                Ok(check_data) => check_data,
+                // closed once drained, when the offloader shuts down
+                Err(RecvError::Closed) => {
+                    info!("Stopped tls warp10 sink");
+                    return Some(());
+                }
+                Err(e) => {
+                    error!("reciever run into an error : {:?}", e);
+                    continue;
+                }
+            };
+            Self::flag_expiry(&check_data);
+
+            let warp10_data = Self::data(check_data);
+            match self.warp10_client.send(warp10_data).await {
+                None => {
+                    error!("Failed to send data to warp10");
+                    continue;
+                }
+                Some(_) => {
+                    info!("Sent data to warp10")
+                }
+            };
+        }
+    }
+}